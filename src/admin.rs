@@ -0,0 +1,185 @@
+//! Authenticated admin API: alarm state, events, the journal, test alarms, acknowledging,
+//! silencing, maintenance and reloading the config
+
+use std::{sync::Arc, time::Duration};
+
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    alarm::{self, AlarmError, Alarms},
+    config::SharedConfig,
+    http::{Request, Response},
+    journal,
+    maintenance::MaintenanceSource,
+    metrics::Metrics,
+    reload,
+};
+
+/// Body of a request to silence an alarm
+#[derive(Debug, Deserialize)]
+struct SilenceRequest {
+    /// silence for this many seconds. 0 lifts the silence.
+    seconds: u64,
+}
+
+/// Body of a request to start or end maintenance
+#[derive(Debug, Deserialize)]
+struct MaintenanceRequest {
+    /// maintenance for this many seconds, at most `maintenance.max_minutes`. 0 ends it.
+    seconds: u64,
+}
+
+/// Compare the bearer token without leaking the position of the first difference
+fn is_authorized(request: &Request, token: &str) -> bool {
+    let Some(given) = request
+        .header("authorization")
+        .and_then(|x| x.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0_u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// The journal query given by the parameters `from`, `to`, `alarm` and `endpoint`
+fn journal_query(request: &Request) -> Result<journal::Query, String> {
+    let time = |name: &str| {
+        request
+            .query(name)
+            .map(|x| journal::parse_time(x).map_err(|e| format!("{name}: {e}")))
+            .transpose()
+    };
+    Ok(journal::Query {
+        from: time("from")?,
+        to: time("to")?,
+        alarm: request.query("alarm").map(ToOwned::to_owned),
+        endpoint: request.query("endpoint").map(ToOwned::to_owned),
+    })
+}
+
+fn alarm_error_response(e: &AlarmError) -> Response {
+    match e {
+        AlarmError::NotFound(_) => Response::text(404, format!("{e}\n")),
+        AlarmError::NotActive(_) => Response::text(409, format!("{e}\n")),
+        AlarmError::DurationTooLong(_) => Response::text(400, format!("{e}\n")),
+    }
+}
+
+/// Answer a request to the admin API
+pub async fn route(
+    request: Request,
+    shared_config: Arc<SharedConfig>,
+    metrics: Arc<Metrics>,
+    alarms: Arc<Alarms>,
+) -> Response {
+    let config = shared_config.get();
+    let token = match &config.admin {
+        Some(x) => x.token.expose(),
+        None => return Response::text(404, "Not Found\n"),
+    };
+    if !is_authorized(&request, token) {
+        warn!(
+            "Unauthorized request to the admin API: {} {}",
+            request.method, request.path
+        );
+        return Response::text(401, "Unauthorized\n");
+    };
+
+    let segments = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .collect::<Vec<_>>();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["api", "alarms"]) => Response::json(200, &alarms.list()),
+        ("GET", ["api", "events"]) => Response::json(200, &alarms.events()),
+        ("GET", ["api", "journal"]) => {
+            let Some(journal_config) = &config.journal else {
+                return Response::text(404, "There is no journal, set journal.file\n");
+            };
+            let query = match journal_query(&request) {
+                Ok(x) => x,
+                Err(e) => return Response::text(400, format!("Invalid query: {e}\n")),
+            };
+            let csv = request.query("format") == Some("csv");
+            let path = journal_config.file.clone();
+            // the journal may be large
+            let res =
+                smol::unblock(move || journal::read(&path, &query).map_err(|e| e.to_string()))
+                    .await;
+            match res {
+                Ok(events) if csv => Response {
+                    status: 200,
+                    content_type: "text/csv; charset=utf-8",
+                    body: journal::to_csv(&events),
+                },
+                Ok(events) => Response::json(200, &events),
+                Err(e) => Response::text(500, format!("Unable to read the journal: {e}\n")),
+            }
+        }
+        ("POST", ["api", "alarms", name, "test"]) => {
+            let Some(index) = config.alarms.iter().position(|x| x.name == *name) else {
+                return alarm_error_response(&AlarmError::NotFound((*name).to_owned()));
+            };
+            info!(alarm_name = name, "Test alarm requested via the admin API.");
+            // the calls are sent in the background
+            let endpoints =
+                alarm::test_call_round(&config, &metrics, &alarms, &config.alarms[index]);
+            Response::json(200, &serde_json::json!({ "calling": endpoints }))
+        }
+        ("POST", ["api", "alarms", name, "acknowledge"]) => match alarms.acknowledge(name) {
+            Ok(()) => Response::text(200, "acknowledged\n"),
+            Err(e) => alarm_error_response(&e),
+        },
+        ("POST", ["api", "alarms", name, "silence"]) => {
+            let silence: SilenceRequest = match serde_json::from_slice(&request.body) {
+                Ok(x) => x,
+                Err(e) => return Response::text(400, format!("Invalid body: {e}\n")),
+            };
+            match alarms.silence(name, Duration::from_secs(silence.seconds)) {
+                Ok(until) => Response::json(200, &serde_json::json!({ "silenced_until": until })),
+                Err(e) => alarm_error_response(&e),
+            }
+        }
+        ("GET", ["api", "maintenance"]) => Response::json(200, &alarms.maintenance()),
+        ("POST", ["api", "maintenance"]) | ("POST", ["api", "alarms", _, "maintenance"]) => {
+            let maintenance: MaintenanceRequest = match serde_json::from_slice(&request.body) {
+                Ok(x) => x,
+                Err(e) => return Response::text(400, format!("Invalid body: {e}\n")),
+            };
+            let alarm = match segments.as_slice() {
+                ["api", "alarms", name, "maintenance"] => Some(*name),
+                _ => None,
+            };
+            match alarms.start_maintenance(
+                &config,
+                alarm,
+                Duration::from_secs(maintenance.seconds),
+                MaintenanceSource::AdminApi,
+            ) {
+                Ok(until) => {
+                    Response::json(200, &serde_json::json!({ "maintenance_until": until }))
+                }
+                Err(e) => alarm_error_response(&e),
+            }
+        }
+        ("POST", ["api", "config", "reload"]) => {
+            info!("Config reload requested via the admin API.");
+            let res = smol::unblock(move || {
+                reload::reload(&shared_config, &metrics, &alarms).map_err(|e| e.to_string())
+            })
+            .await;
+            match res {
+                Ok(()) => Response::text(200, "reloaded\n"),
+                Err(e) => Response::text(400, format!("{e}\nKeeping the old config.\n")),
+            }
+        }
+        (_, ["api", ..]) => Response::text(405, "Method Not Allowed\n"),
+        _ => Response::text(404, "Not Found\n"),
+    }
+}
@@ -0,0 +1,1497 @@
+//! State of the alarm inputs and the reaction to alarm and good states
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    ami,
+    clock::{self, now},
+    config::{AlarmConfig, Config, SharedConfig},
+    journal,
+    maintenance::{Maintenance, MaintenanceSource, MaintenanceState},
+    metrics::Metrics,
+    notify::{self, ClearEvent, OnClearConfig},
+    persist::{self, OnRestart, SavedState},
+    rule::RuleState,
+    schedule::{self, Priority},
+    vote::{InputValue, VotingConfig},
+};
+
+/// Remember at most this many events
+const MAX_EVENTS: usize = 200;
+/// Used as alarm name in events concerning all alarms
+pub const ALL_ALARMS: &str = "*";
+/// Check this often whether call rounds of alarms with a higher priority finished
+const ROUND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What caused an alarm or good state to be processed
+#[derive(Debug, Clone)]
+pub enum Trigger {
+    /// a COE packet from this CMI
+    Cmi(std::net::SocketAddr),
+    /// a test alarm requested via the admin API
+    Test,
+    /// the timer of a rule
+    Timer,
+}
+impl Trigger {
+    /// The address of the CMI, for structured logging
+    fn cmi_addr(&self) -> Option<String> {
+        match self {
+            Self::Cmi(x) => Some(x.to_string()),
+            Self::Test | Self::Timer => None,
+        }
+    }
+}
+impl core::fmt::Display for Trigger {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Cmi(x) => write!(f, "cmi {x}"),
+            Self::Test => write!(f, "test"),
+            Self::Timer => write!(f, "rule timer"),
+        }
+    }
+}
+
+/// The current state of a single alarm
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlarmState {
+    pub name: String,
+    /// the last relevant value was the alarm state
+    pub active: bool,
+    /// unix time the alarm was raised, if active
+    pub active_since: Option<u64>,
+    /// how many times calls were sent since the alarm was raised
+    pub calls_sent: u32,
+    /// an operator acknowledged the active alarm; no more calls are sent until it clears
+    pub acknowledged: bool,
+    /// unix time until which no calls are sent for this alarm
+    pub silenced_until: Option<u64>,
+    /// endpoints successfully called since the alarm was raised
+    pub called_endpoints: Vec<String>,
+    /// the last value of each input of a voting alarm
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<Option<InputValue>>,
+    /// unix time the inputs started to disagree
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disagreeing_since: Option<u64>,
+}
+impl AlarmState {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            active: false,
+            active_since: None,
+            calls_sent: 0,
+            acknowledged: false,
+            silenced_until: None,
+            called_endpoints: Vec::new(),
+            inputs: Vec::new(),
+            disagreeing_since: None,
+        }
+    }
+
+    fn is_silenced(&self) -> bool {
+        self.silenced_until.is_some_and(|x| x > now())
+    }
+}
+
+/// Something that happened to an alarm
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum EventKind {
+    /// the alarm became active
+    Raised {
+        /// what raised it, e.g. `cmi 192.168.10.123:5442`
+        trigger: String,
+    },
+    /// the alarm is no longer active
+    Cleared {
+        /// what cleared it, e.g. `cmi 192.168.10.123:5442` or `config reload`
+        trigger: String,
+        /// how long the alarm was active
+        duration_seconds: u64,
+    },
+    /// asterisk accepted the call to `endpoint`
+    CallSucceeded {
+        /// the endpoint called
+        endpoint: String,
+        /// the ActionID of the originate action
+        action_id: String,
+    },
+    /// calling `endpoint` failed, or connecting to asterisk if it is empty
+    CallFailed {
+        /// the endpoint called, empty if the whole call round failed
+        endpoint: String,
+        /// `None` if no originate action was sent
+        action_id: Option<String>,
+        /// the response of asterisk or the error
+        error: String,
+    },
+    /// the alarm is active, but no call was sent
+    CallSuppressed {
+        /// e.g. `acknowledged` or `silenced`
+        reason: String,
+    },
+    /// an all-clear call or webhook succeeded
+    ClearNotified {
+        /// the endpoint called or the url notified
+        target: String,
+        /// the ActionID of the all-clear call, `None` for webhooks
+        action_id: Option<String>,
+    },
+    /// an all-clear call or webhook failed
+    ClearNotificationFailed {
+        /// the endpoint called or the url notified
+        target: String,
+        /// the ActionID of the all-clear call, `None` for webhooks
+        action_id: Option<String>,
+        /// the response of asterisk or the error
+        error: String,
+    },
+    /// the rest of the call round was cancelled by an alarm with a higher priority
+    CallPreempted {
+        /// the alarm with the higher priority
+        by: String,
+    },
+    /// an operator acknowledged the active alarm
+    Acknowledged,
+    /// an operator silenced the alarm
+    Silenced {
+        /// unix time the silence ends
+        until: u64,
+    },
+    /// maintenance of the alarm, or of all alarms, started
+    MaintenanceStarted {
+        /// unix time the maintenance ends
+        until: u64,
+        /// how it was started
+        source: MaintenanceSource,
+    },
+    /// maintenance of the alarm, or of all alarms, ended
+    MaintenanceEnded {
+        /// how it was started
+        source: MaintenanceSource,
+        /// ended because it reached its end time
+        expired: bool,
+    },
+    /// recorded for all alarms, with the alarm names
+    ConfigReloaded {
+        /// alarms new in the config
+        added: Vec<String>,
+        /// alarms whose config changed
+        changed: Vec<String>,
+        /// alarms no longer in the config
+        removed: Vec<String>,
+    },
+    /// recorded for all alarms; the old config stays in use
+    ConfigReloadFailed {
+        /// why the new config was rejected
+        error: String,
+    },
+    /// replaying a capture or a dry run: these endpoints would have been called
+    WouldCall {
+        /// the endpoints the schedule routes to
+        endpoints: Vec<String>,
+    },
+    /// a test alarm requested via the admin API calls these endpoints; the alarm itself is
+    /// not raised
+    TestCall {
+        /// the endpoints the schedule routes to
+        endpoints: Vec<String>,
+    },
+}
+impl EventKind {
+    /// The endpoint called or the url notified, if any
+    pub fn endpoint(&self) -> Option<&str> {
+        match self {
+            Self::CallSucceeded { endpoint, .. } | Self::CallFailed { endpoint, .. }
+                if !endpoint.is_empty() =>
+            {
+                Some(endpoint)
+            }
+            Self::ClearNotified { target, .. } | Self::ClearNotificationFailed { target, .. } => {
+                Some(target)
+            }
+            _ => None,
+        }
+    }
+
+    /// The ActionID of the originate action, if one was sent
+    pub fn action_id(&self) -> Option<&str> {
+        match self {
+            Self::CallSucceeded { action_id, .. } => Some(action_id),
+            Self::CallFailed { action_id, .. }
+            | Self::ClearNotified { action_id, .. }
+            | Self::ClearNotificationFailed { action_id, .. } => action_id.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Something that happened to an alarm, at a time. Serialized as a line of the journal, e.g.
+/// `{"time":1792338560,"alarm":"fire","kind":"raised","trigger":"cmi 192.168.10.123:5442"}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Event {
+    /// unix time
+    pub time: u64,
+    /// name of the alarm, `*` for events of all alarms like config reloads
+    pub alarm: String,
+    /// what happened
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Errors from operator actions on alarms
+#[derive(Debug)]
+pub enum AlarmError {
+    /// there is no alarm with this name
+    NotFound(String),
+    /// the alarm needs to be active for this action
+    NotActive(String),
+    /// a duration of this many seconds ends too far in the future
+    DurationTooLong(u64),
+}
+impl core::fmt::Display for AlarmError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::NotFound(x) => write!(f, "There is no alarm named {x}"),
+            Self::NotActive(x) => write!(f, "The alarm {x} is not active"),
+            Self::DurationTooLong(x) => write!(f, "{x} seconds is too long"),
+        }
+    }
+}
+impl std::error::Error for AlarmError {}
+
+/// How the alarm inputs differ between two configs, by name
+#[derive(Debug, Default)]
+pub struct AlarmChanges {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    /// active alarms that were removed or whose input changed; they were cleared
+    pub cleared: Vec<String>,
+}
+
+/// The outcome of a value reported by one input of a voting alarm
+#[derive(Debug)]
+pub struct Vote {
+    /// the combined value of all inputs, `None` if no input has a value
+    pub alarm: Option<bool>,
+    /// Process the combined value. While the alarm is active, only the first input reporting
+    /// it is processed, so redundant inputs do not repeat calls more often.
+    pub process: bool,
+    /// raise (`Some(true)`) or clear (`Some(false)`) the disagreement alarm
+    pub disagreement: Option<bool>,
+    /// the inputs just started to disagree
+    pub started_disagreeing: bool,
+    /// the current value of each input, `None` if never heard from or failed
+    pub inputs: Vec<Option<bool>>,
+}
+
+/// Calls being sent for an alarm
+#[derive(Debug)]
+struct Round {
+    alarm: String,
+    priority: Priority,
+    /// stop before calling the next endpoint
+    cancelled: Arc<AtomicBool>,
+}
+
+/// The state of all alarms and the recent events
+#[derive(Debug)]
+pub struct Alarms {
+    states: Mutex<BTreeMap<String, AlarmState>>,
+    events: Mutex<VecDeque<Event>>,
+    maintenance: Mutex<MaintenanceState>,
+    rounds: Mutex<Vec<Round>>,
+    /// the signal values and timers of the rules
+    rules: Mutex<RuleState>,
+    /// keep the state in this file; the lock is held while taking a snapshot
+    state_file: Mutex<Option<PathBuf>>,
+    /// writes the snapshots in the background
+    state_writer: Arc<persist::Writer>,
+    /// append every event to this file
+    journal_file: Option<PathBuf>,
+    /// replaying a capture: record the calls instead of sending them
+    dry_run: bool,
+}
+impl Alarms {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            states: Mutex::new(
+                config
+                    .alarms
+                    .iter()
+                    .map(|x| (x.name.clone(), AlarmState::new(&x.name)))
+                    .collect(),
+            ),
+            events: Mutex::new(VecDeque::new()),
+            maintenance: Mutex::new(MaintenanceState::default()),
+            rounds: Mutex::new(Vec::new()),
+            rules: Mutex::new(RuleState::default()),
+            state_file: Mutex::new(config.state.as_ref().map(|x| x.file.clone())),
+            state_writer: Arc::default(),
+            journal_file: config.journal.as_ref().map(|x| x.file.clone()),
+            dry_run: false,
+        }
+    }
+
+    /// For replaying a capture: no calls or notifications are sent, and neither the state
+    /// nor the journal is written
+    pub fn dry_run(config: &Config) -> Self {
+        Self {
+            state_file: Mutex::new(None),
+            journal_file: None,
+            dry_run: true,
+            ..Self::new(config)
+        }
+    }
+
+    /// Restore the state saved by a previous run, applying `state.on_restart` to alarms that
+    /// were active. Returns the names of the alarms that are still active.
+    pub fn restore(&self, config: &Config) -> Vec<String> {
+        let Some(state_config) = &config.state else {
+            return Vec::new();
+        };
+        let saved = match persist::load(&state_config.file) {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                info!(
+                    "No saved alarm state in {}, starting fresh.",
+                    state_config.file.display()
+                );
+                return Vec::new();
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to read the saved alarm state from {}, starting fresh: {e}",
+                    state_config.file.display()
+                );
+                return Vec::new();
+            }
+        };
+        let age = Duration::from_secs(now().saturating_sub(saved.saved_at));
+        let on_restart = match state_config.max_age {
+            Some(max_age) if age > max_age => {
+                info!(
+                    "The saved alarm state is {} min old, resetting the active alarms.",
+                    age.as_secs() / 60
+                );
+                OnRestart::Reset
+            }
+            _ => state_config.on_restart,
+        };
+        let mut active = Vec::new();
+        {
+            let mut states = self.states.lock().expect("alarm state mutex poisoned");
+            for mut x in saved.alarms {
+                let Some(state) = states.get_mut(&x.name) else {
+                    debug!(
+                        alarm_name = x.name,
+                        "Ignoring the saved state of an alarm that is no longer configured."
+                    );
+                    continue;
+                };
+                if x.active {
+                    match on_restart {
+                        OnRestart::Resume => (),
+                        OnRestart::Reset => {
+                            x = AlarmState {
+                                silenced_until: x.silenced_until,
+                                ..AlarmState::new(&x.name)
+                            };
+                        }
+                        OnRestart::Acknowledge => x.acknowledged = true,
+                    };
+                };
+                if x.active {
+                    active.push(x.name.clone());
+                };
+                *state = x;
+            }
+        }
+        let mut maintenance = self.maintenance.lock().expect("maintenance mutex poisoned");
+        *maintenance = saved.maintenance;
+        maintenance
+            .alarms
+            .retain(|name, _| config.alarms.iter().any(|x| x.name == *name));
+        self.expire_maintenance(&mut maintenance);
+        info!(
+            active = ?active,
+            "Restored the alarm state saved {}s ago from {} ({on_restart} active alarms).",
+            age.as_secs(),
+            state_config.file.display()
+        );
+        active
+    }
+
+    /// Write the state to the state file in the background, if one is configured.
+    ///
+    /// The writes wait for the disk, so they do not hold up the packets and API requests;
+    /// a write still pending when a newer one finished is skipped.
+    pub fn persist(&self) {
+        let Some((path, saved, number)) = self.snapshot() else {
+            return;
+        };
+        let writer = self.state_writer.clone();
+        smol::unblock(move || writer.write(&path, &saved, number)).detach();
+    }
+
+    /// Write the state to the state file before returning, e.g. when shutting down
+    pub fn persist_now(&self) {
+        if let Some((path, saved, number)) = self.snapshot() {
+            self.state_writer.write(&path, &saved, number);
+        };
+    }
+
+    /// The state file, the current state and the number of this snapshot
+    fn snapshot(&self) -> Option<(PathBuf, SavedState, u64)> {
+        let state_file = self.state_file.lock().expect("state file mutex poisoned");
+        let path = state_file.as_ref()?;
+        let saved = SavedState {
+            saved_at: now(),
+            alarms: self.list(),
+            maintenance: self
+                .maintenance
+                .lock()
+                .expect("maintenance mutex poisoned")
+                .clone(),
+        };
+        Some((path.clone(), saved, self.state_writer.next_number()))
+    }
+
+    /// Swap in a new config.
+    ///
+    /// Alarms with an unchanged input keep their state, even if e.g. their priority
+    /// changed. Alarms with a changed input start in the good state, as do added alarms.
+    /// Active alarms that start over or were removed are cleared and their calls stop.
+    /// Returns the old config and what changed.
+    pub fn swap_config(
+        &self,
+        shared: &SharedConfig,
+        new: Arc<Config>,
+    ) -> (Arc<Config>, AlarmChanges) {
+        // hold the lock while swapping, so no packet is processed against the wrong state
+        let mut states = self.states.lock().expect("alarm state mutex poisoned");
+        let old = shared.replace(new.clone());
+        let mut changes = AlarmChanges::default();
+        let mut reset = Vec::new();
+        let mut cleared = Vec::new();
+        for alarm in &new.alarms {
+            match old.alarms.iter().find(|x| x.name == alarm.name) {
+                Some(x) if x == alarm => continue,
+                Some(x) if x.same_input(alarm) => {
+                    changes.changed.push(alarm.name.clone());
+                    continue;
+                }
+                Some(_) => {
+                    changes.changed.push(alarm.name.clone());
+                    reset.push(alarm.name.clone());
+                }
+                None => changes.added.push(alarm.name.clone()),
+            };
+            if let Some(x) = states.insert(alarm.name.clone(), AlarmState::new(&alarm.name)) {
+                if x.active {
+                    cleared.push((x.name, x.active_since.unwrap_or(now())));
+                };
+            };
+        }
+        states.retain(|name, x| {
+            let keep = new.alarms.iter().any(|x| x.name == *name);
+            if !keep {
+                changes.removed.push(name.clone());
+                reset.push(name.clone());
+                if x.active {
+                    cleared.push((name.clone(), x.active_since.unwrap_or(now())));
+                };
+            };
+            keep
+        });
+        self.maintenance
+            .lock()
+            .expect("maintenance mutex poisoned")
+            .alarms
+            .retain(|name, _| states.contains_key(name));
+        drop(states);
+        self.rules
+            .lock()
+            .expect("rule state mutex poisoned")
+            .forget(&reset);
+        for (name, raised_at) in cleared {
+            self.cancel_round(&name);
+            info!(
+                alarm_name = name,
+                "Alarm cleared, its input changed or it was removed with the new config."
+            );
+            self.record(
+                &name,
+                EventKind::Cleared {
+                    trigger: "config reload".to_owned(),
+                    duration_seconds: now().saturating_sub(raised_at),
+                },
+            );
+            changes.cleared.push(name);
+        }
+        self.persist();
+        (old, changes)
+    }
+
+    /// The current state of all alarms
+    pub fn list(&self) -> Vec<AlarmState> {
+        self.states
+            .lock()
+            .expect("alarm state mutex poisoned")
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Remove and return the recorded events, oldest first
+    pub fn take_events(&self) -> Vec<Event> {
+        core::mem::take(&mut *self.events.lock().expect("alarm events mutex poisoned")).into()
+    }
+
+    /// The most recent events, oldest first
+    pub fn events(&self) -> Vec<Event> {
+        self.events
+            .lock()
+            .expect("alarm events mutex poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Remember the event and append it to the journal.
+    /// `alarm` is [`ALL_ALARMS`] for events concerning all alarms.
+    pub fn record(&self, alarm: &str, kind: EventKind) {
+        let event = Event {
+            time: now(),
+            alarm: alarm.to_owned(),
+            kind,
+        };
+        // the lock keeps the journal in the same order
+        let mut events = self.events.lock().expect("alarm events mutex poisoned");
+        if let Some(path) = &self.journal_file {
+            if let Err(e) = journal::append(path, &event) {
+                error!(
+                    "Unable to append to the journal {}: {e}. The event was: {event:?}",
+                    path.display()
+                );
+            };
+        };
+        if events.len() >= MAX_EVENTS {
+            events.pop_front();
+        };
+        events.push_back(event);
+    }
+
+    /// Stop calling for this alarm until it clears
+    pub fn acknowledge(&self, name: &str) -> Result<(), AlarmError> {
+        {
+            let mut states = self.states.lock().expect("alarm state mutex poisoned");
+            let state = states
+                .get_mut(name)
+                .ok_or_else(|| AlarmError::NotFound(name.to_owned()))?;
+            if !state.active {
+                return Err(AlarmError::NotActive(name.to_owned()));
+            };
+            state.acknowledged = true;
+        }
+        info!(alarm_name = name, "Alarm acknowledged.");
+        self.record(name, EventKind::Acknowledged);
+        self.persist();
+        Ok(())
+    }
+
+    /// Do not call for this alarm for the given duration.
+    /// A duration of zero lifts the silence.
+    pub fn silence(&self, name: &str, duration: Duration) -> Result<u64, AlarmError> {
+        let until = now()
+            .checked_add(duration.as_secs())
+            .ok_or(AlarmError::DurationTooLong(duration.as_secs()))?;
+        {
+            let mut states = self.states.lock().expect("alarm state mutex poisoned");
+            let state = states
+                .get_mut(name)
+                .ok_or_else(|| AlarmError::NotFound(name.to_owned()))?;
+            state.silenced_until = if duration.is_zero() {
+                None
+            } else {
+                Some(until)
+            };
+        }
+        info!(
+            alarm_name = name,
+            "Alarm silenced for {}s.",
+            duration.as_secs()
+        );
+        self.record(name, EventKind::Silenced { until });
+        self.persist();
+        Ok(until)
+    }
+
+    /// End maintenance that reached its end time
+    fn expire_maintenance(&self, maintenance: &mut MaintenanceState) {
+        for (alarm, x) in maintenance.expire(now()) {
+            let alarm = alarm.as_deref().unwrap_or(ALL_ALARMS);
+            info!(alarm_name = alarm, source = %x.source, "Maintenance expired.");
+            self.record(
+                alarm,
+                EventKind::MaintenanceEnded {
+                    source: x.source,
+                    expired: true,
+                },
+            );
+        }
+    }
+
+    /// The maintenance started at runtime that is still running
+    pub fn maintenance(&self) -> MaintenanceState {
+        let mut maintenance = self.maintenance.lock().expect("maintenance mutex poisoned");
+        self.expire_maintenance(&mut maintenance);
+        maintenance.clone()
+    }
+
+    /// Start maintenance of a single alarm (`Some(name)`) or of all alarms (`None`) for the
+    /// given duration, at most `maintenance.max_duration`. A duration of zero ends it.
+    ///
+    /// Returns the unix time it ends.
+    pub fn start_maintenance(
+        &self,
+        config: &Config,
+        alarm: Option<&str>,
+        duration: Duration,
+        source: MaintenanceSource,
+    ) -> Result<u64, AlarmError> {
+        if let Some(name) = alarm {
+            if !config.alarms.iter().any(|x| x.name == name) {
+                return Err(AlarmError::NotFound(name.to_owned()));
+            };
+        };
+        let duration = duration.min(config.maintenance.max_duration);
+        let until = now().saturating_add(duration.as_secs());
+        let name = alarm.unwrap_or(ALL_ALARMS);
+        {
+            let mut maintenance = self.maintenance.lock().expect("maintenance mutex poisoned");
+            if duration.is_zero() {
+                if let Some(x) = maintenance.set(alarm, None) {
+                    info!(alarm_name = name, %source, "Maintenance ended.");
+                    self.record(
+                        name,
+                        EventKind::MaintenanceEnded {
+                            source: x.source,
+                            expired: false,
+                        },
+                    );
+                };
+            } else {
+                maintenance.set(alarm, Some(Maintenance { until, source }));
+                info!(
+                    alarm_name = name,
+                    %source,
+                    "Maintenance started for {}s, calls are suppressed.",
+                    duration.as_secs()
+                );
+                self.record(name, EventKind::MaintenanceStarted { until, source });
+            };
+        }
+        self.persist();
+        Ok(until)
+    }
+
+    /// React to the maintenance input reporting the maintenance state (`on`) or not.
+    ///
+    /// Only a change starts or ends maintenance, so it still expires while the input stays on.
+    pub fn maintenance_input(&self, config: &Config, on: bool) {
+        let Some(input) = &config.maintenance.input else {
+            return;
+        };
+        let scopes = if input.alarms.is_empty() {
+            vec![None]
+        } else {
+            input.alarms.iter().map(|x| Some(x.as_str())).collect()
+        };
+        {
+            let mut maintenance = self.maintenance.lock().expect("maintenance mutex poisoned");
+            if core::mem::replace(&mut maintenance.input_on, on) == on {
+                return;
+            };
+        }
+        for alarm in scopes {
+            if on {
+                // errors are impossible, the names were validated
+                let _ = self.start_maintenance(
+                    config,
+                    alarm,
+                    config.maintenance.max_duration,
+                    MaintenanceSource::Input,
+                );
+                continue;
+            };
+            // maintenance started via the admin API outlasts the input
+            let from_input = self
+                .maintenance
+                .lock()
+                .expect("maintenance mutex poisoned")
+                .get(alarm)
+                .is_some_and(|x| x.source == MaintenanceSource::Input);
+            if from_input {
+                let _ =
+                    self.start_maintenance(config, alarm, Duration::ZERO, MaintenanceSource::Input);
+            };
+        }
+    }
+
+    /// Record the value `is_alarm` of one input of a voting alarm and combine it with the
+    /// values of the other inputs
+    pub fn vote(
+        &self,
+        alarm: &AlarmConfig,
+        voting: &VotingConfig,
+        input: usize,
+        is_alarm: bool,
+    ) -> Vote {
+        let now = now();
+        let mut states = self.states.lock().expect("alarm state mutex poisoned");
+        let state = states
+            .entry(alarm.name.clone())
+            .or_insert_with(|| AlarmState::new(&alarm.name));
+        state.inputs.resize(voting.input_count(), None);
+        state.inputs[input] = Some(InputValue {
+            alarm: is_alarm,
+            at: now,
+        });
+        let inputs = voting.current(&state.inputs, now);
+        let combined = voting.combine(&inputs);
+        let process = match combined {
+            Some(true) => {
+                !state.active || inputs.iter().position(|x| *x == Some(true)) == Some(input)
+            }
+            _ => true,
+        };
+        let (disagreement, started_disagreeing) = if voting.disagree(&inputs) {
+            let started = state.disagreeing_since.is_none();
+            let since = *state.disagreeing_since.get_or_insert(now);
+            // raised and repeated by the first input still working
+            let raise = voting.disagreement.as_ref().is_some_and(|x| {
+                now.saturating_sub(since) >= x.after.as_secs()
+                    && inputs.iter().position(Option::is_some) == Some(input)
+            });
+            (raise.then_some(true), started)
+        } else {
+            state.disagreeing_since = None;
+            (voting.disagreement.as_ref().map(|_| false), false)
+        };
+        Vote {
+            alarm: combined,
+            process,
+            disagreement,
+            started_disagreeing,
+            inputs,
+        }
+    }
+
+    /// Record the values of signals and evaluate the rules using them.
+    ///
+    /// Returns the rules with a known result, like a CMI repeating the state of an input.
+    pub fn update_signals<'a>(
+        &self,
+        config: &'a Config,
+        values: &[(&str, f64)],
+    ) -> Vec<(&'a AlarmConfig, bool)> {
+        if values.is_empty() {
+            return Vec::new();
+        };
+        let mut state = self.rules.lock().expect("rule state mutex poisoned");
+        for (signal, value) in values {
+            state.set(signal, *value);
+        }
+        let now = clock::now_ms();
+        config
+            .alarms
+            .iter()
+            .filter_map(|alarm| {
+                let rule = alarm.rule.as_ref()?;
+                let signals = rule.expr.signals();
+                if !values.iter().any(|(x, _)| signals.contains(x)) {
+                    return None;
+                };
+                let result = rule.expr.eval(&alarm.name, &mut state, now)?;
+                state.changed(&alarm.name, result);
+                Some((alarm, result))
+            })
+            .collect()
+    }
+
+    /// Evaluate the rules with timers. Returns the rules whose result changed.
+    pub fn tick_rules<'a>(&self, config: &'a Config) -> Vec<(&'a AlarmConfig, bool)> {
+        let mut state = self.rules.lock().expect("rule state mutex poisoned");
+        let now = clock::now_ms();
+        config
+            .alarms
+            .iter()
+            .filter_map(|alarm| {
+                let rule = alarm.rule.as_ref().filter(|x| x.expr.has_timer())?;
+                let result = rule.expr.eval(&alarm.name, &mut state, now)?;
+                state
+                    .changed(&alarm.name, result)
+                    .then_some((alarm, result))
+            })
+            .collect()
+    }
+
+    /// Register a call round for `alarm`. If its priority pre-empts, the running rounds of
+    /// alarms with a lower priority are cancelled.
+    ///
+    /// Returns `None` if a round for this alarm is still running.
+    fn start_round(&self, config: &Config, alarm: &AlarmConfig) -> Option<Arc<AtomicBool>> {
+        let mut rounds = self.rounds.lock().expect("call rounds mutex poisoned");
+        if rounds.iter().any(|x| x.alarm == alarm.name) {
+            return None;
+        };
+        if config.priorities.get(alarm.priority).preempt {
+            for round in rounds
+                .iter()
+                .filter(|x| x.priority < alarm.priority && !x.cancelled.load(Ordering::Relaxed))
+            {
+                round.cancelled.store(true, Ordering::Relaxed);
+                info!(
+                    alarm_name = round.alarm,
+                    "Call round pre-empted by the {} priority alarm {}.",
+                    alarm.priority,
+                    alarm.name
+                );
+                self.record(
+                    &round.alarm,
+                    EventKind::CallPreempted {
+                        by: alarm.name.clone(),
+                    },
+                );
+            }
+        };
+        let cancelled = Arc::new(AtomicBool::new(false));
+        rounds.push(Round {
+            alarm: alarm.name.clone(),
+            priority: alarm.priority,
+            cancelled: cancelled.clone(),
+        });
+        Some(cancelled)
+    }
+
+    fn end_round(&self, alarm: &str) {
+        self.rounds
+            .lock()
+            .expect("call rounds mutex poisoned")
+            .retain(|x| x.alarm != alarm);
+    }
+
+    /// Stop the running call round of this alarm, e.g. because it cleared
+    fn cancel_round(&self, alarm: &str) {
+        for round in self
+            .rounds
+            .lock()
+            .expect("call rounds mutex poisoned")
+            .iter()
+            .filter(|x| x.alarm == alarm)
+        {
+            round.cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Block until no call round of an alarm with a higher priority is running
+    fn wait_for_higher_rounds(&self, priority: Priority) {
+        while self
+            .rounds
+            .lock()
+            .expect("call rounds mutex poisoned")
+            .iter()
+            .any(|x| x.priority > priority && !x.cancelled.load(Ordering::Relaxed))
+        {
+            std::thread::sleep(ROUND_POLL_INTERVAL);
+        }
+    }
+
+    /// Why calls for this alarm are suppressed by maintenance, if they are
+    fn maintenance_reason(&self, config: &Config, alarm: &str) -> Option<String> {
+        let mut maintenance = self.maintenance.lock().expect("maintenance mutex poisoned");
+        self.expire_maintenance(&mut maintenance);
+        if let Some(x) = maintenance.covering(alarm) {
+            return Some(format!(
+                "in maintenance via the {} for another {}s",
+                x.source,
+                x.until.saturating_sub(now())
+            ));
+        };
+        config
+            .maintenance
+            .window_at(alarm, clock::now_utc())
+            .map(|x| {
+                format!(
+                    "in the maintenance window until {}",
+                    x.to.format(&Rfc3339).unwrap_or_default()
+                )
+            })
+    }
+}
+
+/// Send the AMI command to asterisk, calling each of `endpoints`.
+///
+/// Calls of alarms with a higher priority go first. Stops early once `cancelled` is set.
+/// Calls for a test alarm are not counted in the originate metrics, and the endpoints called
+/// do not get the all-clear notification.
+fn send_ami_command(
+    config: &Config,
+    metrics: &Metrics,
+    alarms: &Alarms,
+    alarm: &AlarmConfig,
+    endpoints: &[String],
+    cancelled: &AtomicBool,
+    trigger: &Trigger,
+) -> Result<(), Box<dyn std::error::Error>> {
+    alarms.wait_for_higher_rounds(alarm.priority);
+    metrics.ami_reconnect();
+    let conn_result = config.asterisk_connection();
+    metrics.set_ami_connected(conn_result.is_ok());
+    let mut ami_conn = conn_result?;
+    let counted = !matches!(trigger, Trigger::Test);
+
+    for (i, external_number) in endpoints.iter().enumerate() {
+        alarms.wait_for_higher_rounds(alarm.priority);
+        if cancelled.load(Ordering::Relaxed) {
+            info!(
+                alarm_name = alarm.name,
+                "Call round stopped, not calling {}.",
+                endpoints[i..].join(", ")
+            );
+            break;
+        };
+        let external_number = external_number.as_str();
+        let action_id = ami::action_id();
+        let command = config
+            .asterisk
+            .originate_action(external_number, &action_id);
+        if counted {
+            metrics.originate_attempt(external_number);
+        };
+        match ami_conn.send_action(command) {
+            Ok(response) => {
+                debug!(
+                    alarm_name = alarm.name,
+                    endpoint = external_number,
+                    "Got this response from asterisk: {response}."
+                );
+                if response.lines().any(|l| l.starts_with("Response: Success")) {
+                    if counted {
+                        metrics.originate_success(external_number);
+                    };
+                    let mut states = alarms.states.lock().expect("alarm state mutex poisoned");
+                    match states.get_mut(&alarm.name) {
+                        Some(state)
+                            if counted
+                                && !state.called_endpoints.iter().any(|x| x == external_number) =>
+                        {
+                            state.called_endpoints.push(external_number.to_owned());
+                        }
+                        _ => (),
+                    };
+                    drop(states);
+                    alarms.record(
+                        &alarm.name,
+                        EventKind::CallSucceeded {
+                            endpoint: external_number.to_owned(),
+                            action_id,
+                        },
+                    );
+                } else {
+                    if counted {
+                        metrics.originate_failure(external_number);
+                    };
+                    alarms.record(
+                        &alarm.name,
+                        EventKind::CallFailed {
+                            endpoint: external_number.to_owned(),
+                            action_id: Some(action_id),
+                            error: response,
+                        },
+                    );
+                };
+            }
+            Err(e) => {
+                if counted {
+                    metrics.originate_failure(external_number);
+                };
+                warn!(
+                    alarm_name = alarm.name,
+                    endpoint = external_number,
+                    "Error sending Command to asterisk for external number {external_number}: {e}."
+                );
+                alarms.record(
+                    &alarm.name,
+                    EventKind::CallFailed {
+                        endpoint: external_number.to_owned(),
+                        action_id: Some(action_id),
+                        error: e.to_string(),
+                    },
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Tell whoever was called for the alarm that it cleared
+fn notify_clear(
+    config: &Config,
+    metrics: &Metrics,
+    alarms: &Alarms,
+    alarm: &AlarmConfig,
+    on_clear: &OnClearConfig,
+    event: &ClearEvent,
+) {
+    let record = |target: &str, action_id: Option<String>, res: Result<(), String>| {
+        let kind = match res {
+            Ok(()) => EventKind::ClearNotified {
+                target: target.to_owned(),
+                action_id,
+            },
+            Err(error) => {
+                warn!(
+                    alarm_name = alarm.name,
+                    "Unable to send the all-clear notification to {target}: {error}"
+                );
+                EventKind::ClearNotificationFailed {
+                    target: target.to_owned(),
+                    action_id,
+                    error,
+                }
+            }
+        };
+        alarms.record(&alarm.name, kind);
+    };
+    if let (Some(exten), false) = (&on_clear.exten, event.called_endpoints.is_empty()) {
+        let context = on_clear
+            .context
+            .as_deref()
+            .unwrap_or(&config.asterisk.execute_context);
+        let variables = [
+            ("ALARM_NAME", alarm.name.clone()),
+            ("ALARM_DURATION", event.duration_seconds.to_string()),
+        ];
+        metrics.ami_reconnect();
+        let conn_result = config.asterisk_connection();
+        metrics.set_ami_connected(conn_result.is_ok());
+        match conn_result {
+            Ok(mut ami_conn) => {
+                for endpoint in event.called_endpoints {
+                    let action_id = ami::action_id();
+                    let command = config
+                        .asterisk
+                        .originate_action_to(endpoint, context, exten, &variables, &action_id);
+                    metrics.originate_attempt(endpoint);
+                    let res = match ami_conn.send_action(command) {
+                        Ok(response)
+                            if response.lines().any(|l| l.starts_with("Response: Success")) =>
+                        {
+                            metrics.originate_success(endpoint);
+                            Ok(())
+                        }
+                        Ok(response) => {
+                            metrics.originate_failure(endpoint);
+                            Err(response)
+                        }
+                        Err(e) => {
+                            metrics.originate_failure(endpoint);
+                            Err(e.to_string())
+                        }
+                    };
+                    record(endpoint, Some(action_id), res);
+                }
+            }
+            Err(e) => record("asterisk", None, Err(e.to_string())),
+        };
+    };
+    if let Some(url) = &on_clear.webhook {
+        record(
+            &url.to_string(),
+            None,
+            notify::post_json(config, url, event).map_err(|e| e.to_string()),
+        );
+    };
+    info!(
+        alarm_name = alarm.name,
+        "Finished the all-clear notifications."
+    );
+}
+
+/// Send a call round for the alarm and count it
+fn call_round(
+    config: &Config,
+    metrics: &Metrics,
+    alarms: &Alarms,
+    alarm: &AlarmConfig,
+    route: &OwnedRoute,
+    cancelled: &AtomicBool,
+    trigger: &Trigger,
+) {
+    match send_ami_command(
+        config,
+        metrics,
+        alarms,
+        alarm,
+        &route.endpoints,
+        cancelled,
+        trigger,
+    ) {
+        Ok(()) => {
+            if let Some(state) = alarms
+                .states
+                .lock()
+                .expect("alarm state mutex poisoned")
+                .get_mut(&alarm.name)
+            {
+                state.calls_sent += 1;
+            };
+            alarms.persist();
+            info!(
+                alarm_name = alarm.name,
+                cmi_addr = trigger.cmi_addr(),
+                trigger = %trigger,
+                schedule_rule = route.rule,
+                "Alarm received, all commands send to asterisk successfully."
+            );
+        }
+        Err(e) => {
+            error!(
+                alarm_name = alarm.name,
+                cmi_addr = trigger.cmi_addr(),
+                trigger = %trigger,
+                "Tried to send AMI commands to asterisk, but got this error: {e}"
+            );
+            alarms.record(
+                &alarm.name,
+                EventKind::CallFailed {
+                    endpoint: String::new(),
+                    action_id: None,
+                    error: e.to_string(),
+                },
+            );
+        }
+    };
+}
+
+/// Send the call round of the alarm as if it was raised, for a test alarm.
+///
+/// The state of the alarm and its all-clear notifications are not touched and the calls are
+/// not counted in the originate metrics; maintenance, silences and acknowledgements do not
+/// suppress the calls. Returns the endpoints called in the background.
+pub fn test_call_round(
+    config: &Arc<Config>,
+    metrics: &Arc<Metrics>,
+    alarms: &Arc<Alarms>,
+    alarm: &AlarmConfig,
+) -> Vec<String> {
+    let endpoints = schedule::route(config, clock::now_utc(), alarm.priority, Duration::ZERO)
+        .endpoints
+        .into_iter()
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    alarms.record(
+        &alarm.name,
+        EventKind::TestCall {
+            endpoints: endpoints.clone(),
+        },
+    );
+    if alarms.dry_run {
+        return endpoints;
+    };
+    let (config, metrics, alarms) = (config.clone(), metrics.clone(), alarms.clone());
+    let (name, called) = (alarm.name.clone(), endpoints.clone());
+    // calling blocks while talking to asterisk
+    smol::unblock(move || {
+        if let Some(alarm) = config.alarms.iter().find(|x| x.name == name) {
+            let cancelled = AtomicBool::new(false);
+            if let Err(e) = send_ami_command(
+                &config,
+                &metrics,
+                &alarms,
+                alarm,
+                &called,
+                &cancelled,
+                &Trigger::Test,
+            ) {
+                error!(
+                    alarm_name = alarm.name,
+                    "Tried to send the AMI commands of the test alarm, but got this error: {e}"
+                );
+                alarms.record(
+                    &alarm.name,
+                    EventKind::CallFailed {
+                        endpoint: String::new(),
+                        action_id: None,
+                        error: e.to_string(),
+                    },
+                );
+            };
+        };
+    })
+    .detach();
+    endpoints
+}
+
+/// A [`schedule::Route`] that can be moved to the thread sending the calls
+#[derive(Debug)]
+struct OwnedRoute {
+    rule: Option<String>,
+    endpoints: Vec<String>,
+}
+
+/// React to one input of an alarm reporting the alarm state (`is_alarm`) or the good state.
+///
+/// The value of an input of a voting alarm is combined with the other inputs first,
+/// and may raise or clear the disagreement alarm.
+pub fn process_input(
+    config: &Arc<Config>,
+    metrics: &Arc<Metrics>,
+    alarms: &Arc<Alarms>,
+    alarm: &AlarmConfig,
+    input: usize,
+    is_alarm: bool,
+    trigger: Trigger,
+) {
+    let Some(voting) = &alarm.voting else {
+        process(config, metrics, alarms, alarm, is_alarm, trigger);
+        return;
+    };
+    let vote = alarms.vote(alarm, voting, input, is_alarm);
+    debug!(
+        alarm_name = alarm.name,
+        inputs = ?vote.inputs,
+        combined = vote.alarm,
+        "Input {input} of a voting alarm reported {}.",
+        if is_alarm { "the alarm state" } else { "the good state" }
+    );
+    // e.g. right after the start, before every CMI sent its value; the disagreement alarm
+    // is only raised if it lasts
+    if vote.started_disagreeing {
+        info!(
+            alarm_name = alarm.name,
+            inputs = ?vote.inputs,
+            "The inputs of the alarm disagree or one of them failed."
+        );
+    };
+    if let (Some(x), true) = (vote.alarm, vote.process) {
+        process(config, metrics, alarms, alarm, x, trigger.clone());
+    };
+    let disagreement_alarm = config
+        .alarms
+        .iter()
+        .find(|x| x.disagreement_of.as_deref() == Some(alarm.name.as_str()));
+    if let (Some(x), Some(disagreement_alarm)) = (vote.disagreement, disagreement_alarm) {
+        process(config, metrics, alarms, disagreement_alarm, x, trigger);
+    };
+}
+
+/// React to an alarm reporting the alarm state (`is_alarm`) or the good state.
+///
+/// This is the single code path for COE packets and rule timers; test alarms only send the
+/// call round, see [`test_call_round`].
+/// Calls are sent in the background, so packets are processed meanwhile.
+pub fn process(
+    config: &Arc<Config>,
+    metrics: &Arc<Metrics>,
+    alarms: &Arc<Alarms>,
+    alarm: &AlarmConfig,
+    is_alarm: bool,
+    trigger: Trigger,
+) {
+    if !is_alarm {
+        let cleared = {
+            let mut states = alarms.states.lock().expect("alarm state mutex poisoned");
+            let state = states
+                .entry(alarm.name.clone())
+                .or_insert_with(|| AlarmState::new(&alarm.name));
+            // reset the alarm repeat count
+            state.calls_sent = 0;
+            state.acknowledged = false;
+            let raised_at = state.active_since.take();
+            let called_endpoints = core::mem::take(&mut state.called_endpoints);
+            core::mem::replace(&mut state.active, false)
+                .then(|| (raised_at.unwrap_or(now()), called_endpoints))
+        };
+        if let Some((raised_at, called_endpoints)) = cleared {
+            alarms.cancel_round(&alarm.name);
+            let cleared_at = now();
+            let duration_seconds = cleared_at.saturating_sub(raised_at);
+            metrics.alarm_cleared(&alarm.name);
+            info!(
+                alarm_name = alarm.name,
+                cmi_addr = trigger.cmi_addr(),
+                trigger = %trigger,
+                duration_seconds,
+                "Alarm cleared."
+            );
+            alarms.record(
+                &alarm.name,
+                EventKind::Cleared {
+                    trigger: trigger.to_string(),
+                    duration_seconds,
+                },
+            );
+            alarms.persist();
+            if alarm.on_clear.is_some() && !alarms.dry_run {
+                let (config, metrics, alarms) = (config.clone(), metrics.clone(), alarms.clone());
+                let name = alarm.name.clone();
+                // notifying blocks while talking to asterisk and the webhook
+                smol::unblock(move || {
+                    let Some(alarm) = config.alarms.iter().find(|x| x.name == name) else {
+                        return;
+                    };
+                    let Some(on_clear) = &alarm.on_clear else {
+                        return;
+                    };
+                    let event = ClearEvent {
+                        event: "cleared",
+                        alarm: &alarm.name,
+                        raised_at,
+                        cleared_at,
+                        duration_seconds,
+                        called_endpoints: &called_endpoints,
+                    };
+                    notify_clear(&config, &metrics, &alarms, alarm, on_clear, &event);
+                })
+                .detach();
+            };
+        };
+        return;
+    };
+
+    let maintenance = alarms.maintenance_reason(config, &alarm.name);
+    // decide whether and whom to call while holding the lock, but call without it
+    let (newly_raised, suppressed, route) = {
+        let mut states = alarms.states.lock().expect("alarm state mutex poisoned");
+        let state = states
+            .entry(alarm.name.clone())
+            .or_insert_with(|| AlarmState::new(&alarm.name));
+        let newly_raised = !state.active;
+        if newly_raised {
+            state.active = true;
+            state.active_since = Some(now());
+        };
+        let active_for =
+            Duration::from_secs(now().saturating_sub(state.active_since.unwrap_or(now())));
+        let route = schedule::route(config, clock::now_utc(), alarm.priority, active_for);
+        // check if we have already sent the alarm to many times
+        let suppressed = if maintenance.is_some() {
+            maintenance
+        } else if state.acknowledged {
+            Some("acknowledged".to_owned())
+        } else if state.is_silenced() {
+            Some("silenced".to_owned())
+        } else if config
+            .asterisk
+            .repeat_alarm
+            .is_some_and(|max_nr_of_repeats| max_nr_of_repeats < state.calls_sent)
+        {
+            Some("already called the max number of times".to_owned())
+        } else if alarm.priority < route.min_priority {
+            Some(format!(
+                "{} priority, deferred by the schedule rule {} until the priority is at least {}",
+                alarm.priority,
+                route.rule.unwrap_or_default(),
+                route.min_priority
+            ))
+        } else {
+            None
+        };
+        let route = OwnedRoute {
+            rule: route.rule.map(str::to_owned),
+            endpoints: route.endpoints.into_iter().map(str::to_owned).collect(),
+        };
+        (newly_raised, suppressed, route)
+    };
+    if newly_raised {
+        warn!(
+            alarm_name = alarm.name,
+            cmi_addr = trigger.cmi_addr(),
+            trigger = %trigger,
+            "Alarm raised."
+        );
+        metrics.alarm_raised(&alarm.name);
+        alarms.record(
+            &alarm.name,
+            EventKind::Raised {
+                trigger: trigger.to_string(),
+            },
+        );
+        alarms.persist();
+    } else {
+        // the CMI repeats the alarm state every few seconds
+        debug!(
+            alarm_name = alarm.name,
+            cmi_addr = trigger.cmi_addr(),
+            trigger = %trigger,
+            "Alarm still active."
+        );
+    };
+
+    if let Some(reason) = suppressed {
+        info!(
+            alarm_name = alarm.name,
+            "Received an Alarm, but not calling: {reason}."
+        );
+        if newly_raised {
+            alarms.record(&alarm.name, EventKind::CallSuppressed { reason });
+        };
+        return;
+    };
+    if alarms.dry_run {
+        if let Some(state) = alarms
+            .states
+            .lock()
+            .expect("alarm state mutex poisoned")
+            .get_mut(&alarm.name)
+        {
+            state.calls_sent += 1;
+        };
+        alarms.record(
+            &alarm.name,
+            EventKind::WouldCall {
+                endpoints: route.endpoints,
+            },
+        );
+        return;
+    };
+    let Some(cancelled) = alarms.start_round(config, alarm) else {
+        info!(
+            alarm_name = alarm.name,
+            "Received an Alarm, but not calling: the previous call round is still running."
+        );
+        return;
+    };
+    let (config, metrics, alarms) = (config.clone(), metrics.clone(), alarms.clone());
+    let name = alarm.name.clone();
+    // calling blocks while talking to asterisk
+    smol::unblock(move || {
+        if let Some(alarm) = config.alarms.iter().find(|x| x.name == name) {
+            call_round(
+                &config, &metrics, &alarms, alarm, &route, &cancelled, &trigger,
+            );
+        };
+        alarms.end_round(&name);
+    })
+    .detach();
+}
@@ -1,8 +1,10 @@
-//! Handles reading of packets from ami
+//! Client of the asterisk manager interface (AMI): reading messages and sending actions
 
 use std::{
     io::{Read, Write},
     net::TcpStream,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use rustls::{ClientConnection, StreamOwned};
@@ -46,18 +48,53 @@ impl From<std::str::Utf8Error> for AmiError {
 }
 impl std::error::Error for AmiError {}
 
+/// Numbers the actions sent by this process
+static NEXT_ACTION: AtomicU64 = AtomicU64::new(1);
+
+/// A new ActionID, unique across restarts: `taaa-<unix time>-<counter>`.
+/// Asterisk repeats it in the response and in events concerning the action.
+pub fn action_id() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    format!("taaa-{now}-{}", NEXT_ACTION.fetch_add(1, Ordering::Relaxed))
+}
+
+/// A logged-in connection to AMI, usually from [`crate::Config::asterisk_connection`].
+///
+/// Sends `Action: Logoff` when dropped.
 pub struct AmiConnection {
     stream: StreamOwned<ClientConnection, TcpStream>,
-    buffer: String,
+    /// bytes received, but not yet returned as a line or message
+    buffer: Vec<u8>,
 }
 impl AmiConnection {
+    /// Wrap a TLS stream to AMI; nothing is read or sent yet
     pub fn new(stream: StreamOwned<ClientConnection, TcpStream>) -> Self {
         Self {
             stream,
-            buffer: String::new(),
+            buffer: Vec::new(),
         }
     }
 
+    /// Read the bytes available on the stream into the buffer (blocking)
+    fn fill_buffer(&mut self) -> Result<(), AmiError> {
+        const READ_BUF_LEN: usize = 256;
+        let mut buf = [0_u8; READ_BUF_LEN];
+
+        let bytes_read = self.stream.read(&mut buf).map_err(AmiError::Read)?;
+        if bytes_read == 0 {
+            return Err(AmiError::NoBytes);
+        };
+        if buf[..bytes_read].contains(&0) {
+            self.buffer.clear();
+            return Err(AmiError::EofBeforeNeline);
+        };
+        self.buffer.extend_from_slice(&buf[..bytes_read]);
+        Ok(())
+    }
+
     /// Read the first line from an AMI stream.
     /// In that line, asterisk will push its Version number.
     ///
@@ -65,81 +102,54 @@ impl AmiConnection {
     /// - The Version line, if everything was successful.
     /// - AmiError, if reading failed or the read values are not utf8-parsable.
     pub fn read_version_line(&mut self) -> Result<String, AmiError> {
-        const VERSION_LINE_BUF_LEN: usize = 128;
-        let mut buf = [0_u8; VERSION_LINE_BUF_LEN];
-
-        let mut version_line = String::new();
-
         loop {
-            let bytes_read = self.stream.read(&mut buf).map_err(AmiError::Read)?;
-            if bytes_read == 0 {
-                continue;
-            };
-            let first_nullbyte = buf.iter().position(|x| *x == 0);
             // seek to the first \n
-            if let Some(idx) = buf.iter().position(|x| *x == b'\n') {
-                if idx == 0 || buf[idx - 1] != b'\r' {
+            if let Some(idx) = self.buffer.iter().position(|x| *x == b'\n') {
+                if idx == 0 || self.buffer[idx - 1] != b'\r' {
                     return Err(AmiError::IsolatedNewline);
                 }
                 // the spec does not say how the first line is supposed to look like..
                 // so we just ignore the first line completely
                 // but we need to check that they are actually utf-8 first
-                version_line.push_str(std::str::from_utf8(&buf[0..=idx - 1])?);
-                self.buffer.push_str(std::str::from_utf8(
-                    &buf[idx + 1..first_nullbyte.unwrap_or(VERSION_LINE_BUF_LEN)],
-                )?);
+                let version_line = std::str::from_utf8(&self.buffer[..idx - 1])?.to_owned();
+                self.buffer.drain(..=idx);
                 return Ok(version_line);
-            } else if first_nullbyte.is_none() {
-                version_line.push_str(std::str::from_utf8(&buf)?);
-            } else {
-                return Err(AmiError::EofBeforeNeline);
-            }
+            };
+            self.fill_buffer()?;
         }
     }
 
-    /// Read the next response (blocking)
+    /// Read the next message, a response or an event (blocking)
     ///
-    /// On Error, the internal buffer is reset. It may be impossible to recover from this.
-    pub fn read_next_response(&mut self) -> Result<String, AmiError> {
-        const MESSAGE_BUF_LEN: usize = 256;
-        let mut buf = [0_u8; MESSAGE_BUF_LEN];
-
+    /// A message may arrive in several reads, and a read may contain several messages.
+    pub fn read_next_message(&mut self) -> Result<String, AmiError> {
         loop {
-            let bytes_read = self.stream.read(&mut buf).map_err(AmiError::Read)?;
-            if bytes_read == 0 {
-                return Err(AmiError::NoBytes);
+            if let Some(pos) = self.buffer.windows(4).position(|x| x == b"\r\n\r\n") {
+                // the message without the last \r\n, which carries no semantics since it occurs
+                // at the end of a Message where it is mandatory by the Protocol
+                let message = self.buffer.drain(..pos + 4).collect::<Vec<_>>();
+                return Ok(std::str::from_utf8(&message[..pos + 2])?.to_owned());
             };
-            let first_nullbyte = buf.iter().position(|x| *x == 0);
-            // convert bytes to utf-8
-            let as_str =
-                match std::str::from_utf8(&buf[..first_nullbyte.unwrap_or(MESSAGE_BUF_LEN)]) {
-                    Ok(x) => x,
-                    Err(e) => {
-                        self.buffer.clear();
-                        return Err(e)?;
-                    }
-                };
-            if let Some(first_double_crlf_pos) = as_str.find("\r\n\r\n") {
-                self.buffer.push_str(&as_str[..first_double_crlf_pos + 2]);
-                // self.buffer now contains the entire Message we care about (minus the last \r\n
-                // which carry no semantics since they occur at the end of a Message where they are
-                // mandatory by the Protocol)
+            self.fill_buffer()?;
+        }
+    }
 
-                // We now return self.buffer and set it to an empty string
-                let mut new_buf = String::new();
-                core::mem::swap(&mut new_buf, &mut self.buffer);
-                return Ok(new_buf);
-            } else {
-                self.buffer.push_str(as_str);
+    /// Read the next response (blocking), skipping the events sent meanwhile
+    pub fn read_next_response(&mut self) -> Result<String, AmiError> {
+        loop {
+            let message = self.read_next_message()?;
+            if message.starts_with("Event:") {
+                trace!("Skipping an event while waiting for a response: {message}");
                 continue;
             };
+            return Ok(message);
         }
     }
 
     /// Send an action to the Server and read the next response.
     pub fn send_action(&mut self, action: String) -> Result<String, AmiError> {
         self.stream
-            .write(action.as_bytes())
+            .write_all(action.as_bytes())
             .map_err(AmiError::Write)?;
         self.read_next_response()
     }
@@ -0,0 +1,92 @@
+//! Capture of the received COE datagrams, to replay them later
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Where to write the captured datagrams
+#[derive(Debug, PartialEq, Eq)]
+pub struct CaptureConfig {
+    pub file: PathBuf,
+}
+impl From<CaptureConfigData> for CaptureConfig {
+    fn from(value: CaptureConfigData) -> Self {
+        Self {
+            file: value.file.into(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CaptureConfigData {
+    /// every datagram is appended as a line of JSON, e.g. `/var/lib/ta-asterisk-alarm/capture.jsonl`
+    pub file: String,
+}
+
+/// A single received datagram
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Datagram {
+    /// milliseconds since the unix epoch
+    pub at_ms: u64,
+    pub from: SocketAddr,
+    /// the bytes received, as hex
+    pub data: String,
+}
+impl Datagram {
+    pub fn new(at_ms: u64, from: SocketAddr, data: &[u8]) -> Self {
+        Self {
+            at_ms,
+            from,
+            data: data.iter().map(|x| format!("{x:02x}")).collect(),
+        }
+    }
+
+    /// The bytes received
+    pub fn bytes(&self) -> Result<Vec<u8>, String> {
+        self.data
+            .as_bytes()
+            .chunks(2)
+            .map(|x| {
+                core::str::from_utf8(x)
+                    .ok()
+                    .filter(|x| x.len() == 2)
+                    .and_then(|x| u8::from_str_radix(x, 16).ok())
+                    .ok_or_else(|| format!("{:?} is not hex", self.data))
+            })
+            .collect()
+    }
+}
+
+/// Append a single datagram as a line of JSON
+pub fn append(path: &Path, datagram: &Datagram) -> Result<(), Box<dyn std::error::Error>> {
+    let mut line = serde_json::to_vec(datagram)?;
+    line.push(b'\n');
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(&line)?;
+    Ok(())
+}
+
+/// Read all datagrams of a capture, in the order they were received
+pub fn read(path: &Path) -> Result<Vec<Datagram>, Box<dyn std::error::Error>> {
+    let file =
+        std::fs::File::open(path).map_err(|e| format!("Unable to open {}: {e}", path.display()))?;
+    let mut res = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        };
+        res.push(
+            serde_json::from_str::<Datagram>(&line)
+                .map_err(|e| format!("line {} of {}: {e}", i + 1, path.display()))?,
+        );
+    }
+    Ok(res)
+}
@@ -0,0 +1,725 @@
+//! The command line interface of the `ta-asterisk-alarm` binary: the service and the
+//! diagnostic subcommands, see [`run`]
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand};
+use coe::{Packet, Payload};
+use smol::net::UdpSocket;
+
+use time::{OffsetDateTime, Time};
+use time_tz::TimeZone;
+
+use crate::{
+    alarm::EventKind,
+    capture, clock,
+    config::{Config, ConfigData, ConfigError},
+    engine::Engine,
+    journal, logging,
+    schedule::{Day, Priority},
+    service, signal, simulate,
+    source::{self, Resolver, Source, Sources},
+};
+
+/// Reads COE packets from a CMI and tells asterisk to make outgoing calls
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub(crate) struct Cli {
+    /// Path of the config file
+    #[arg(short, long, default_value = "/etc/ta-asterisk-alarm/config.yaml")]
+    pub config: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Run the service (default)
+    Run,
+    /// Read and check the config without connecting anywhere, report all issues, then exit
+    CheckConfig,
+    /// Connect and login to AMI, then logoff
+    TestAmi,
+    /// Ask the running service on `/readyz` whether it is ready, for container health checks.
+    /// Passes if the http section is not configured.
+    Healthcheck,
+    /// Call a single endpoint with the configured context and extension
+    TestCall {
+        /// e.g. PJSIP/1111222233334444@sip_trunk_endpoint
+        endpoint: String,
+    },
+    /// Print every COE payload received on the CMI listen sockets, decoded, without sending
+    /// alarms
+    #[command(visible_alias = "sniff")]
+    Listen {
+        /// only packets from this address, subnet or hostname; can be given more than once
+        #[arg(long, value_parser = source::parse_source)]
+        from: Vec<Source>,
+        /// only payloads to this CAN-ID
+        #[arg(long)]
+        node: Option<u8>,
+        /// only payloads of this PDO, numbered as in the web-GUI of the CMI
+        #[arg(long)]
+        pdo: Option<u8>,
+        /// show a table of the last value per source, CAN-ID and PDO instead, updated every
+        /// second
+        #[arg(long)]
+        table: bool,
+    },
+    /// Print the events in the journal, oldest first, as JSON lines or CSV
+    Journal {
+        /// only events at or after this time: a date (UTC), RFC 3339 or unix time
+        #[arg(long, value_parser = journal::parse_time)]
+        from: Option<u64>,
+        /// only events before this time: a date (UTC), RFC 3339 or unix time
+        #[arg(long, value_parser = journal::parse_time)]
+        to: Option<u64>,
+        /// only events of this alarm input and those concerning all alarms
+        #[arg(long, visible_alias = "input")]
+        alarm: Option<String>,
+        /// only calls and notifications to endpoints or urls containing this
+        #[arg(long)]
+        endpoint: Option<String>,
+        /// print CSV instead of JSON lines
+        #[arg(long)]
+        csv: bool,
+    },
+    /// Act as a CMI: send COE to the service from a scripted scenario, then exit. Does not
+    /// read the config.
+    Simulate {
+        /// a YAML file with the steps to run, see the README
+        scenario: PathBuf,
+        /// `host:port` to send to instead of the target of the scenario
+        #[arg(long)]
+        target: Option<String>,
+    },
+    /// Feed a capture through the alarms with simulated time and print the events, without
+    /// calling anyone
+    Replay {
+        /// a file written with capture.file in the config
+        file: PathBuf,
+        /// keep the simulated time running this many seconds after the last datagram, e.g.
+        /// for rules with `for`
+        #[arg(long, default_value_t = 0)]
+        tail_seconds: u64,
+        /// print CSV instead of JSON lines
+        #[arg(long)]
+        csv: bool,
+    },
+}
+
+/// Parse the command line of the process and run its command, by default the service.
+///
+/// This is the whole `ta-asterisk-alarm` binary, e.g. to ship its subcommands in another
+/// binary. `--help` lists the subcommands; on invalid arguments this prints the usage and
+/// exits the process.
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    // the diagnostic subcommands only log to stdout
+    let diagnostic = |f: &dyn Fn() -> Result<(), Box<dyn std::error::Error>>| {
+        tracing::subscriber::with_default(logging::bootstrap_subscriber(), f)
+    };
+    let command = cli.command.unwrap_or(Command::Run);
+    // checking reports all issues instead of failing on the first one
+    if let Command::CheckConfig = command {
+        return diagnostic(&|| check_config(&cli.config));
+    };
+    // the simulator usually runs on another host than the service
+    if let Command::Simulate { scenario, target } = &command {
+        return diagnostic(&|| {
+            simulate::run(&simulate::Scenario::read(scenario)?, target.as_deref())
+        });
+    };
+
+    // setup config
+    // until we know which outputs are configured, log to stdout
+    let config = Arc::new(tracing::subscriber::with_default(
+        logging::bootstrap_subscriber(),
+        || Config::create(&cli.config),
+    )?);
+
+    match command {
+        Command::Run => service::run(cli.config, config),
+        Command::CheckConfig | Command::Simulate { .. } => {
+            unreachable!("handled before reading the config")
+        }
+        Command::TestAmi => diagnostic(&|| test_ami(&config)),
+        Command::Healthcheck => diagnostic(&|| healthcheck(&config)),
+        Command::TestCall { endpoint } => diagnostic(&|| test_call(&config, &endpoint)),
+        Command::Listen {
+            from,
+            node,
+            pdo,
+            table,
+        } => {
+            let filter = ListenFilter {
+                from: (!from.is_empty()).then_some(source::Sources(from)),
+                node,
+                pdo,
+            };
+            tracing::subscriber::with_default(logging::bootstrap_subscriber(), || {
+                listen(&config, filter, table)
+            })
+        }
+        Command::Journal {
+            from,
+            to,
+            alarm,
+            endpoint,
+            csv,
+        } => {
+            let query = journal::Query {
+                from,
+                to,
+                alarm,
+                endpoint,
+            };
+            diagnostic(&|| journal(&config, &query, csv))
+        }
+        Command::Replay {
+            file,
+            tail_seconds,
+            csv,
+        } => {
+            // the events go to stdout, so they can be piped
+            tracing::subscriber::with_default(logging::stderr_subscriber(), || {
+                replay(&config, &file, tail_seconds, csv)
+            })
+        }
+    }
+}
+
+fn hh_mm(time: Time) -> String {
+    format!("{:02}:{:02}", time.hour(), time.minute())
+}
+
+/// Check the config offline, print every issue found or a summary of the valid config
+pub(crate) fn check_config(config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let (config_data, issues) = match ConfigData::read(config_path) {
+        Ok(x) => {
+            let issues = x.validate();
+            (Some(x), issues)
+        }
+        // environment variables or files referenced by the config are missing
+        Err(e) => match e.downcast::<ConfigError>() {
+            Ok(e) => match *e {
+                ConfigError::Invalid(issues) => (None, issues),
+                e => return Err(e.into()),
+            },
+            Err(e) => return Err(e),
+        },
+    };
+    if !issues.is_empty() {
+        println!("{} has {} issue(s):", config_path.display(), issues.len());
+        for issue in &issues {
+            println!("- {issue}");
+        }
+        return Err("The config is invalid.".into());
+    };
+    let config: Config = config_data
+        .expect("config data is read if there are no issues")
+        .try_into()?;
+    println!("Config is valid.");
+    println!(
+        "Listening for COE on {}",
+        config
+            .cmi
+            .listen
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    for alarm in &config.alarms {
+        if let Some(x) = &alarm.disagreement_of {
+            println!(
+                "Alarm {}: the inputs of {x} disagree, priority {}",
+                alarm.name, alarm.priority
+            );
+            continue;
+        };
+        if let Some(rule) = &alarm.rule {
+            println!(
+                "Alarm {}: when {}, priority {}",
+                alarm.name, rule.when, alarm.priority
+            );
+            continue;
+        };
+        println!(
+            "Alarm {}: from {}, CAN-ID {}, PDO {}, {}, priority {}",
+            alarm.name,
+            alarm.expect_from_addr,
+            alarm.expect_index,
+            alarm.expect_pdo,
+            if alarm.circuit_is_normally_closed {
+                "normally closed"
+            } else {
+                "normally open"
+            },
+            alarm.priority
+        );
+        if let Some(voting) = &alarm.voting {
+            for (i, x) in voting.inputs.iter().enumerate() {
+                println!(
+                    "- input {}: from {}, CAN-ID {}, PDO {}",
+                    i + 1,
+                    x.expect_from_addr,
+                    x.expect_index,
+                    x.expect_pdo
+                );
+            }
+            println!(
+                "- vote: {} of {} input(s){}",
+                voting.vote,
+                voting.input_count(),
+                voting
+                    .input_timeout
+                    .map(|x| format!(", inputs fail after {}s", x.as_secs()))
+                    .unwrap_or_default()
+            );
+        };
+    }
+    for signal in &config.signals {
+        println!(
+            "Signal {}: from {}, CAN-ID {}, PDO {}",
+            signal.name, signal.expect_from_addr, signal.expect_index, signal.expect_pdo
+        );
+    }
+    println!(
+        "Calls to: {}",
+        config.asterisk.call_external_endpoints.join(", ")
+    );
+    if let Some(schedule) = &config.schedule {
+        println!(
+            "Schedule in {} with {} holiday(s):",
+            schedule.timezone.name(),
+            schedule.holidays.len()
+        );
+        for rule in &schedule.rules {
+            println!(
+                "- {}: {} {}-{}, calls {}{}{}",
+                rule.name,
+                if rule.days.is_empty() {
+                    "every day".to_owned()
+                } else {
+                    rule.days
+                        .iter()
+                        .map(|x| match x {
+                            Day::Weekday(x) => x.to_string(),
+                            Day::Holiday => "holiday".to_owned(),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                },
+                hh_mm(rule.from),
+                hh_mm(rule.to),
+                if rule.call_external_endpoints.is_empty() {
+                    "the default endpoints".to_owned()
+                } else {
+                    rule.call_external_endpoints.join(", ")
+                },
+                rule.escalate
+                    .iter()
+                    .map(|x| format!(
+                        ", after {} min also {}",
+                        x.after.as_secs() / 60,
+                        x.call_external_endpoints.join(", ")
+                    ))
+                    .collect::<String>(),
+                if rule.min_priority > Priority::Low {
+                    format!(", only for priority {} and above", rule.min_priority)
+                } else {
+                    String::new()
+                },
+            );
+        }
+        println!(
+            "Applies now: {}",
+            schedule
+                .rule_at(OffsetDateTime::now_utc())
+                .map_or("no rule, calls the default endpoints", |x| &x.name)
+        );
+    };
+    let maintenance = &config.maintenance;
+    let scope = |alarms: &[String]| {
+        if alarms.is_empty() {
+            "all alarms".to_owned()
+        } else {
+            alarms.join(", ")
+        }
+    };
+    for window in &maintenance.windows {
+        println!(
+            "Maintenance window of {}: {} to {}",
+            scope(&window.alarms),
+            window.from,
+            window.to
+        );
+    }
+    if let Some(input) = &maintenance.input {
+        println!(
+            "Maintenance input for {}: from {}, CAN-ID {}, PDO {}, maintenance while {}",
+            scope(&input.alarms),
+            input.expect_from_addr,
+            input.expect_index,
+            input.expect_pdo,
+            if input.maintenance_when_on {
+                "ON"
+            } else {
+                "OFF"
+            }
+        );
+    };
+    println!(
+        "Maintenance ends after {} min at the latest",
+        maintenance.max_duration.as_secs() / 60
+    );
+    Ok(())
+}
+
+/// Ask the running service whether it is ready; passes if the http section is not configured
+pub(crate) fn healthcheck(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    let Some(http) = &config.http else {
+        println!("The http section is not configured, there is nothing to check.");
+        return Ok(());
+    };
+    // a service listening on all addresses is asked on the loopback address
+    let mut addr = http.listen;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    };
+    let mut stream = std::net::TcpStream::connect_timeout(&addr, TIMEOUT)
+        .map_err(|e| format!("Unable to connect to {addr}: {e}"))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.write_all(
+        format!("GET /readyz HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n").as_bytes(),
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.lines().next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(format!("Not ready ({status}): {}", body.trim()).into());
+    };
+    println!("Ready: {}", body.trim());
+    Ok(())
+}
+
+/// Connect and login to AMI. The connection logs off when dropped.
+pub(crate) fn test_ami(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let _conn = config.asterisk_connection()?;
+    println!(
+        "Connected and logged in to AMI at {}:{}.",
+        config.asterisk.host,
+        config.asterisk.port()
+    );
+    Ok(())
+}
+
+/// Originate a single call to `endpoint`
+pub(crate) fn test_call(config: &Config, endpoint: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut conn = config.asterisk_connection()?;
+    let response = conn.send_action(
+        config
+            .asterisk
+            .originate_action(endpoint, &crate::ami::action_id()),
+    )?;
+    println!("Asterisk responded:\n{response}");
+    if response.lines().any(|l| l.starts_with("Response: Success")) {
+        Ok(())
+    } else {
+        Err("Asterisk did not accept the originate action.")?
+    }
+}
+
+/// Print the events in the journal matching `query`
+pub(crate) fn journal(
+    config: &Config,
+    query: &journal::Query,
+    csv: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(journal_config) = &config.journal else {
+        return Err("There is no journal, set journal.file in the config.".into());
+    };
+    let events = journal::read(&journal_config.file, query)?;
+    if csv {
+        print!("{}", journal::to_csv(&events));
+    } else {
+        for event in &events {
+            println!("{}", serde_json::to_string(event)?);
+        }
+    };
+    Ok(())
+}
+
+/// Which payloads `listen` shows
+#[derive(Debug)]
+pub(crate) struct ListenFilter {
+    /// `None` for all sources
+    pub from: Option<Sources>,
+    pub node: Option<u8>,
+    /// numbered as in the web-GUI
+    pub pdo: Option<u8>,
+}
+impl ListenFilter {
+    fn matches(&self, ip: IpAddr, payload: &Payload, resolver: &Resolver) -> bool {
+        if let Some(from) = &self.from {
+            if !from.contains(ip, resolver) {
+                return false;
+            };
+        };
+        self.node.unwrap_or(payload.node()) == payload.node()
+            && self.pdo.unwrap_or(payload.pdo_index() + 1) == payload.pdo_index() + 1
+    }
+}
+
+/// The last value of a CAN-ID and PDO, for the table of `listen`
+#[derive(Debug)]
+struct LastValue {
+    value_type: &'static str,
+    unit_id: u8,
+    value: String,
+    at: Instant,
+    count: u64,
+}
+
+/// The last values by source, CAN-ID and on-wire PDO, and the packets that did not parse
+#[derive(Debug, Default)]
+struct ListenTable {
+    values: BTreeMap<(IpAddr, u8, u8), LastValue>,
+    parse_errors: u64,
+}
+impl ListenTable {
+    fn print(&self) {
+        // clear the screen and move to the top left
+        print!("\x1b[2J\x1b[H");
+        println!(
+            "{:<40} {:>6} {:>7} {:>7} {:<15} {:<10} {:>12} {:>6} {:>7}",
+            "SOURCE", "CAN-ID", "PDO", "ON-WIRE", "TYPE", "UNIT", "VALUE", "AGE", "COUNT"
+        );
+        for ((ip, node, pdo), x) in &self.values {
+            println!(
+                "{:<40} {:>6} {:>7} {:>7} {:<15} {:<10} {:>12} {:>5}s {:>7}",
+                ip.to_string(),
+                node,
+                u16::from(*pdo) + 1,
+                pdo,
+                x.value_type,
+                format!("{} {}", x.unit_id, signal::unit_symbol(x.unit_id)),
+                x.value,
+                x.at.elapsed().as_secs(),
+                x.count
+            );
+        }
+        if self.parse_errors > 0 {
+            println!("{} packet(s) were not valid COE", self.parse_errors);
+        };
+    }
+}
+
+/// Print every COE payload received on `socket` and matching `filter`, forever. With a
+/// table, record them there instead.
+async fn print_packets(
+    socket: UdpSocket,
+    filter: Arc<ListenFilter>,
+    resolver: Arc<Resolver>,
+    table: Option<Arc<Mutex<ListenTable>>>,
+) -> Result<(), std::io::Error> {
+    let mut buf = [0_u8; 252];
+    loop {
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        let addr = std::net::SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        let packet: Packet = match buf[..len].try_into() {
+            Ok(x) => x,
+            Err(e) => {
+                match &table {
+                    Some(table) => {
+                        table
+                            .lock()
+                            .expect("listen table mutex poisoned")
+                            .parse_errors += 1
+                    }
+                    None => println!("{addr}: unable to parse COE packet: {e}"),
+                };
+                continue;
+            }
+        };
+        let now = OffsetDateTime::now_utc();
+        for payload in packet {
+            if !filter.matches(addr.ip(), &payload, &resolver) {
+                continue;
+            };
+            let value = signal::display_value(&payload);
+            if let Some(table) = &table {
+                let mut table = table.lock().expect("listen table mutex poisoned");
+                let count = table
+                    .values
+                    .get(&(addr.ip(), payload.node(), payload.pdo_index()))
+                    .map_or(0, |x| x.count);
+                table.values.insert(
+                    (addr.ip(), payload.node(), payload.pdo_index()),
+                    LastValue {
+                        value_type: signal::value_type(&payload),
+                        unit_id: payload.unit_id(),
+                        value,
+                        at: Instant::now(),
+                        count: count + 1,
+                    },
+                );
+                continue;
+            };
+            // NOTE: the web-gui shows the pdo one higher than it is on-wire
+            println!(
+                "{:02}:{:02}:{:02}Z {addr}: CAN-ID {} PDO {} (on-wire {}): {}, unit {} {}: {value}",
+                now.hour(),
+                now.minute(),
+                now.second(),
+                payload.node(),
+                u16::from(payload.pdo_index()) + 1,
+                payload.pdo_index(),
+                signal::value_type(&payload),
+                payload.unit_id(),
+                signal::unit_symbol(payload.unit_id()),
+            );
+        }
+    }
+}
+
+/// Print every COE payload matching `filter` received on all listen sockets, forever
+pub(crate) fn listen(
+    config: &Config,
+    filter: ListenFilter,
+    table: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let resolver = Arc::new(Resolver::default());
+    if let Some(from) = &filter.from {
+        resolver.resolve(from.hosts().map(ToOwned::to_owned).collect(), Duration::MAX);
+    };
+    let filter = Arc::new(filter);
+    let table = table.then(|| Arc::new(Mutex::new(ListenTable::default())));
+    smol::block_on(async {
+        let sockets = config.cmi_listen_sockets().await?;
+        let mut tasks = Vec::new();
+        for socket in sockets {
+            println!("Listening for COE packets on {}", socket.local_addr()?);
+            tasks.push(smol::spawn(print_packets(
+                socket,
+                filter.clone(),
+                resolver.clone(),
+                table.clone(),
+            )));
+        }
+        if let Some(table) = table {
+            smol::spawn(async move {
+                loop {
+                    smol::Timer::after(Duration::from_secs(1)).await;
+                    table.lock().expect("listen table mutex poisoned").print();
+                }
+            })
+            .detach();
+        };
+        // they only return on errors
+        for task in tasks {
+            task.await?;
+        }
+        Ok(())
+    })
+}
+
+/// Evaluate the rules with timers at every simulated second from `next_ms` up to and including
+/// `to_ms`, independent of when the datagrams arrive; `next_ms` is moved past the last tick.
+fn tick_until(engine: &Engine, clock: &clock::Simulation, next_ms: &mut u64, to_ms: u64) {
+    if !engine
+        .config()
+        .alarms
+        .iter()
+        .any(|x| x.rule.as_ref().is_some_and(|x| x.expr.has_timer()))
+    {
+        return;
+    };
+    while *next_ms <= to_ms {
+        clock.set(*next_ms);
+        engine.tick();
+        let Some(next) = next_ms.checked_add(1000) else {
+            break;
+        };
+        *next_ms = next;
+    }
+}
+
+/// Replay the datagrams of a capture with simulated time, print the events of the alarms.
+///
+/// The time is simulated for the whole process until this returns.
+pub(crate) fn replay(
+    config: &Arc<Config>,
+    file: &Path,
+    tail_seconds: u64,
+    csv: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let datagrams = capture::read(file)?;
+    let (Some(first), Some(last)) = (datagrams.first(), datagrams.last()) else {
+        return Err(format!("{} contains no datagrams", file.display()).into());
+    };
+    let (first_ms, last_ms) = (first.at_ms, last.at_ms);
+    // hostnames resolve to their current addresses, not those at the time of the capture
+    let engine = Engine::dry_run(config.clone());
+    // back to the real time when returning
+    let clock = clock::simulate(first_ms);
+
+    let mut events = Vec::new();
+    // the service ticks once per second, here at the whole seconds after the first datagram
+    let mut next_tick_ms = (first_ms / 1000).saturating_add(1).saturating_mul(1000);
+    let mut previous_ms = first_ms;
+    for datagram in &datagrams {
+        tick_until(&engine, &clock, &mut next_tick_ms, datagram.at_ms);
+        previous_ms = previous_ms.max(datagram.at_ms);
+        clock.set(previous_ms);
+        engine.handle_datagram(&datagram.bytes()?, datagram.from);
+        events.extend(engine.alarms().take_events());
+    }
+    tick_until(
+        &engine,
+        &clock,
+        &mut next_tick_ms,
+        previous_ms.saturating_add(tail_seconds.saturating_mul(1000)),
+    );
+    events.extend(engine.alarms().take_events());
+    drop(clock);
+
+    if csv {
+        print!("{}", journal::to_csv(&events));
+    } else {
+        for event in &events {
+            println!("{}", serde_json::to_string(event)?);
+        }
+    };
+    // the summary goes to stderr, so the events can be piped
+    let raised = |name: &str| {
+        events
+            .iter()
+            .filter(|x| x.alarm == name && matches!(x.kind, EventKind::Raised { .. }))
+            .count()
+    };
+    eprintln!(
+        "Replayed {} datagrams over {}s.",
+        datagrams.len(),
+        last_ms.saturating_sub(first_ms) / 1000
+    );
+    for alarm in &config.alarms {
+        match raised(&alarm.name) {
+            0 => eprintln!("- {}: not raised", alarm.name),
+            x => eprintln!("- {}: raised {x} time(s)", alarm.name),
+        };
+    }
+    Ok(())
+}
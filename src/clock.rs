@@ -0,0 +1,74 @@
+//! The current time, simulated while replaying a capture
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use time::OffsetDateTime;
+
+/// Milliseconds since the unix epoch to use as current time, 0 for the real time
+static SIMULATED_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Milliseconds since the unix epoch
+pub fn now_ms() -> u64 {
+    match SIMULATED_MS.load(Ordering::Relaxed) {
+        0 => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| u64::try_from(x.as_millis()).unwrap_or(u64::MAX))
+            .unwrap_or_default(),
+        x => x,
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+    now_ms() / 1000
+}
+
+pub fn now_utc() -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp_nanos(i128::from(now_ms()) * 1_000_000)
+        .unwrap_or_else(|_| OffsetDateTime::now_utc())
+}
+
+/// Simulated time in the whole process; the real time is back once this is dropped.
+///
+/// Only one simulation may exist at a time.
+#[derive(Debug)]
+pub struct Simulation(());
+impl Simulation {
+    /// Use `at_ms` as the current time from now on
+    pub fn set(&self, at_ms: u64) {
+        SIMULATED_MS.store(at_ms.max(1), Ordering::Relaxed);
+    }
+}
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        SIMULATED_MS.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Use `at_ms` as the current time in the whole process until the simulation is dropped
+pub fn simulate(at_ms: u64) -> Simulation {
+    let res = Simulation(());
+    res.set(at_ms);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulation_ends_when_dropped() {
+        let simulation = simulate(1_500);
+        assert_eq!(now_ms(), 1_500);
+        assert_eq!(now(), 1);
+        simulation.set(0);
+        assert_eq!(now_ms(), 1);
+        simulation.set(86_400_000);
+        assert_eq!(now_utc().unix_timestamp(), 86_400);
+        drop(simulation);
+        assert!(now() > 1_700_000_000);
+    }
+}
@@ -1,11 +1,10 @@
 //! Configuration parameters for the TA->Asterisk sync
 
 use std::{
-    fs::File,
     io::BufReader,
-    net::{IpAddr, TcpStream},
-    path::Path,
-    sync::Arc, time::Duration,
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock}, time::Duration,
 };
 
 use rustls::{pki_types::TrustAnchor, ClientConfig, ClientConnection};
@@ -14,169 +13,832 @@ use smol::net::UdpSocket;
 use tracing::{debug, error, event, trace, warn, Level};
 
 use crate::ami::{AmiConnection, AmiError};
+use crate::capture::{CaptureConfig, CaptureConfigData};
+use crate::journal::{JournalConfig, JournalConfigData};
+use crate::logging::LoggingConfig;
+use crate::maintenance::{MaintenanceConfig, MaintenanceConfigData};
+use crate::notify::{OnClearConfig, OnClearConfigData};
+use crate::persist::{StateConfig, StateConfigData};
+use crate::rule::{self, RuleConfig, RuleConfigData};
+use crate::schedule::{
+    PrioritiesConfig, PrioritiesConfigData, Priority, ScheduleConfig, ScheduleConfigData,
+};
+use crate::signal::{SignalConfig, SignalConfigData};
+use crate::source::{Sources, DEFAULT_RESOLVE_INTERVAL};
+use crate::vote::{VotingConfig, VotingConfigData};
+
+mod env;
+mod secret;
+mod validate;
+pub use secret::Secret;
+pub use validate::ConfigIssue;
+
+/// Everything that can go wrong when converting [`ConfigData`] to [`Config`]
+#[derive(Debug)]
+pub enum ConfigError {
+    /// an IP address or socket address could not be parsed
+    AddrParse(core::net::AddrParseError),
+    /// `cmi` contains some, but not all of the fields defining an alarm
+    IncompleteCmiAlarm,
+    /// neither `cmi` nor `alarms` define an alarm
+    NoAlarm,
+    /// two alarms have the same name
+    DuplicateAlarmName(String),
+    /// semantic validation found these issues
+    Invalid(Vec<ConfigIssue>),
+    /// the schedule could not be parsed
+    Schedule(String),
+    /// the maintenance windows or input could not be parsed
+    Maintenance(String),
+    /// the notifications of an alarm could not be parsed
+    OnClear(String),
+    /// the allowed sources of an input could not be parsed
+    Source(String),
+    /// the redundant inputs of an alarm could not be parsed
+    Voting(String),
+    /// the condition of a rule could not be parsed
+    Rule(String),
+}
+impl core::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::AddrParse(x) => write!(f, "Unable to parse address: {x}"),
+            Self::IncompleteCmiAlarm => write!(
+                f,
+                "cmi needs all of expect_from_addr, expect_index, expect_pdo and circuit_is_normally_closed, or none of them"
+            ),
+            Self::NoAlarm => write!(f, "No alarm is configured in cmi or alarms"),
+            Self::DuplicateAlarmName(x) => write!(f, "The alarm name {x} is used more than once"),
+            Self::Schedule(x) => write!(f, "Invalid schedule: {x}"),
+            Self::Maintenance(x) => write!(f, "Invalid maintenance: {x}"),
+            Self::OnClear(x) => write!(f, "Invalid on_clear: {x}"),
+            Self::Source(x) => write!(f, "Invalid expect_from_addr: {x}"),
+            Self::Voting(x) => write!(f, "Invalid voting: {x}"),
+            Self::Rule(x) => write!(f, "Invalid rule: {x}"),
+            Self::Invalid(issues) => {
+                write!(f, "The config has {} issue(s):", issues.len())?;
+                for issue in issues {
+                    write!(f, "\n- {issue}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl From<core::net::AddrParseError> for ConfigError {
+    fn from(value: core::net::AddrParseError) -> Self {
+        Self::AddrParse(value)
+    }
+}
+impl std::error::Error for ConfigError {}
 
+/// A checked config, see [`Config::create`]
 #[derive(Debug)]
 pub struct Config {
-    pub cmi: CmiConfig,
-    pub asterisk: AsteriskConfig,
+    pub(crate) cmi: CmiConfig,
+    pub(crate) alarms: Vec<AlarmConfig>,
+    pub(crate) signals: Vec<SignalConfig>,
+    pub(crate) asterisk: AsteriskConfig,
+    pub(crate) logging: LoggingConfig,
+    pub(crate) http: Option<HttpConfig>,
+    pub(crate) admin: Option<AdminConfig>,
+    pub(crate) schedule: Option<ScheduleConfig>,
+    pub(crate) maintenance: MaintenanceConfig,
+    pub(crate) priorities: PrioritiesConfig,
+    pub(crate) state: Option<StateConfig>,
+    pub(crate) journal: Option<JournalConfig>,
+    pub(crate) capture: Option<CaptureConfig>,
+}
+/// The config currently in use, which can be replaced at runtime
+#[derive(Debug)]
+pub struct SharedConfig {
+    /// reload from this file
+    pub path: PathBuf,
+    current: RwLock<Arc<Config>>,
 }
+impl SharedConfig {
+    pub fn new(path: PathBuf, config: Arc<Config>) -> Self {
+        Self {
+            path,
+            current: RwLock::new(config),
+        }
+    }
+
+    /// A snapshot of the current config. It does not change during a reload.
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().expect("config lock poisoned").clone()
+    }
+
+    /// Swap in a new config, returning the old one
+    pub fn replace(&self, config: Arc<Config>) -> Arc<Config> {
+        core::mem::replace(
+            &mut *self.current.write().expect("config lock poisoned"),
+            config,
+        )
+    }
+}
+
 impl TryFrom<ConfigData> for Config {
-    type Error = core::net::AddrParseError;
+    type Error = ConfigError;
     fn try_from(value: ConfigData) -> Result<Self, Self::Error> {
+        let mut alarms = Vec::<AlarmConfig>::new();
+        // the alarm defined directly in the cmi section
+        let cmi_alarm = (
+            value.cmi.expect_from_addr,
+            value.cmi.expect_index,
+            value.cmi.expect_pdo,
+            value.cmi.circuit_is_normally_closed,
+        );
+        match cmi_alarm {
+            (
+                Some(expect_from_addr),
+                Some(expect_index),
+                Some(expect_pdo),
+                Some(circuit_is_normally_closed),
+            ) => {
+                alarms.push(AlarmConfig {
+                    name: value.cmi.alarm_name.unwrap_or_else(|| "alarm".to_owned()),
+                    expect_from_addr: expect_from_addr
+                        .as_slice()
+                        .try_into()
+                        .map_err(ConfigError::Source)?,
+                    expect_index,
+                    expect_pdo,
+                    circuit_is_normally_closed,
+                    priority: value.cmi.priority,
+                    on_clear: None,
+                    voting: None,
+                    disagreement_of: None,
+                    rule: None,
+                });
+            }
+            (None, None, None, None) => (),
+            _ => return Err(ConfigError::IncompleteCmiAlarm),
+        };
+        for alarm in value.alarms {
+            if alarms.iter().any(|x| x.name == alarm.name) {
+                return Err(ConfigError::DuplicateAlarmName(alarm.name));
+            };
+            alarms.push(alarm.try_into()?);
+        }
+        // the disagreement alarms of voting inputs have no input of their own
+        let disagreement_alarms = alarms
+            .iter()
+            .filter_map(|alarm| {
+                let x = alarm.voting.as_ref()?.disagreement.as_ref()?;
+                Some(AlarmConfig {
+                    name: x.alarm.clone(),
+                    expect_from_addr: Sources(Vec::new()),
+                    expect_index: alarm.expect_index,
+                    expect_pdo: alarm.expect_pdo,
+                    circuit_is_normally_closed: alarm.circuit_is_normally_closed,
+                    priority: x.priority,
+                    on_clear: None,
+                    voting: None,
+                    disagreement_of: Some(alarm.name.clone()),
+                    rule: None,
+                })
+            })
+            .collect::<Vec<_>>();
+        for alarm in value.rules {
+            if alarms.iter().any(|x| x.name == alarm.name) {
+                return Err(ConfigError::DuplicateAlarmName(alarm.name));
+            };
+            alarms.push(alarm.try_into()?);
+        }
+        for alarm in disagreement_alarms {
+            if alarms.iter().any(|x| x.name == alarm.name) {
+                return Err(ConfigError::DuplicateAlarmName(alarm.name));
+            };
+            alarms.push(alarm);
+        }
+        if alarms.is_empty() {
+            return Err(ConfigError::NoAlarm);
+        };
         Ok(Self {
-            cmi: value.cmi.try_into()?,
+            cmi: CmiConfig {
+                listen: value
+                    .cmi
+                    .listen_addr
+                    .as_slice()
+                    .iter()
+                    .map(|x| parse_listen_addr(x, value.cmi.port.unwrap_or(DEFAULT_COE_PORT)))
+                    .collect::<Result<_, _>>()?,
+                resolve_interval: value
+                    .cmi
+                    .resolve_interval
+                    .map_or(DEFAULT_RESOLVE_INTERVAL, Duration::from_secs),
+            },
+            alarms,
+            signals: value
+                .signals
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_, _>>()
+                .map_err(ConfigError::Source)?,
             asterisk: value.asterisk,
+            logging: value.logging,
+            http: value.http.map(TryInto::try_into).transpose()?,
+            admin: value.admin.map(TryInto::try_into).transpose()?,
+            schedule: value
+                .schedule
+                .map(TryInto::try_into)
+                .transpose()
+                .map_err(ConfigError::Schedule)?,
+            maintenance: value
+                .maintenance
+                .try_into()
+                .map_err(ConfigError::Maintenance)?,
+            priorities: value.priorities.into(),
+            state: value.state.map(Into::into),
+            journal: value.journal.map(Into::into),
+            capture: value.capture.map(Into::into),
         })
     }
 }
 
+/// The config as read from YAML, before it is checked.
+///
+/// Deserialize it, e.g. from a section of another config file or from values built in code,
+/// and convert it with `try_into` to get a [`Config`]. The fields are those of the config
+/// file, see `config.example.yaml`.
+///
+/// ```
+/// use serde::Deserialize;
+/// use ta_asterisk_alarm::{Config, ConfigData};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let data = ConfigData::deserialize(serde_json::json!({
+///     "cmi": {
+///         "listen_addr": "0.0.0.0",
+///         "expect_from_addr": "192.168.10.123",
+///         "expect_index": 12,
+///         "expect_pdo": 1,
+///         "circuit_is_normally_closed": false,
+///     },
+///     "asterisk": {
+///         "host": "pbx.example.com",
+///         "execute_context": "commands",
+///         "execute_exten": "alarm",
+///         "username": "ta-asterisk-alarm",
+///         "secret": "secret",
+///         "call_external_endpoints": ["PJSIP/1111@sip_trunk_endpoint"],
+///         "caller_id": "5555",
+///     },
+/// }))?;
+/// let config = Config::try_from(data)?;
+/// assert_eq!(config.alarms()[0].name(), "alarm");
+/// assert_eq!(config.asterisk().port(), 5039);
+/// # Ok(())
+/// # }
+/// ```
 #[derive(Debug, Deserialize)]
 pub struct ConfigData {
-    pub cmi: CmiConfigData,
-    pub asterisk: AsteriskConfig,
+    pub(crate) cmi: CmiConfigData,
+    #[serde(default)]
+    pub(crate) alarms: Vec<AlarmConfigData>,
+    /// named COE values used in rules
+    #[serde(default)]
+    pub(crate) signals: Vec<SignalConfigData>,
+    /// alarms raised while a condition over the signals holds
+    #[serde(default)]
+    pub(crate) rules: Vec<RuleConfigData>,
+    pub(crate) asterisk: AsteriskConfig,
+    #[serde(default)]
+    pub(crate) logging: LoggingConfig,
+    pub(crate) http: Option<HttpConfigData>,
+    pub(crate) admin: Option<AdminConfigData>,
+    pub(crate) schedule: Option<ScheduleConfigData>,
+    #[serde(default)]
+    pub(crate) maintenance: MaintenanceConfigData,
+    #[serde(default)]
+    pub(crate) priorities: PrioritiesConfigData,
+    pub(crate) state: Option<StateConfigData>,
+    pub(crate) journal: Option<JournalConfigData>,
+    /// write every received COE datagram to a file, to replay it later
+    pub(crate) capture: Option<CaptureConfigData>,
+}
+
+/// The config for the authenticated admin API
+#[derive(Debug)]
+pub struct AdminConfig {
+    /// listen on this address and port
+    pub listen: SocketAddr,
+    /// clients need to send this as bearer token
+    pub token: Secret,
+}
+impl TryFrom<AdminConfigData> for AdminConfig {
+    type Error = core::net::AddrParseError;
+    fn try_from(value: AdminConfigData) -> Result<Self, Self::Error> {
+        Ok(Self {
+            listen: value.listen.parse()?,
+            token: value.token,
+        })
+    }
+}
+#[derive(Debug, Deserialize)]
+pub struct AdminConfigData {
+    /// listen on this address and port, e.g. `127.0.0.1:9443`
+    pub listen: String,
+    pub token: Secret,
 }
 
+/// The config for the HTTP server exposing metrics and health endpoints
+#[derive(Debug)]
+pub struct HttpConfig {
+    /// listen on this address and port
+    pub listen: SocketAddr,
+    /// only ready if we heard from the CMI within this time
+    pub readiness_cmi_window: Option<Duration>,
+    /// connect and login to AMI this often to check that it is reachable
+    pub readiness_ami_check_interval: Option<Duration>,
+}
+impl TryFrom<HttpConfigData> for HttpConfig {
+    type Error = core::net::AddrParseError;
+    fn try_from(value: HttpConfigData) -> Result<Self, Self::Error> {
+        Ok(Self {
+            listen: value.listen.parse()?,
+            readiness_cmi_window: value.readiness_cmi_window.map(Duration::from_secs),
+            readiness_ami_check_interval: value
+                .readiness_ami_check_interval
+                .map(Duration::from_secs),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HttpConfigData {
+    /// listen on this address and port, e.g. `0.0.0.0:9442` or `[::]:9442`
+    pub listen: String,
+    /// in seconds. Default: do not check when we last heard from the CMI
+    pub readiness_cmi_window: Option<u64>,
+    /// in seconds. Default: only connect to AMI on startup and when sending alarms
+    pub readiness_ami_check_interval: Option<u64>,
+}
+
+/// CMIs send COE to this UDP port, unless configured otherwise
+pub const DEFAULT_COE_PORT: u16 = 5442;
+
 #[derive(Debug)]
 pub struct CmiConfig {
-    /// listen on this address
-    pub listen_addr: IpAddr,
-    /// Expect the packet to arrive from this address. Ignore all other packets.
-    pub expect_from_addr: IpAddr,
+    /// listen on these addresses and ports
+    pub listen: Vec<SocketAddr>,
+    /// resolve the hostnames of allowed sources again after this long
+    pub resolve_interval: Duration,
+}
+
+/// A single value or a list of them
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum OneOrMore {
+    One(String),
+    Many(Vec<String>),
+}
+impl OneOrMore {
+    pub fn as_slice(&self) -> &[String] {
+        match self {
+            Self::One(x) => core::slice::from_ref(x),
+            Self::Many(x) => x,
+        }
+    }
+
+    /// The path of the `i`th value for config issues: `path` or `path[i]`
+    pub fn path_of(&self, path: &str, i: usize) -> String {
+        match self {
+            Self::One(_) => path.to_owned(),
+            Self::Many(_) => format!("{path}[{i}]"),
+        }
+    }
+}
+
+/// An IP address listening on `port`, or an address with its own port,
+/// e.g. `0.0.0.0`, `::`, `[::1]` or `[fd00::2]:5443`
+pub fn parse_listen_addr(value: &str, port: u16) -> Result<SocketAddr, core::net::AddrParseError> {
+    if let Ok(x) = value.parse::<SocketAddr>() {
+        return Ok(x);
+    };
+    let ip = value
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(value);
+    Ok(SocketAddr::new(ip.parse()?, port))
+}
+
+/// The config for listening for messages from a CMI
+///
+/// The expect_* fields and circuit_is_normally_closed define a single alarm.
+/// They need to be given all together or not at all.
+#[derive(Debug, Deserialize)]
+pub struct CmiConfigData {
+    /// name of the alarm, used in logs
+    /// Default: "alarm"
+    pub alarm_name: Option<String>,
+    /// listen on this address, or on a list of addresses, e.g. one per VLAN.
+    /// An address may have its own port, e.g. `[fd00::2]:5443`.
+    pub listen_addr: OneOrMore,
+    /// UDP port of addresses without one. Default: 5442
+    pub port: Option<u16>,
+    /// in seconds, how often hostnames in `expect_from_addr` are resolved. Default: 300
+    pub resolve_interval: Option<u64>,
+    /// Expect the packet to arrive from this address, subnet or hostname, or any of a list of
+    /// them. Ignore all other packets.
+    pub expect_from_addr: Option<OneOrMore>,
     /// expect this CAN-ID in messages we get (ignore others)
-    pub expect_index: u8,
+    pub expect_index: Option<u8>,
     /// expect this PDO in messages we get (ignore others)
-    pub expect_pdo: u8,
+    pub expect_pdo: Option<u8>,
+    pub circuit_is_normally_closed: Option<bool>,
+    /// priority of the alarm. Default: normal
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+/// A single alarm input: one digital value sent by a CMI
+#[derive(Debug, PartialEq, Eq)]
+pub struct AlarmConfig {
+    /// name of the alarm, used in logs and the admin API
+    pub(crate) name: String,
+    /// Expect the packet to arrive from any of these sources. Ignore all other packets.
+    pub(crate) expect_from_addr: Sources,
+    /// expect this CAN-ID in messages we get (ignore others)
+    pub(crate) expect_index: u8,
+    /// expect this PDO in messages we get (ignore others)
+    pub(crate) expect_pdo: u8,
     /// IF true:
     /// expect the value ON to be sent; iff OFF is sent (circuit open), originate a call
     /// IF false:
     /// expect the value OFF to be sent; iff ON is sent (circuit closed), originate a call
-    pub circuit_is_normally_closed: bool,
+    pub(crate) circuit_is_normally_closed: bool,
+    pub(crate) priority: Priority,
+    /// notify when the alarm clears
+    pub(crate) on_clear: Option<OnClearConfig>,
+    /// more inputs of the same contact, voting on the alarm state
+    pub(crate) voting: Option<VotingConfig>,
+    /// this is the disagreement alarm of the inputs of this alarm, and has no input itself
+    pub(crate) disagreement_of: Option<String>,
+    /// raised while the condition of this rule holds; a rule has no input itself
+    pub(crate) rule: Option<RuleConfig>,
 }
-impl TryFrom<CmiConfigData> for CmiConfig {
-    type Error = core::net::AddrParseError;
-    fn try_from(value: CmiConfigData) -> Result<Self, Self::Error> {
+impl AlarmConfig {
+    /// Name of the alarm, used in logs, events and the admin API
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Priority of the alarm
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// CAN-ID of the input, 0 for alarms raised by a rule or a disagreement of inputs
+    pub fn expect_index(&self) -> u8 {
+        self.expect_index
+    }
+
+    /// PDO of the input as shown in the CMI web-GUI, 0 for alarms raised by a rule or a
+    /// disagreement of inputs
+    pub fn expect_pdo(&self) -> u8 {
+        self.expect_pdo
+    }
+
+    /// Whether the input sends ON in the good state and OFF for the alarm
+    pub fn circuit_is_normally_closed(&self) -> bool {
+        self.circuit_is_normally_closed
+    }
+
+    /// The sources, CAN-ID and PDO of all inputs, the one of the alarm itself first
+    pub(crate) fn inputs(&self) -> Vec<(&Sources, u8, u8)> {
+        let mut res = vec![(&self.expect_from_addr, self.expect_index, self.expect_pdo)];
+        if let Some(voting) = &self.voting {
+            res.extend(
+                voting
+                    .inputs
+                    .iter()
+                    .map(|x| (&x.expect_from_addr, x.expect_index, x.expect_pdo)),
+            );
+        };
+        res
+    }
+
+    /// Whether both alarms are raised and cleared by the same inputs, regardless of their
+    /// priority and all-clear notification
+    pub(crate) fn same_input(&self, other: &Self) -> bool {
+        self.expect_from_addr == other.expect_from_addr
+            && self.expect_index == other.expect_index
+            && self.expect_pdo == other.expect_pdo
+            && self.circuit_is_normally_closed == other.circuit_is_normally_closed
+            && self.voting == other.voting
+            && self.disagreement_of == other.disagreement_of
+            && self.rule == other.rule
+    }
+}
+impl TryFrom<AlarmConfigData> for AlarmConfig {
+    type Error = ConfigError;
+    fn try_from(value: AlarmConfigData) -> Result<Self, Self::Error> {
         Ok(Self {
-            listen_addr: value.listen_addr.parse()?,
-            expect_from_addr: value.expect_from_addr.parse()?,
+            name: value.name,
+            expect_from_addr: value
+                .expect_from_addr
+                .as_slice()
+                .try_into()
+                .map_err(ConfigError::Source)?,
             expect_index: value.expect_index,
             expect_pdo: value.expect_pdo,
             circuit_is_normally_closed: value.circuit_is_normally_closed,
+            priority: value.priority,
+            on_clear: value
+                .on_clear
+                .map(TryInto::try_into)
+                .transpose()
+                .map_err(ConfigError::OnClear)?,
+            voting: value
+                .voting
+                .map(TryInto::try_into)
+                .transpose()
+                .map_err(ConfigError::Voting)?,
+            disagreement_of: None,
+            rule: None,
+        })
+    }
+}
+impl TryFrom<RuleConfigData> for AlarmConfig {
+    type Error = ConfigError;
+    fn try_from(value: RuleConfigData) -> Result<Self, Self::Error> {
+        let expr = rule::parse(&value.when)
+            .map_err(|e| ConfigError::Rule(format!("{}: {e}", value.name)))?;
+        Ok(Self {
+            name: value.name,
+            expect_from_addr: Sources(Vec::new()),
+            expect_index: 0,
+            expect_pdo: 0,
+            circuit_is_normally_closed: false,
+            priority: value.priority,
+            on_clear: value
+                .on_clear
+                .map(TryInto::try_into)
+                .transpose()
+                .map_err(ConfigError::OnClear)?,
+            voting: None,
+            disagreement_of: None,
+            rule: Some(RuleConfig {
+                when: value.when,
+                expr,
+            }),
         })
     }
 }
 
-/// The config for listening for messages from a CMI
 #[derive(Debug, Deserialize)]
-pub struct CmiConfigData {
-    /// listen on this address
-    pub listen_addr: String,
-    /// Expect the packet to arrive from this address. Ignore all other packets.
-    pub expect_from_addr: String,
-    /// expect this CAN-ID in messages we get (ignore others)
+pub struct AlarmConfigData {
+    pub name: String,
+    /// an address, a subnet or a hostname, or a list of them
+    pub expect_from_addr: OneOrMore,
     pub expect_index: u8,
-    /// expect this PDO in messages we get (ignore others)
     pub expect_pdo: u8,
     pub circuit_is_normally_closed: bool,
+    /// Default: normal
+    #[serde(default)]
+    pub priority: Priority,
+    /// Default: no notification when the alarm clears
+    pub on_clear: Option<OnClearConfigData>,
+    /// the same contact wired into more inputs, e.g. of a second CMI. Default: a single input
+    pub voting: Option<VotingConfigData>,
 }
 
 /// Configuration for the interaction with Asterisk.
 #[derive(Debug, Deserialize)]
 pub struct AsteriskConfig {
     /// The host to make calls to.
-    pub host: String,
+    pub(crate) host: String,
     /// The port to make calls to.
-    /// Default: 5038
-    pub port: Option<u16>,
+    /// Default: 5039
+    pub(crate) port: Option<u16>,
     /// The contexet to send a call in.
-    pub execute_context: String,
+    pub(crate) execute_context: String,
     /// The extension to call.
-    pub execute_exten: String,
+    pub(crate) execute_exten: String,
     /// The priority to start execution at in the given extension.
     /// Default: "1"
-    pub execute_priority: Option<String>,
+    pub(crate) execute_priority: Option<String>,
     /// In addition to global certs, also trust the CAs in this pem file
-    pub trust_extra_pem: Option<String>,
+    pub(crate) trust_extra_pem: Option<String>,
     /// use to login to asterisk
-    pub username: String,
-    pub secret: String,
-    pub call_external_endpoints: Vec<String>,
-    pub caller_id: String,
-    pub repeat_alarm: Option<u32>,
+    pub(crate) username: String,
+    pub(crate) secret: Secret,
+    pub(crate) call_external_endpoints: Vec<String>,
+    pub(crate) caller_id: String,
+    pub(crate) repeat_alarm: Option<u32>,
 }
 
-impl Config {
-    pub fn create() -> Result<Self, Box<dyn std::error::Error>> {
-        let config_path = Path::new("/etc/ta-asterisk-alarm/config.yaml");
-        let f = match File::open(config_path) {
+impl AsteriskConfig {
+    /// Host of the asterisk manager interface (AMI)
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Port of the asterisk manager interface, 5039 unless configured
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(5039)
+    }
+
+    /// User logging in to the asterisk manager interface
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// The endpoints called for an alarm, unless a schedule routes the calls elsewhere
+    pub fn endpoints(&self) -> &[String] {
+        &self.call_external_endpoints
+    }
+
+    /// Caller ID of the calls
+    pub fn caller_id(&self) -> &str {
+        &self.caller_id
+    }
+
+    /// The AMI action that calls `endpoint` and connects it to the configured extension
+    pub(crate) fn originate_action(&self, endpoint: &str, action_id: &str) -> String {
+        self.originate_action_to(
+            endpoint,
+            &self.execute_context,
+            &self.execute_exten,
+            &[],
+            action_id,
+        )
+    }
+
+    /// The AMI action that calls `endpoint` and connects it to `exten` in `context`,
+    /// setting the channel `variables`
+    pub(crate) fn originate_action_to(
+        &self,
+        endpoint: &str,
+        context: &str,
+        exten: &str,
+        variables: &[(&str, String)],
+        action_id: &str,
+    ) -> String {
+        let priority = if let Some(x) = &self.execute_priority {
+            x
+        } else {
+            "1"
+        };
+        let variables = variables
+            .iter()
+            .map(|(name, value)| format!("Variable: {name}={value}\r\n"))
+            .collect::<String>();
+        format!(
+            "Action: Originate\r\nActionID: {action_id}\r\nExten: {}\r\nContext: {}\r\nPriority: {}\r\nChannel: {}\r\nCallerID: {}\r\n{variables}Async: true\r\n\r\n",
+            exten, context, priority,
+            endpoint, self.caller_id,
+        )
+    }
+}
+
+/// Load the CAs in this pem file as trust anchors
+fn load_trust_anchors(
+    pemfile: &str,
+) -> Result<Vec<TrustAnchor<'static>>, Box<dyn std::error::Error>> {
+    let reader = std::fs::File::open(pemfile)?;
+    let mut res = Vec::<TrustAnchor<'static>>::new();
+    for der_obj in rustls_pemfile::certs(&mut BufReader::new(reader)) {
+        match der_obj {
+            Ok(cert) => {
+                res.push(webpki::anchor_from_trusted_cert(&cert)?.to_owned());
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(res)
+}
+
+impl ConfigData {
+    /// Read the config file, without semantic validation.
+    ///
+    /// `${NAME}` is replaced by the environment variable `NAME`, then `TAAA_SECTION__FIELD`
+    /// environment variables override values, then `<key>_file: <path>` is replaced by
+    /// `<key>: <content of the file>`.
+    /// The file is optional if any `TAAA_` environment variable is set.
+    pub(crate) fn read(config_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let overrides = env::overrides();
+        let text = match std::fs::read_to_string(config_path) {
             Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !overrides.is_empty() => {
+                debug!(
+                    "No config file at {}, using only {}* environment variables.",
+                    config_path.display(),
+                    env::PREFIX
+                );
+                String::new()
+            }
             Err(e) => {
                 event!(
                     Level::ERROR,
-                    "config file /etc/asterconf/config.yaml not readable: {e}"
+                    "config file {} not readable: {e}",
+                    config_path.display()
                 );
                 return Err(Box::new(e));
             }
         };
-        let config_data: ConfigData = match serde_yaml::from_reader(f) {
+        let mut value: serde_yaml::Value = match serde_yaml::from_str(&text) {
             Ok(x) => x,
             Err(e) => {
                 event!(Level::ERROR, "config file had syntax errors: {e}");
                 return Err(Box::new(e));
             }
         };
+        let original = value.clone();
+        let mut issues = Vec::new();
+        secret::substitute_env(&mut value, "", &mut issues);
+        issues.extend(env::apply(&mut value, &overrides));
+        secret::read_value_files(&mut value, "", &mut issues);
+        if !issues.is_empty() {
+            return Err(ConfigError::Invalid(issues))?;
+        };
+        // parsing the text keeps the line numbers in the error messages
+        if value == original {
+            match serde_yaml::from_str(&text) {
+                Ok(x) => Ok(x),
+                Err(e) => {
+                    event!(Level::ERROR, "config file had syntax errors: {e}");
+                    Err(Box::new(e))
+                }
+            }
+        } else {
+            match ConfigData::deserialize(env::Lenient(value)) {
+                Ok(x) => Ok(x),
+                Err(e) => {
+                    event!(
+                        Level::ERROR,
+                        "config from the file, {}* environment variables and *_file files is invalid: {e}",
+                        env::PREFIX
+                    );
+                    Err(Box::new(e))
+                }
+            }
+        }
+    }
+}
+
+impl Config {
+    /// All alarms: those of the cmi section, the alarms list, the rules and the disagreement
+    /// alarms of voting inputs
+    pub fn alarms(&self) -> &[AlarmConfig] {
+        &self.alarms
+    }
+
+    /// How to reach asterisk and whom to call
+    pub fn asterisk(&self) -> &AsteriskConfig {
+        &self.asterisk
+    }
+
+    /// Read and check the config file at `config_path`, logging every issue found.
+    ///
+    /// The values are overridden by environment variables, see the README.
+    pub fn create(config_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let log_issues = |issues: &[ConfigIssue]| {
+            for issue in issues {
+                event!(Level::ERROR, "config file is invalid: {issue}");
+            }
+        };
+        let config_data = ConfigData::read(config_path).inspect_err(|e| {
+            if let Some(ConfigError::Invalid(issues)) = e.downcast_ref() {
+                log_issues(issues);
+            };
+        })?;
+        let issues = config_data.validate();
+        if !issues.is_empty() {
+            log_issues(&issues);
+            return Err(ConfigError::Invalid(issues))?;
+        };
         Ok(config_data.try_into()?)
     }
 
-    /// create the UDP socket required
-    pub async fn cmi_listen_socket(&self) -> Result<UdpSocket, std::io::Error> {
-        UdpSocket::bind(format!("{}:5442", self.cmi.listen_addr)).await
+    /// create the UDP sockets required, one per listen address
+    pub(crate) async fn cmi_listen_sockets(&self) -> Result<Vec<UdpSocket>, std::io::Error> {
+        let mut res = Vec::new();
+        for addr in &self.cmi.listen {
+            let socket = UdpSocket::bind(addr).await.map_err(|e| {
+                std::io::Error::new(e.kind(), format!("unable to listen on {addr}: {e}"))
+            })?;
+            res.push(socket);
+        }
+        Ok(res)
     }
 
     /// load additional certs if required by the config
     fn additional_certs(&self) -> Result<Vec<TrustAnchor<'static>>, Box<dyn std::error::Error>> {
         if let Some(pemfile) = &self.asterisk.trust_extra_pem {
             // read pem file given in the file given in the config
-            let reader = std::fs::File::open(pemfile)?;
-            let mut res = Vec::<TrustAnchor<'static>>::new();
-            for der_obj in rustls_pemfile::certs(&mut BufReader::new(reader)) {
-                match der_obj {
-                    Ok(cert) => {
-                        res.push(webpki::anchor_from_trusted_cert(&cert)?.to_owned());
-                    }
-                    Err(e) => {
-                        return Err(e)?;
-                    }
-                }
-            }
-            Ok(res)
+            load_trust_anchors(pemfile)
         } else {
             Ok(std::vec::Vec::<TrustAnchor<'static>>::new())
         }
     }
 
-    /// prepare the stream to talk to asterisk with
-    pub fn asterisk_connection(&self) -> Result<AmiConnection, Box<dyn std::error::Error>> {
-        debug!("Trying to connect to Asterisk AMI. Make sure asterisk is reachable if this hangs or fails!");
-        // setup rustls config (used for TCP stream with asterisk)
-        let asterisk_tcp = match TcpStream::connect(format!(
-            "{}:{}",
-            self.asterisk.host,
-            self.asterisk.port.unwrap_or(5039)
-        )) {
-            Ok(x) => x,
-            Err(e) => {
-                error!(
-                    "Unable to start TCP socket on {}:{} : {e}",
-                    self.asterisk.host,
-                    self.asterisk.port.unwrap_or(5039)
-                );
-                Err(e)?
-            }
-        };
-        // this timeout is very long, because AMI may sometimes wait a long time until it sends
-        // more bytes.
-        asterisk_tcp.set_read_timeout(Some(Duration::from_millis(5000))).expect("statically not-null time given.");
-
+    /// TLS client config trusting webpki roots and the certs in `asterisk.trust_extra_pem`
+    pub(crate) fn tls_client_config(&self) -> Result<ClientConfig, Box<dyn std::error::Error>> {
         let mut roots: Vec<TrustAnchor> = webpki_roots::TLS_SERVER_ROOTS.into();
         let add_certs = match self.additional_certs() {
             Ok(x) => x,
@@ -190,9 +852,32 @@ impl Config {
         };
         roots.extend(add_certs);
         let root_store = rustls::RootCertStore { roots };
-        let tls_config = ClientConfig::builder()
+        Ok(ClientConfig::builder()
             .with_root_certificates(root_store)
-            .with_no_client_auth();
+            .with_no_client_auth())
+    }
+
+    /// prepare the stream to talk to asterisk with
+    pub fn asterisk_connection(&self) -> Result<AmiConnection, Box<dyn std::error::Error>> {
+        debug!("Trying to connect to Asterisk AMI. Make sure asterisk is reachable if this hangs or fails!");
+        // setup rustls config (used for TCP stream with asterisk)
+        let asterisk_tcp =
+            match TcpStream::connect(format!("{}:{}", self.asterisk.host, self.asterisk.port())) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!(
+                        "Unable to start TCP socket on {}:{} : {e}",
+                        self.asterisk.host,
+                        self.asterisk.port()
+                    );
+                    Err(e)?
+                }
+            };
+        // this timeout is very long, because AMI may sometimes wait a long time until it sends
+        // more bytes.
+        asterisk_tcp.set_read_timeout(Some(Duration::from_millis(5000))).expect("statically not-null time given.");
+
+        let tls_config = self.tls_client_config()?;
         // TLS stream to asterisk
         let asterisk_conn = match ClientConnection::new(
             Arc::new(tls_config),
@@ -210,7 +895,8 @@ impl Config {
         trace!("Was able to get this version from ami: {version}.");
         let command = format!(
             "Action: Login\r\nAuthType: plain\r\nUsername: {}\r\nSecret: {}\r\nEvents: off\r\n\r\n",
-            self.asterisk.username, self.asterisk.secret
+            self.asterisk.username,
+            self.asterisk.secret.expose()
         );
         let response = match conn.send_action(command) {
             Ok(x) => x,
@@ -0,0 +1,342 @@
+//! Overriding config values with `TAAA_SECTION__FIELD` environment variables
+
+use serde::{
+    de::{
+        value::{MapDeserializer, SeqDeserializer},
+        Error as _, IntoDeserializer, Visitor,
+    },
+    forward_to_deserialize_any, Deserializer,
+};
+use serde_yaml::{Mapping, Value};
+
+use super::ConfigIssue;
+
+/// Only environment variables starting with this are used
+pub const PREFIX: &str = "TAAA_";
+/// Separates the path segments in the name of an environment variable
+const SEPARATOR: &str = "__";
+
+/// A single environment variable overriding a config value
+#[derive(Debug)]
+pub struct Override {
+    /// name of the environment variable
+    pub name: String,
+    /// lowercase path into the config, e.g. `["asterisk", "host"]`
+    pub path: Vec<String>,
+    pub value: String,
+}
+
+/// All environment variables starting with [`PREFIX`], sorted by name
+pub fn overrides() -> Vec<Override> {
+    let mut res = std::env::vars()
+        .filter_map(|(name, value)| {
+            let path = name
+                .strip_prefix(PREFIX)?
+                .split(SEPARATOR)
+                .map(str::to_ascii_lowercase)
+                .collect();
+            Some(Override { name, path, value })
+        })
+        .collect::<Vec<_>>();
+    res.sort_by(|a, b| a.name.cmp(&b.name));
+    res
+}
+
+/// Set the values of the overrides in the config, creating sections and list entries as
+/// needed. A number selects the entry of a list, e.g. `TAAA_ALARMS__0__NAME`.
+pub fn apply(config: &mut Value, overrides: &[Override]) -> Vec<ConfigIssue> {
+    let mut issues = Vec::new();
+    'overrides: for x in overrides {
+        if x.path.iter().any(String::is_empty) {
+            issues.push(ConfigIssue::new(
+                &x.name,
+                format!("empty path segment; separate the fields with {SEPARATOR}"),
+            ));
+            continue;
+        };
+        let mut current = &mut *config;
+        for segment in &x.path {
+            if current.is_null() {
+                *current = match segment.parse::<usize>() {
+                    Ok(_) => Value::Sequence(Vec::new()),
+                    Err(_) => Value::Mapping(Mapping::new()),
+                };
+            };
+            current = match (current, segment.parse::<usize>()) {
+                (Value::Mapping(mapping), _) => mapping
+                    .entry(Value::String(segment.clone()))
+                    .or_insert(Value::Null),
+                (Value::Sequence(seq), Ok(index)) => {
+                    if index > seq.len() {
+                        issues.push(ConfigIssue::new(
+                            &x.name,
+                            format!("the list has {} entries, add them in order", seq.len()),
+                        ));
+                        continue 'overrides;
+                    };
+                    if index == seq.len() {
+                        seq.push(Value::Null);
+                    };
+                    &mut seq[index]
+                }
+                _ => {
+                    issues.push(ConfigIssue::new(
+                        &x.name,
+                        format!("{segment} is not a field of a section or an index in a list"),
+                    ));
+                    continue 'overrides;
+                }
+            };
+        }
+        *current = override_value(&x.value);
+    }
+    issues
+}
+
+/// Lists are given as YAML, e.g. `[PJSIP/1@trunk, PJSIP/2@trunk]`.
+/// Everything else stays a string and is converted by [`Lenient`] as needed.
+fn override_value(value: &str) -> Value {
+    if value.trim_start().starts_with('[') {
+        if let Ok(x @ Value::Sequence(_)) = serde_yaml::from_str(value) {
+            return x;
+        };
+    };
+    Value::String(value.to_owned())
+}
+
+/// Deserializes a YAML value, accepting strings for numbers and booleans and vice versa.
+///
+/// Values from the environment are always strings.
+pub struct Lenient(pub Value);
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match self.0 {
+                    Value::String(x) => match x.trim().parse() {
+                        Ok(parsed) => visitor.$visit(parsed),
+                        Err(_) => Err(Self::Error::custom(format!(
+                            "invalid value {x:?}, expected {}",
+                            stringify!($visit).trim_start_matches("visit_")
+                        ))),
+                    },
+                    other => other.$method(visitor),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Lenient {
+    type Error = serde_yaml::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Mapping(mapping) => visitor.visit_map(MapDeserializer::new(
+                mapping.into_iter().map(|(k, v)| (Lenient(k), Lenient(v))),
+            )),
+            Value::Sequence(seq) => {
+                visitor.visit_seq(SeqDeserializer::new(seq.into_iter().map(Lenient)))
+            }
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Number(x) => visitor.visit_string(x.to_string()),
+            Value::Bool(x) => visitor.visit_string(x.to_string()),
+            other => other.deserialize_string(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            other => visitor.visit_some(Lenient(other)),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_deserialize_any! {
+        char bytes byte_buf unit unit_struct newtype_struct seq tuple tuple_struct map struct
+        identifier ignored_any i128 u128
+    }
+}
+
+impl<'de> IntoDeserializer<'de, serde_yaml::Error> for Lenient {
+    type Deserializer = Self;
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    fn env(name: &str, value: &str) -> Override {
+        Override {
+            name: format!("{PREFIX}{name}"),
+            path: name.split(SEPARATOR).map(str::to_ascii_lowercase).collect(),
+            value: value.to_owned(),
+        }
+    }
+
+    fn yaml(text: &str) -> Value {
+        serde_yaml::from_str(text).expect("valid YAML")
+    }
+
+    #[test]
+    fn overrides_and_creates_fields() {
+        let mut config = yaml("asterisk:\n  host: a\n  port: 1\n");
+        let issues = apply(
+            &mut config,
+            &[env("ASTERISK__HOST", "b"), env("HTTP__LISTEN_ADDR", "::")],
+        );
+        assert!(issues.is_empty(), "{issues:?}");
+        assert_eq!(
+            config,
+            yaml("asterisk:\n  host: b\n  port: 1\nhttp:\n  listen_addr: '::'\n")
+        );
+    }
+
+    #[test]
+    fn index_past_the_end_appends_to_the_list() {
+        let mut config = yaml("alarms:\n- name: a\n");
+        let issues = apply(
+            &mut config,
+            &[env("ALARMS__0__NAME", "x"), env("ALARMS__1__NAME", "y")],
+        );
+        assert!(issues.is_empty(), "{issues:?}");
+        assert_eq!(config, yaml("alarms:\n- name: x\n- name: y\n"));
+    }
+
+    #[test]
+    fn index_out_of_order_is_an_issue() {
+        let mut config = yaml("alarms:\n- name: a\n");
+        let issues = apply(
+            &mut config,
+            &[env("ALARMS__2__NAME", "z"), env("ALARMS__1__NAME", "y")],
+        );
+        assert_eq!(issues.len(), 1, "{issues:?}");
+        assert_eq!(issues[0].path, "TAAA_ALARMS__2__NAME");
+        assert!(issues[0].message.contains("has 1 entries"), "{issues:?}");
+        assert_eq!(config, yaml("alarms:\n- name: a\n- name: y\n"));
+    }
+
+    #[test]
+    fn scalar_cannot_be_overridden_by_a_section() {
+        let mut config = yaml("asterisk: a\n");
+        let issues = apply(&mut config, &[env("ASTERISK__HOST", "b")]);
+        assert_eq!(issues.len(), 1, "{issues:?}");
+        assert_eq!(issues[0].path, "TAAA_ASTERISK__HOST");
+        assert_eq!(config, yaml("asterisk: a\n"));
+    }
+
+    #[test]
+    fn section_is_replaced_by_a_scalar() {
+        let mut config = yaml("http:\n  listen_addr: '::'\n");
+        let issues = apply(&mut config, &[env("HTTP", "off")]);
+        assert!(issues.is_empty(), "{issues:?}");
+        assert_eq!(config, yaml("http: 'off'\n"));
+    }
+
+    #[test]
+    fn empty_segment_is_an_issue() {
+        let mut config = yaml("asterisk:\n  host: a\n");
+        let issues = apply(&mut config, &[env("ASTERISK____HOST", "b")]);
+        assert_eq!(issues.len(), 1, "{issues:?}");
+        assert_eq!(config, yaml("asterisk:\n  host: a\n"));
+    }
+
+    #[test]
+    fn lists_are_parsed_as_yaml() {
+        let mut config = Value::Null;
+        let issues = apply(&mut config, &[env("ENDPOINTS", "[a, b]")]);
+        assert!(issues.is_empty(), "{issues:?}");
+        assert_eq!(config, yaml("endpoints: [a, b]\n"));
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Coerced {
+        flag: bool,
+        port: u16,
+        offset: i32,
+        ratio: f64,
+        name: String,
+        maybe: Option<u8>,
+    }
+
+    #[test]
+    fn lenient_converts_strings_to_numbers_and_booleans() {
+        let value = yaml(
+            "flag: 'true'\nport: ' 5038 '\noffset: '-3'\nratio: '0.5'\nname: 12\nmaybe: '7'\n",
+        );
+        assert_eq!(
+            Coerced::deserialize(Lenient(value)).expect("coerced"),
+            Coerced {
+                flag: true,
+                port: 5038,
+                offset: -3,
+                ratio: 0.5,
+                name: "12".to_owned(),
+                maybe: Some(7),
+            }
+        );
+    }
+
+    #[test]
+    fn lenient_keeps_native_values() {
+        let value = yaml("flag: false\nport: 1\noffset: 2\nratio: 3\nname: x\nmaybe: null\n");
+        assert_eq!(
+            Coerced::deserialize(Lenient(value)).expect("native values"),
+            Coerced {
+                flag: false,
+                port: 1,
+                offset: 2,
+                ratio: 3.0,
+                name: "x".to_owned(),
+                maybe: None,
+            }
+        );
+    }
+
+    #[test]
+    fn lenient_reports_invalid_strings() {
+        let value = yaml("flag: yes please\nport: 1\noffset: 2\nratio: 3\nname: x\n");
+        let e = Coerced::deserialize(Lenient(value)).expect_err("not a bool");
+        assert!(e.to_string().contains("expected bool"), "{e}");
+        let value = yaml("flag: true\nport: '70000'\noffset: 2\nratio: 3\nname: x\n");
+        assert!(Coerced::deserialize(Lenient(value)).is_err());
+    }
+}
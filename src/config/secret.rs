@@ -0,0 +1,164 @@
+//! Values from environment variables and files, and secrets that are not printed
+
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+use super::ConfigIssue;
+
+/// Keys ending in this read the value of the key without it from a file
+const FILE_SUFFIX: &str = "_file";
+
+/// A string that is not shown in `Debug` output, e.g. passwords and tokens
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+impl Secret {
+    /// The secret value itself. Only use it where it is sent, never log it.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+impl core::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+/// Replace `${NAME}` by the value of the environment variable `NAME` in all strings,
+/// recursively.
+///
+/// `$${` is an escaped `${`. Only values are replaced, so a value can never change the
+/// structure of the config, and keys and comments are left as they are.
+pub fn substitute_env(value: &mut Value, path: &str, issues: &mut Vec<ConfigIssue>) {
+    match value {
+        Value::String(x) => *x = substitute_env_str(x, path, issues),
+        Value::Mapping(mapping) => {
+            for (key, x) in mapping.iter_mut() {
+                if let Some(key) = key.as_str() {
+                    let path = if path.is_empty() {
+                        key.to_owned()
+                    } else {
+                        format!("{path}.{key}")
+                    };
+                    substitute_env(x, &path, issues);
+                };
+            }
+        }
+        Value::Sequence(seq) => {
+            for (i, x) in seq.iter_mut().enumerate() {
+                substitute_env(x, &format!("{path}[{i}]"), issues);
+            }
+        }
+        Value::Tagged(x) => substitute_env(&mut x.value, path, issues),
+        _ => (),
+    };
+}
+
+/// Replace `${NAME}` in a single string, see [`substitute_env`]
+fn substitute_env_str(text: &str, path: &str, issues: &mut Vec<ConfigIssue>) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        res.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(x) = rest.strip_prefix("$${") {
+            res.push_str("${");
+            rest = x;
+        } else if let Some(x) = rest.strip_prefix("${") {
+            let Some(end) = x.find('}') else {
+                issues.push(ConfigIssue::new(
+                    path,
+                    "unterminated ${, write $${ for a literal ${",
+                ));
+                res.push_str(rest);
+                return res;
+            };
+            let name = &x[..end];
+            match std::env::var(name) {
+                Ok(value) => res.push_str(&value),
+                Err(std::env::VarError::NotPresent) => issues.push(ConfigIssue::new(
+                    path,
+                    format!("the environment variable {name} is not set"),
+                )),
+                Err(std::env::VarError::NotUnicode(_)) => issues.push(ConfigIssue::new(
+                    path,
+                    format!("the environment variable {name} is not valid unicode"),
+                )),
+            };
+            rest = &x[end + 1..];
+        } else {
+            res.push('$');
+            rest = &rest[1..];
+        };
+    }
+    res.push_str(rest);
+    res
+}
+
+/// Replace every `<key>_file: <path>` by `<key>: <content of the file>`, recursively.
+///
+/// A single trailing newline is removed from the content.
+pub fn read_value_files(value: &mut Value, path: &str, issues: &mut Vec<ConfigIssue>) {
+    match value {
+        Value::Mapping(mapping) => read_mapping_files(mapping, path, issues),
+        Value::Sequence(seq) => {
+            for (i, x) in seq.iter_mut().enumerate() {
+                read_value_files(x, &format!("{path}[{i}]"), issues);
+            }
+        }
+        _ => (),
+    };
+}
+
+fn read_mapping_files(mapping: &mut Mapping, path: &str, issues: &mut Vec<ConfigIssue>) {
+    let field_path = |key: &str| {
+        if path.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{path}.{key}")
+        }
+    };
+    let file_keys = mapping
+        .keys()
+        .filter_map(Value::as_str)
+        .filter(|x| x.len() > FILE_SUFFIX.len() && x.ends_with(FILE_SUFFIX))
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    for file_key in file_keys {
+        let key = &file_key[..file_key.len() - FILE_SUFFIX.len()];
+        let Some(Value::String(file)) = mapping.remove(file_key.as_str()) else {
+            issues.push(ConfigIssue::new(
+                field_path(&file_key),
+                "must be the path of a file",
+            ));
+            continue;
+        };
+        if mapping.contains_key(key) {
+            issues.push(ConfigIssue::new(
+                field_path(key),
+                format!("given together with {file_key}, remove one of them"),
+            ));
+            continue;
+        };
+        match std::fs::read_to_string(&file) {
+            Ok(mut content) => {
+                if content.ends_with('\n') {
+                    content.pop();
+                    if content.ends_with('\r') {
+                        content.pop();
+                    };
+                };
+                mapping.insert(Value::String(key.to_owned()), Value::String(content));
+            }
+            Err(e) => issues.push(ConfigIssue::new(
+                field_path(&file_key),
+                format!("unable to read {file}: {e}"),
+            )),
+        };
+    }
+    for (key, x) in mapping.iter_mut() {
+        if let Some(key) = key.as_str() {
+            read_value_files(x, &field_path(key), issues);
+        };
+    }
+}
@@ -0,0 +1,741 @@
+//! Semantic checks of the config, reported with the path of the offending field
+
+use std::{collections::BTreeMap, net::SocketAddr, str::FromStr};
+
+use tracing_subscriber::filter::{Directive, LevelFilter};
+
+use super::{load_trust_anchors, ConfigData, OneOrMore};
+use crate::{
+    logging::{facility_code, SyslogTransport},
+    maintenance,
+    notify::{self, OnClearConfigData},
+    rule, schedule,
+    source::{self, Source},
+    vote::VotePolicy,
+};
+
+/// Highest CAN node number a CMI sends
+const MAX_NODE: u8 = 62;
+/// Highest PDO number (web-gui numbering) in a COE packet
+const MAX_PDO: u8 = 64;
+
+/// A single problem found in the config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    /// where the problem is, e.g. `alarms[1].expect_pdo`
+    pub path: String,
+    /// what is wrong and how to fix it
+    pub message: String,
+}
+impl ConfigIssue {
+    pub(super) fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+impl core::fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// A single alarm input, no matter where it is configured
+struct AlarmInput<'a> {
+    path: String,
+    /// `None` for the further inputs of a voting alarm
+    name: Option<&'a str>,
+    /// the sources that could be parsed
+    expect_from_addr: Vec<Source>,
+    expect_index: u8,
+    expect_pdo: u8,
+}
+
+fn check_socket_addr(issues: &mut Vec<ConfigIssue>, path: &str, value: &str) {
+    if let Err(e) = value.parse::<SocketAddr>() {
+        issues.push(ConfigIssue::new(
+            path,
+            format!(
+                "{value:?} is not an address with port ({e}), e.g. 127.0.0.1:9442 or [::1]:9442"
+            ),
+        ));
+    };
+}
+
+/// Binding both fails: the same address and port, or a wildcard address covering the other.
+/// `::` also covers IPv4 on most systems.
+fn listen_addrs_overlap(a: SocketAddr, b: SocketAddr) -> bool {
+    let covers =
+        |x: SocketAddr, y: SocketAddr| x.ip().is_unspecified() && (x.is_ipv6() || y.is_ipv4());
+    a.port() == b.port() && (a.ip() == b.ip() || covers(a, b) || covers(b, a))
+}
+
+/// Parse every allowed source, reporting those that are invalid
+fn check_sources(issues: &mut Vec<ConfigIssue>, path: &str, value: &OneOrMore) -> Vec<Source> {
+    if value.as_slice().is_empty() {
+        issues.push(ConfigIssue::new(
+            path,
+            "no source; add at least one address, subnet or hostname",
+        ));
+    };
+    value
+        .as_slice()
+        .iter()
+        .enumerate()
+        .filter_map(|(i, x)| match source::parse_source(x) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                issues.push(ConfigIssue::new(value.path_of(path, i), e));
+                None
+            }
+        })
+        .collect()
+}
+
+/// Why both inputs would receive the same value, if they do
+fn input_collision(first: &AlarmInput, second: &AlarmInput) -> Option<String> {
+    if (first.expect_index, first.expect_pdo) != (second.expect_index, second.expect_pdo) {
+        return None;
+    };
+    let (a, b) = first.expect_from_addr.iter().find_map(|a| {
+        second
+            .expect_from_addr
+            .iter()
+            .find(|b| a.overlaps(b))
+            .map(|b| (a, b))
+    })?;
+    Some(if a == b {
+        format!(
+            "listens to the same address, CAN-ID and PDO as {}",
+            first.path
+        )
+    } else {
+        format!(
+            "listens to the same CAN-ID and PDO as {}, and {b} overlaps with its source {a}",
+            first.path
+        )
+    })
+}
+
+/// Values that end up in an AMI action must not be able to inject headers
+fn check_ami_value(issues: &mut Vec<ConfigIssue>, path: &str, value: &str) {
+    if value.contains(['\r', '\n']) {
+        issues.push(ConfigIssue::new(path, "must not contain line breaks"));
+    };
+}
+
+/// A file the service writes to: it needs a name and an existing directory
+fn check_file_path(issues: &mut Vec<ConfigIssue>, path: &str, value: &str) {
+    let file = std::path::Path::new(value);
+    if value.trim().is_empty() {
+        issues.push(ConfigIssue::new(path, "must not be empty"));
+    } else if file.is_dir() {
+        issues.push(ConfigIssue::new(
+            path,
+            format!("{value} is a directory, give the path of a file in it"),
+        ));
+    } else if file
+        .parent()
+        .is_some_and(|x| !x.as_os_str().is_empty() && !x.is_dir())
+    {
+        issues.push(ConfigIssue::new(
+            path,
+            format!("the directory of {value} does not exist"),
+        ));
+    };
+}
+
+/// Record the error of parsing a value
+fn check_parsed<T>(issues: &mut Vec<ConfigIssue>, path: impl Into<String>, res: Result<T, String>) {
+    if let Err(e) = res {
+        issues.push(ConfigIssue::new(path, e));
+    };
+}
+
+/// A number of minutes needs to fit into a duration in seconds
+fn check_minutes(issues: &mut Vec<ConfigIssue>, path: impl Into<String>, minutes: u64) {
+    if minutes.checked_mul(60).is_none() {
+        issues.push(ConfigIssue::new(
+            path,
+            format!("{minutes} minutes is too long"),
+        ));
+    };
+}
+
+/// The CAN-ID and PDO of an input need to be sendable by a CMI
+fn check_node_and_pdo(issues: &mut Vec<ConfigIssue>, path: &str, index: u8, pdo: u8) {
+    if !(1..=MAX_NODE).contains(&index) {
+        issues.push(ConfigIssue::new(
+            format!("{path}.expect_index"),
+            format!("{index} is not a CAN node number, use 1 to {MAX_NODE}"),
+        ));
+    };
+    if !(1..=MAX_PDO).contains(&pdo) {
+        issues.push(ConfigIssue::new(
+            format!("{path}.expect_pdo"),
+            format!(
+                "{pdo} never matches; PDOs are numbered as in the web-gui, from 1 to {MAX_PDO}"
+            ),
+        ));
+    };
+}
+
+/// Something needs to be notified, and nothing may inject AMI headers
+fn check_on_clear(issues: &mut Vec<ConfigIssue>, path: &str, on_clear: &OnClearConfigData) {
+    if on_clear.exten.is_none() && on_clear.webhook.is_none() {
+        issues.push(ConfigIssue::new(
+            path,
+            "neither exten nor webhook is given, nothing is notified",
+        ));
+    };
+    if on_clear.context.is_some() && on_clear.exten.is_none() {
+        issues.push(ConfigIssue::new(
+            format!("{path}.context"),
+            "only used together with exten",
+        ));
+    };
+    for (field, value) in [("exten", &on_clear.exten), ("context", &on_clear.context)] {
+        if let Some(value) = value {
+            check_ami_value(issues, &format!("{path}.{field}"), value);
+        };
+    }
+    if let Some(url) = &on_clear.webhook {
+        check_parsed(issues, format!("{path}.webhook"), notify::parse_url(url));
+    };
+}
+
+/// Endpoints are dialled as `TECH/resource`, e.g. `PJSIP/1234@trunk`
+fn check_endpoint(issues: &mut Vec<ConfigIssue>, path: &str, value: &str) {
+    let valid = match value.split_once('/') {
+        Some((tech, resource)) => {
+            !tech.is_empty()
+                && tech.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && !resource.is_empty()
+                && !resource.chars().any(char::is_whitespace)
+        }
+        None => false,
+    };
+    if !valid {
+        issues.push(ConfigIssue::new(
+            path,
+            format!("{value:?} is not of the form TECH/resource, e.g. PJSIP/1111222233334444@sip_trunk_endpoint"),
+        ));
+    };
+}
+
+impl ConfigData {
+    /// Check the whole config without touching the network.
+    ///
+    /// All issues are collected, so they can be fixed in one go.
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+
+        let port = self.cmi.port.unwrap_or(super::DEFAULT_COE_PORT);
+        if self.cmi.port == Some(0) {
+            issues.push(ConfigIssue::new(
+                "cmi.port",
+                "0 picks a random port the CMI cannot know; leave it out to use 5442",
+            ));
+        };
+        if self.cmi.resolve_interval == Some(0) {
+            issues.push(ConfigIssue::new(
+                "cmi.resolve_interval",
+                "0 would look up the hostnames every 5 seconds; leave it out to use 300",
+            ));
+        };
+        let listen_addrs = self.cmi.listen_addr.as_slice();
+        if listen_addrs.is_empty() {
+            issues.push(ConfigIssue::new(
+                "cmi.listen_addr",
+                "no address to listen on; add at least one, e.g. 0.0.0.0",
+            ));
+        };
+        let mut listen = BTreeMap::new();
+        for (i, value) in listen_addrs.iter().enumerate() {
+            let path = self.cmi.listen_addr.path_of("cmi.listen_addr", i);
+            match super::parse_listen_addr(value, port) {
+                Ok(addr) => {
+                    if let Some((other, first)) =
+                        listen.iter().find(|(x, _)| listen_addrs_overlap(**x, addr))
+                    {
+                        issues.push(ConfigIssue::new(
+                            path,
+                            format!("{addr} overlaps with {other} of {first}; only one of them can be bound"),
+                        ));
+                    } else {
+                        listen.insert(addr, path);
+                    };
+                }
+                Err(e) => issues.push(ConfigIssue::new(
+                    path,
+                    format!(
+                        "{value:?} is not an IP address ({e}), e.g. 0.0.0.0, :: or [fd00::2]:5443"
+                    ),
+                )),
+            };
+        }
+
+        let mut inputs = Vec::new();
+        match (
+            &self.cmi.expect_from_addr,
+            self.cmi.expect_index,
+            self.cmi.expect_pdo,
+            self.cmi.circuit_is_normally_closed,
+        ) {
+            (Some(addr), Some(index), Some(pdo), Some(_)) => inputs.push(AlarmInput {
+                path: "cmi".to_owned(),
+                name: Some(self.cmi.alarm_name.as_deref().unwrap_or("alarm")),
+                expect_from_addr: check_sources(&mut issues, "cmi.expect_from_addr", addr),
+                expect_index: index,
+                expect_pdo: pdo,
+            }),
+            (None, None, None, None) => {}
+            (addr, index, pdo, nc) => {
+                for (field, given) in [
+                    ("expect_from_addr", addr.is_some()),
+                    ("expect_index", index.is_some()),
+                    ("expect_pdo", pdo.is_some()),
+                    ("circuit_is_normally_closed", nc.is_some()),
+                ] {
+                    if !given {
+                        issues.push(ConfigIssue::new(
+                            format!("cmi.{field}"),
+                            "missing; the alarm in the cmi section needs expect_from_addr, expect_index, expect_pdo and circuit_is_normally_closed, or none of them",
+                        ));
+                    };
+                }
+            }
+        };
+        for (i, alarm) in self.alarms.iter().enumerate() {
+            inputs.push(AlarmInput {
+                path: format!("alarms[{i}]"),
+                name: Some(&alarm.name),
+                expect_from_addr: check_sources(
+                    &mut issues,
+                    &format!("alarms[{i}].expect_from_addr"),
+                    &alarm.expect_from_addr,
+                ),
+                expect_index: alarm.expect_index,
+                expect_pdo: alarm.expect_pdo,
+            });
+            let Some(voting) = &alarm.voting else {
+                continue;
+            };
+            for (j, input) in voting.inputs.iter().enumerate() {
+                let path = format!("alarms[{i}].voting.inputs[{j}]");
+                inputs.push(AlarmInput {
+                    expect_from_addr: check_sources(
+                        &mut issues,
+                        &format!("{path}.expect_from_addr"),
+                        &input.expect_from_addr,
+                    ),
+                    path,
+                    name: None,
+                    expect_index: input.expect_index,
+                    expect_pdo: input.expect_pdo,
+                });
+            }
+        }
+        if inputs.is_empty() && self.rules.is_empty() {
+            issues.push(ConfigIssue::new(
+                "alarms",
+                "no alarm is configured; add one to alarms or rules, or give the expect_* fields in cmi",
+            ));
+        };
+
+        // alarms without an input of their own: disagreement alarms of voting inputs and rules
+        let derived_alarms = self
+            .alarms
+            .iter()
+            .enumerate()
+            .filter_map(|(i, alarm)| {
+                let x = alarm.voting.as_ref()?.disagreement.as_ref()?;
+                Some((
+                    format!("alarms[{i}].voting.disagreement.alarm"),
+                    x.alarm.as_str(),
+                ))
+            })
+            .chain(
+                self.rules
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| (format!("rules[{i}].name"), x.name.as_str())),
+            )
+            .collect::<Vec<_>>();
+        let mut names = BTreeMap::<&str, &str>::new();
+        for input in &inputs {
+            match input.name {
+                Some(name) if name.trim().is_empty() => issues.push(ConfigIssue::new(
+                    format!("{}.name", input.path),
+                    "must not be empty",
+                )),
+                Some(name) => {
+                    if let Some(first) = names.insert(name, &input.path) {
+                        issues.push(ConfigIssue::new(
+                            format!("{}.name", input.path),
+                            format!("{name:?} is already used by {first}"),
+                        ));
+                    };
+                }
+                None => (),
+            };
+            check_node_and_pdo(
+                &mut issues,
+                &input.path,
+                input.expect_index,
+                input.expect_pdo,
+            );
+        }
+        for (path, name) in &derived_alarms {
+            if name.trim().is_empty() {
+                issues.push(ConfigIssue::new(path, "must not be empty"));
+            } else if let Some(first) = names.insert(name, path) {
+                issues.push(ConfigIssue::new(
+                    path,
+                    format!("{name:?} is already used by {first}"),
+                ));
+            };
+        }
+        for (i, input) in inputs.iter().enumerate() {
+            if let Some(message) = inputs[..i].iter().find_map(|x| input_collision(x, input)) {
+                issues.push(ConfigIssue::new(&input.path, message));
+            };
+        }
+
+        let mut signals = BTreeMap::<&str, String>::new();
+        for (i, signal) in self.signals.iter().enumerate() {
+            let path = format!("signals[{i}]");
+            if signal.name.trim().is_empty() {
+                issues.push(ConfigIssue::new(
+                    format!("{path}.name"),
+                    "must not be empty",
+                ));
+            } else if let Some(first) = signals.insert(&signal.name, path.clone()) {
+                issues.push(ConfigIssue::new(
+                    format!("{path}.name"),
+                    format!("{:?} is already used by {first}", signal.name),
+                ));
+            };
+            check_sources(
+                &mut issues,
+                &format!("{path}.expect_from_addr"),
+                &signal.expect_from_addr,
+            );
+            check_node_and_pdo(&mut issues, &path, signal.expect_index, signal.expect_pdo);
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            let path = format!("rules[{i}].when");
+            match rule::parse(&rule.when) {
+                Ok(expr) => {
+                    let mut unknown = expr
+                        .signals()
+                        .into_iter()
+                        .filter(|x| !signals.contains_key(x))
+                        .collect::<Vec<_>>();
+                    unknown.sort_unstable();
+                    unknown.dedup();
+                    for name in unknown {
+                        issues.push(ConfigIssue::new(
+                            &path,
+                            format!("there is no signal named {name:?}; add it to signals"),
+                        ));
+                    }
+                }
+                Err(e) => issues.push(ConfigIssue::new(&path, e)),
+            };
+        }
+
+        for (i, alarm) in self.alarms.iter().enumerate() {
+            let Some(voting) = &alarm.voting else {
+                continue;
+            };
+            let path = format!("alarms[{i}].voting");
+            if voting.inputs.is_empty() {
+                issues.push(ConfigIssue::new(
+                    format!("{path}.inputs"),
+                    "no other input; add the inputs of the other CMIs the contact is wired into",
+                ));
+            };
+            let count = voting.inputs.len() + 1;
+            match voting.vote.clone().map(VotePolicy::try_from) {
+                Some(Err(e)) => issues.push(ConfigIssue::new(format!("{path}.vote"), e)),
+                Some(Ok(VotePolicy::AtLeast(0))) => issues.push(ConfigIssue::new(
+                    format!("{path}.vote"),
+                    "0 would raise the alarm even if no input reports it; use 1 to the number of inputs",
+                )),
+                Some(Ok(VotePolicy::AtLeast(x))) if x > count => issues.push(ConfigIssue::new(
+                    format!("{path}.vote"),
+                    format!("{x} never matches, the alarm has only {count} input(s)"),
+                )),
+                _ => (),
+            };
+            if voting.input_timeout_seconds == Some(0) {
+                issues.push(ConfigIssue::new(
+                    format!("{path}.input_timeout_seconds"),
+                    "0 would fail every input right away; leave it out if inputs never fail",
+                ));
+            };
+        }
+
+        let on_clears = self
+            .alarms
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (format!("alarms[{i}].on_clear"), &x.on_clear))
+            .chain(
+                self.rules
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| (format!("rules[{i}].on_clear"), &x.on_clear)),
+            );
+        for (path, on_clear) in on_clears {
+            if let Some(on_clear) = on_clear {
+                check_on_clear(&mut issues, &path, on_clear);
+            };
+        }
+
+        let asterisk = &self.asterisk;
+        if asterisk.host.trim().is_empty() {
+            issues.push(ConfigIssue::new("asterisk.host", "must not be empty"));
+        } else if rustls::pki_types::ServerName::try_from(asterisk.host.as_str()).is_err() {
+            issues.push(ConfigIssue::new(
+                "asterisk.host",
+                format!("{:?} is not a valid host name or IP address", asterisk.host),
+            ));
+        };
+        if asterisk.port == Some(0) {
+            issues.push(ConfigIssue::new(
+                "asterisk.port",
+                "0 is not a valid port; leave it out to use 5039",
+            ));
+        };
+        if asterisk.call_external_endpoints.is_empty() {
+            issues.push(ConfigIssue::new(
+                "asterisk.call_external_endpoints",
+                "no endpoint to call; add at least one",
+            ));
+        };
+        for (i, endpoint) in asterisk.call_external_endpoints.iter().enumerate() {
+            let path = format!("asterisk.call_external_endpoints[{i}]");
+            check_endpoint(&mut issues, &path, endpoint);
+            check_ami_value(&mut issues, &path, endpoint);
+        }
+        for (field, value) in [
+            ("execute_context", Some(asterisk.execute_context.as_str())),
+            ("execute_exten", Some(asterisk.execute_exten.as_str())),
+            ("execute_priority", asterisk.execute_priority.as_deref()),
+            ("username", Some(asterisk.username.as_str())),
+            ("secret", Some(asterisk.secret.expose())),
+            ("caller_id", Some(asterisk.caller_id.as_str())),
+        ] {
+            if let Some(value) = value {
+                check_ami_value(&mut issues, &format!("asterisk.{field}"), value);
+            };
+        }
+        if let Some(pemfile) = &asterisk.trust_extra_pem {
+            match load_trust_anchors(pemfile) {
+                Ok(x) if x.is_empty() => issues.push(ConfigIssue::new(
+                    "asterisk.trust_extra_pem",
+                    format!("{pemfile} contains no certificate"),
+                )),
+                Ok(_) => {}
+                Err(e) => issues.push(ConfigIssue::new(
+                    "asterisk.trust_extra_pem",
+                    format!("unable to load certificates from {pemfile}: {e}"),
+                )),
+            };
+        };
+
+        if let Some(http) = &self.http {
+            check_socket_addr(&mut issues, "http.listen", &http.listen);
+        };
+        if let Some(admin) = &self.admin {
+            check_socket_addr(&mut issues, "admin.listen", &admin.listen);
+            if admin.token.expose().trim().is_empty() {
+                issues.push(ConfigIssue::new("admin.token", "must not be empty"));
+            };
+        };
+
+        let logging = &self.logging;
+        if let Some(level) = &logging.level {
+            if LevelFilter::from_str(level).is_err() {
+                issues.push(ConfigIssue::new(
+                    "logging.level",
+                    format!("{level:?} is not one of trace, debug, info, warn, error"),
+                ));
+            };
+        };
+        for (i, filter) in logging.filters.iter().enumerate() {
+            if let Err(e) = Directive::from_str(filter) {
+                issues.push(ConfigIssue::new(
+                    format!("logging.filters[{i}]"),
+                    format!("{filter:?} is not a filter directive: {e}"),
+                ));
+            };
+        }
+        if let Some(syslog) = &logging.syslog {
+            if syslog.transport != SyslogTransport::Local && syslog.address.is_none() {
+                issues.push(ConfigIssue::new(
+                    "logging.syslog.address",
+                    "required for udp and tcp, e.g. syslog.example.com:514",
+                ));
+            };
+            if let Some(facility) = &syslog.facility {
+                if facility_code(facility).is_none() {
+                    issues.push(ConfigIssue::new(
+                        "logging.syslog.facility",
+                        format!("{facility:?} is not a syslog facility, e.g. daemon or local0"),
+                    ));
+                };
+            };
+        };
+
+        if let Some(schedule) = &self.schedule {
+            if let Some(timezone) = &schedule.timezone {
+                check_parsed(
+                    &mut issues,
+                    "schedule.timezone",
+                    schedule::parse_timezone(timezone),
+                );
+            };
+            for (i, holiday) in schedule.holidays.iter().enumerate() {
+                check_parsed(
+                    &mut issues,
+                    format!("schedule.holidays[{i}]"),
+                    schedule::parse_holiday(holiday),
+                );
+            }
+            if let Some(path) = &schedule.holidays_ical {
+                check_parsed(
+                    &mut issues,
+                    "schedule.holidays_ical",
+                    schedule::read_ical(path),
+                );
+            };
+            for (i, rule) in schedule.rules.iter().enumerate() {
+                let path = format!("schedule.rules[{i}]");
+                if rule.name.trim().is_empty() {
+                    issues.push(ConfigIssue::new(
+                        format!("{path}.name"),
+                        "must not be empty",
+                    ));
+                };
+                for (j, day) in rule.days.iter().enumerate() {
+                    check_parsed(
+                        &mut issues,
+                        format!("{path}.days[{j}]"),
+                        schedule::parse_day(day),
+                    );
+                }
+                for (field, value) in [("from", &rule.from), ("to", &rule.to)] {
+                    if let Some(value) = value {
+                        check_parsed(
+                            &mut issues,
+                            format!("{path}.{field}"),
+                            schedule::parse_time(value),
+                        );
+                    };
+                }
+                for (j, endpoint) in rule.call_external_endpoints.iter().enumerate() {
+                    let path = format!("{path}.call_external_endpoints[{j}]");
+                    check_endpoint(&mut issues, &path, endpoint);
+                    check_ami_value(&mut issues, &path, endpoint);
+                }
+                for (j, step) in rule.escalate.iter().enumerate() {
+                    check_minutes(
+                        &mut issues,
+                        format!("{path}.escalate[{j}].after_minutes"),
+                        step.after_minutes,
+                    );
+                    let path = format!("{path}.escalate[{j}].call_external_endpoints");
+                    if step.call_external_endpoints.is_empty() {
+                        issues.push(ConfigIssue::new(
+                            &path,
+                            "no endpoint to call; add at least one",
+                        ));
+                    };
+                    for (k, endpoint) in step.call_external_endpoints.iter().enumerate() {
+                        let path = format!("{path}[{k}]");
+                        check_endpoint(&mut issues, &path, endpoint);
+                        check_ami_value(&mut issues, &path, endpoint);
+                    }
+                }
+            }
+        };
+
+        if let Some(state) = &self.state {
+            check_file_path(&mut issues, "state.file", &state.file);
+            if let Some(x) = state.max_age_minutes {
+                check_minutes(&mut issues, "state.max_age_minutes", x);
+            };
+        };
+        if let Some(journal) = &self.journal {
+            check_file_path(&mut issues, "journal.file", &journal.file);
+        };
+        if let Some(capture) = &self.capture {
+            check_file_path(&mut issues, "capture.file", &capture.file);
+        };
+
+        let maintenance = &self.maintenance;
+        if maintenance.max_minutes == Some(0) {
+            issues.push(ConfigIssue::new(
+                "maintenance.max_minutes",
+                "0 would end maintenance right away; leave it out to use 240",
+            ));
+        };
+        if let Some(x) = maintenance.max_minutes {
+            check_minutes(&mut issues, "maintenance.max_minutes", x);
+        };
+        let check_alarm_names = |issues: &mut Vec<ConfigIssue>, path: &str, alarms: &[String]| {
+            for (i, name) in alarms.iter().enumerate() {
+                if !names.contains_key(name.as_str()) {
+                    issues.push(ConfigIssue::new(
+                        format!("{path}.alarms[{i}]"),
+                        format!("there is no alarm named {name:?}"),
+                    ));
+                };
+            }
+        };
+        for (i, window) in maintenance.windows.iter().enumerate() {
+            let path = format!("maintenance.windows[{i}]");
+            let from = maintenance::parse_datetime(&window.from);
+            let to = maintenance::parse_datetime(&window.to);
+            if let (Ok(from), Ok(to)) = (&from, &to) {
+                if from >= to {
+                    issues.push(ConfigIssue::new(
+                        format!("{path}.to"),
+                        format!("{} is not after from", window.to),
+                    ));
+                };
+            };
+            check_parsed(&mut issues, format!("{path}.from"), from);
+            check_parsed(&mut issues, format!("{path}.to"), to);
+            check_alarm_names(&mut issues, &path, &window.alarms);
+        }
+        if let Some(input) = &maintenance.input {
+            let path = "maintenance.input";
+            let input_sources = AlarmInput {
+                path: path.to_owned(),
+                name: Some("maintenance"),
+                expect_from_addr: check_sources(
+                    &mut issues,
+                    &format!("{path}.expect_from_addr"),
+                    &input.expect_from_addr,
+                ),
+                expect_index: input.expect_index,
+                expect_pdo: input.expect_pdo,
+            };
+            check_node_and_pdo(&mut issues, path, input.expect_index, input.expect_pdo);
+            if let Some(message) = inputs
+                .iter()
+                .find_map(|x| input_collision(x, &input_sources))
+            {
+                issues.push(ConfigIssue::new(path, message));
+            };
+            check_alarm_names(&mut issues, path, &input.alarms);
+        };
+
+        issues
+    }
+}
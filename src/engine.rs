@@ -0,0 +1,328 @@
+//! The alarm engine: COE datagrams in, alarm states, events and calls out
+//!
+//! [`Engine`] bundles everything needed to evaluate the datagrams of CMIs against a config.
+//! The service feeds it from its UDP sockets, but it can be fed from anywhere, e.g. a
+//! supervisor receiving COE itself.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use coe::Packet;
+use tracing::{debug, trace, warn};
+
+use crate::{
+    alarm::{self, Alarms, Event, Trigger},
+    config::{AlarmConfig, Config},
+    metrics::{DropReason, Metrics},
+    signal,
+    source::Resolver,
+};
+
+/// Evaluates COE datagrams against a config, calling asterisk for raised alarms.
+///
+/// Calls and all-clear notifications are sent and the state file is written in the
+/// background. The journal is appended to before [`Engine::handle_datagram`] returns, so it
+/// blocks while writing to the disk if it is configured.
+#[derive(Debug)]
+pub struct Engine {
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    alarms: Arc<Alarms>,
+    resolver: Arc<Resolver>,
+}
+impl Engine {
+    /// An engine calling asterisk like the service does.
+    ///
+    /// The state saved by a previous run is restored if `state` is configured, and the
+    /// hostnames of `expect_from_addr` are resolved, so this blocks.
+    pub fn new(config: Arc<Config>) -> Self {
+        let metrics = Arc::new(Metrics::default());
+        for alarm in &config.alarms {
+            metrics.register_alarm(&alarm.name);
+        }
+        let alarms = Arc::new(Alarms::new(&config));
+        for name in alarms.restore(&config) {
+            metrics.alarm_restored(&name);
+        }
+        Self::with_alarms(config, metrics, alarms)
+    }
+
+    /// An engine recording the calls it would send as `would_call` events instead of
+    /// sending them. Neither the state nor the journal is read or written.
+    pub fn dry_run(config: Arc<Config>) -> Self {
+        let alarms = Arc::new(Alarms::dry_run(&config));
+        Self::with_alarms(config, Arc::new(Metrics::default()), alarms)
+    }
+
+    fn with_alarms(config: Arc<Config>, metrics: Arc<Metrics>, alarms: Arc<Alarms>) -> Self {
+        let resolver = Arc::new(Resolver::default());
+        resolver.resolve_due(&config);
+        Self {
+            config,
+            metrics,
+            alarms,
+            resolver,
+        }
+    }
+
+    /// The config the datagrams are evaluated against
+    pub(crate) fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    /// The state of the alarms and the recent events
+    pub(crate) fn alarms(&self) -> &Arc<Alarms> {
+        &self.alarms
+    }
+
+    /// Counters of packets, alarms and calls; [`Metrics::render`] exports them
+    pub(crate) fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// The addresses of the hostnames in `expect_from_addr`
+    pub(crate) fn resolver(&self) -> &Arc<Resolver> {
+        &self.resolver
+    }
+
+    /// The names of the active alarms, i.e. raised and not cleared yet
+    pub fn active_alarms(&self) -> Vec<String> {
+        self.alarms
+            .list()
+            .into_iter()
+            .filter(|x| x.active)
+            .map(|x| x.name)
+            .collect()
+    }
+
+    /// Remove and return the events recorded since the last call, oldest first.
+    ///
+    /// They serialize like the lines of the journal.
+    pub fn take_events(&self) -> Vec<Event> {
+        self.alarms.take_events()
+    }
+
+    /// The counters of packets, alarms and calls in the prometheus text exposition format
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render()
+    }
+
+    /// Resolve the hostnames not resolved within `cmi.resolve_interval` again. This blocks.
+    pub fn resolve_due(&self) {
+        self.resolver.resolve_due(&self.config);
+    }
+
+    /// Process a single COE datagram received from `from`.
+    ///
+    /// This blocks while appending to the journal, if an alarm changed and it is configured.
+    /// The state file is written in the background.
+    pub fn handle_datagram(&self, buf: &[u8], from: SocketAddr) {
+        handle_datagram(
+            &self.config,
+            &self.metrics,
+            &self.alarms,
+            &self.resolver,
+            buf,
+            from,
+        );
+    }
+
+    /// Evaluate the rules with a timer, e.g. `temp > 80 for 5m`. Call this every second.
+    pub fn tick(&self) {
+        tick(&self.config, &self.metrics, &self.alarms);
+    }
+}
+
+/// Evaluate the rules with a timer against `config`
+pub fn tick(config: &Arc<Config>, metrics: &Arc<Metrics>, alarms: &Arc<Alarms>) {
+    for (alarm, result) in alarms.tick_rules(config) {
+        alarm::process(config, metrics, alarms, alarm, result, Trigger::Timer);
+    }
+}
+
+/// The relevant values in a single COE packet
+#[derive(Debug, Default)]
+struct PacketStates<'a> {
+    /// alarms with a relevant payload, together with the index of the input and whether the
+    /// payload is the alarm state (true) or the good state (false)
+    alarms: Vec<(&'a AlarmConfig, usize, bool)>,
+    /// values of the signals used in rules, by name
+    signals: Vec<(&'a str, f64)>,
+    /// whether the maintenance input reports maintenance, if the packet contains it
+    maintenance: Option<bool>,
+}
+
+/// Process a single UDP packet, checking which alarms it contains the state of.
+fn packet_is_alarm<'a>(
+    config: &'a Config,
+    metrics: &Metrics,
+    resolver: &Resolver,
+    buf: &[u8],
+    remote: SocketAddr,
+) -> Result<PacketStates<'a>, Box<dyn std::error::Error>> {
+    let remote_ip = remote.ip();
+    // check if we want to receive packets from the remote
+    let from_remote = config
+        .alarms
+        .iter()
+        .flat_map(|alarm| {
+            alarm
+                .inputs()
+                .into_iter()
+                .enumerate()
+                .map(move |(i, (sources, index, pdo))| (alarm, i, sources, index, pdo))
+        })
+        .filter(|(_, _, sources, _, _)| sources.contains(remote_ip, resolver))
+        .collect::<Vec<_>>();
+    let signals_from_remote = config
+        .signals
+        .iter()
+        .filter(|x| x.expect_from_addr.contains(remote_ip, resolver))
+        .collect::<Vec<_>>();
+    let maintenance_input = config
+        .maintenance
+        .input
+        .as_ref()
+        .filter(|x| x.expect_from_addr.contains(remote_ip, resolver));
+    if from_remote.is_empty() && signals_from_remote.is_empty() && maintenance_input.is_none() {
+        metrics.packet_dropped(DropReason::SourceIp);
+        metrics.source_rejected(&remote_ip.to_string());
+        // a misconfigured CMI should be visible, but a chatty one must not flood the log
+        if resolver.log_rejected(remote_ip) {
+            warn!(
+                "Ignoring UDP packets from {remote_ip}: no input expects packets from it. If it is a CMI, add it to expect_from_addr. Logged again in 10 minutes at the earliest."
+            );
+        } else {
+            trace!(
+                "Got a COE payload, but ignoring it because no alarm expects packets from {remote_ip}"
+            );
+        };
+        return Ok(PacketStates::default());
+    };
+    metrics.packet_from(&remote_ip.to_string());
+    // try to parse the packet
+    let packet: Packet = match buf.try_into() {
+        Ok(x) => x,
+        Err(e) => {
+            metrics.parse_error();
+            return Err(e)?;
+        }
+    };
+    let mut res = PacketStates::default();
+    // ignore packets to the wrong ID or PDO
+    'payload: for payload in packet {
+        if let Some(input) = maintenance_input
+            .filter(|x| x.expect_index == payload.node() && x.expect_pdo == payload.pdo_index() + 1)
+        {
+            match payload.value() {
+                coe::COEValue::Digital(coe::DigitalCOEValue::OnOff(x)) => {
+                    res.maintenance = Some(x == input.maintenance_when_on);
+                }
+                _ => {
+                    trace!("Got value for the maintenance input, but ignoring it because the value is not DigitalOnOff.");
+                    metrics.packet_dropped(DropReason::ValueType);
+                }
+            };
+            continue 'payload;
+        };
+        // a signal may also be the input of an alarm
+        let signals = signals_from_remote
+            .iter()
+            .filter(|x| x.expect_index == payload.node() && x.expect_pdo == payload.pdo_index() + 1)
+            .map(|x| (x.name.as_str(), signal::numeric(&payload)))
+            .collect::<Vec<_>>();
+        let is_signal = !signals.is_empty();
+        res.signals.extend(signals);
+        if !from_remote
+            .iter()
+            .any(|(_, _, _, index, _)| *index == payload.node())
+        {
+            if !is_signal {
+                trace!(
+                    "Got a COE payload, but ignoring it because no alarm expects the CAN-ID {}",
+                    payload.node()
+                );
+                metrics.packet_dropped(DropReason::Node);
+            };
+            continue 'payload;
+        };
+        // NOTE: shift the index by +1; date on-wire is one lower then data entered in CMIs web-gui
+        let Some((alarm, input, ..)) = from_remote.iter().find(|(_, _, _, index, pdo)| {
+            *index == payload.node() && *pdo == payload.pdo_index() + 1
+        }) else {
+            if !is_signal {
+                trace!(
+                    "Got a COE payload, but ignoring it because no alarm expects the pdo index {}",
+                    payload.pdo_index() + 1
+                );
+                metrics.packet_dropped(DropReason::Pdo);
+            };
+            continue 'payload;
+        };
+        // we have a packet to the correct CAN-ID and PDO, from the correct Address
+        // ignore it if it is not digital.
+        match payload.value() {
+            // Originate an alarm iff:
+            // IF the circuit is_normally_closed, we alarm when false is sent
+            // IF the circuit IS NOT is_normally_closed, we alarm when true is sent
+            coe::COEValue::Digital(coe::DigitalCOEValue::OnOff(x)) => {
+                if x == alarm.circuit_is_normally_closed {
+                    trace!("Got correctly formed value from the expected IP/Node/PDO. Value is {x}, which is the no-alarm state.");
+                    res.alarms.push((*alarm, *input, false));
+                } else {
+                    res.alarms.push((*alarm, *input, true));
+                }
+            }
+            _ => {
+                trace!("Got value from the expected IP/NODE/PDO, but ignoring it because the value is not DigitalOnOff.");
+                metrics.packet_dropped(DropReason::ValueType);
+            }
+        };
+    }
+    if res.alarms.is_empty() && res.signals.is_empty() && res.maintenance.is_none() {
+        debug!("Got a COE packet, but no payload was relevant.");
+    };
+    Ok(res)
+}
+
+/// Process a single datagram received from `addr`, with the same config for the whole packet
+pub fn handle_datagram(
+    config: &Arc<Config>,
+    metrics: &Arc<Metrics>,
+    alarms: &Arc<Alarms>,
+    resolver: &Resolver,
+    buf: &[u8],
+    addr: SocketAddr,
+) {
+    // We have a relevant packet. Process it.
+    match packet_is_alarm(config, metrics, resolver, buf, addr) {
+        Ok(mut states) => {
+            // a packet starting maintenance suppresses the alarms it contains
+            if let Some(on) = states.maintenance {
+                alarms.maintenance_input(config, on);
+            };
+            // alarms with a higher priority are called first
+            states
+                .alarms
+                .sort_by_key(|(alarm, ..)| core::cmp::Reverse(alarm.priority));
+            for (alarm, input, is_alarm) in states.alarms {
+                alarm::process_input(
+                    config,
+                    metrics,
+                    alarms,
+                    alarm,
+                    input,
+                    is_alarm,
+                    Trigger::Cmi(addr),
+                );
+            }
+            for (alarm, result) in alarms.update_signals(config, &states.signals) {
+                alarm::process(config, metrics, alarms, alarm, result, Trigger::Cmi(addr));
+            }
+            trace!("Correctly handled a single UDP packet from the CMI.");
+        }
+        Err(e) => {
+            warn!("Error while processing incoming UDP packet: {e}");
+        }
+    };
+}
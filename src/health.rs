@@ -0,0 +1,71 @@
+//! Liveness and readiness of the service
+
+use std::{sync::Arc, time::Duration};
+
+use tracing::{debug, warn};
+
+use crate::{
+    config::{Config, SharedConfig},
+    metrics::Metrics,
+};
+
+/// Check whether the service is ready to handle alarms.
+///
+/// Returns the list of failed checks, if any.
+pub fn readiness(config: &Config, metrics: &Metrics) -> Result<(), Vec<String>> {
+    let mut failed = Vec::new();
+    if !metrics.udp_socket_bound() {
+        failed.push("UDP socket for the CMI is not bound".to_owned());
+    };
+    if !metrics.ami_connected() {
+        failed.push("last connection and login to AMI failed".to_owned());
+    };
+    let window = config.http.as_ref().and_then(|x| x.readiness_cmi_window);
+    if let Some(window) = window {
+        match metrics.since_last_packet() {
+            Some(x) if x <= window => (),
+            Some(x) => failed.push(format!(
+                "last packet from the CMI arrived {}s ago",
+                x.as_secs()
+            )),
+            None => failed.push("no packet from the CMI since startup".to_owned()),
+        };
+    };
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(failed)
+    }
+}
+
+/// Periodically connect and login to AMI, so that readiness reflects whether asterisk is
+/// reachable even when no alarm is sent.
+pub async fn check_ami_periodically(
+    config: Arc<SharedConfig>,
+    metrics: Arc<Metrics>,
+    interval: Duration,
+) {
+    loop {
+        smol::Timer::after(interval).await;
+        // pick up AMI settings changed by a reload
+        let config = config.get();
+        // the AMI connection is blocking
+        let res = smol::unblock(move || {
+            config
+                .asterisk_connection()
+                .map(|_conn| ())
+                .map_err(|e| e.to_string())
+        })
+        .await;
+        match res {
+            Ok(()) => {
+                debug!("Periodic AMI check succeeded.");
+                metrics.set_ami_connected(true);
+            }
+            Err(e) => {
+                warn!("Periodic AMI check failed: {e}");
+                metrics.set_ami_connected(false);
+            }
+        };
+    }
+}
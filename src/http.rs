@@ -0,0 +1,249 @@
+//! Minimal HTTP/1.1 server for the metrics, health and admin endpoints
+
+use std::{future::Future, time::Duration};
+
+use serde::Serialize;
+use smol::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, trace, warn};
+
+use crate::{config::Config, health, metrics::Metrics};
+
+/// Do not accept requests with a larger head than this
+const MAX_HEAD_LEN: usize = 8192;
+/// Do not accept requests with a larger body than this
+const MAX_BODY_LEN: usize = 65536;
+/// Close connections that do not send a full request within this time
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Decode `%XX` escapes. `+` is kept, so RFC 3339 offsets work unescaped.
+fn percent_decode(value: &str) -> Option<String> {
+    let mut res = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            res.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            res.push(b);
+        };
+    }
+    String::from_utf8(res).ok()
+}
+
+/// A parsed HTTP request
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// the decoded parameters of the query string
+    pub query: Vec<(String, String)>,
+    /// header names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+impl Request {
+    /// Parse the request line and headers.
+    fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut parts = lines.next()?.split(' ');
+        let method = parts.next()?.to_owned();
+        let target = parts.next()?;
+        if !parts.next()?.starts_with("HTTP/1.") {
+            return None;
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let path = path.to_owned();
+        let query = query
+            .split('&')
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                let (name, value) = x.split_once('=').unwrap_or((x, ""));
+                Some((percent_decode(name)?, percent_decode(value)?))
+            })
+            .collect::<Option<_>>()?;
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line.split_once(':')?;
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+        }
+        Some(Self {
+            method,
+            path,
+            query,
+            headers,
+            body: Vec::new(),
+        })
+    }
+
+    /// The value of the first query parameter with this name
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The value of the first header with this (lowercase) name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A response to send back
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+
+    pub fn json(status: u16, body: &impl Serialize) -> Self {
+        match serde_json::to_string(body) {
+            Ok(x) => Self {
+                status,
+                content_type: "application/json",
+                body: x,
+            },
+            Err(e) => Self::text(500, format!("Unable to serialize response: {e}\n")),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut res = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason(),
+            self.content_type,
+            self.body.len(),
+        )
+        .into_bytes();
+        res.extend_from_slice(self.body.as_bytes());
+        res
+    }
+}
+
+/// Answer requests to the metrics and health endpoints
+pub fn status_route(request: &Request, config: &Config, metrics: &Metrics) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body: metrics.render(),
+        },
+        // we are able to answer, so the process is alive
+        ("GET", "/healthz") => Response::text(200, "ok\n"),
+        ("GET", "/readyz") => match health::readiness(config, metrics) {
+            Ok(()) => Response::text(200, "ready\n"),
+            Err(failed) => Response::text(503, format!("not ready:\n{}\n", failed.join("\n"))),
+        },
+        (_, "/metrics" | "/healthz" | "/readyz") => Response::text(405, "Method Not Allowed\n"),
+        _ => Response::text(404, "Not Found\n"),
+    }
+}
+
+/// Read the request head (everything up to the empty line) and the body
+async fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut data = Vec::<u8>::new();
+    let mut buf = [0_u8; 1024];
+    let (mut request, body_start) = loop {
+        let bytes_read = stream.read(&mut buf).await.ok()?;
+        if bytes_read == 0 {
+            return None;
+        };
+        data.extend_from_slice(&buf[..bytes_read]);
+        if let Some(end) = data.windows(4).position(|x| x == b"\r\n\r\n") {
+            let request = Request::parse(std::str::from_utf8(&data[..end]).ok()?)?;
+            break (request, end + 4);
+        };
+        if data.len() > MAX_HEAD_LEN {
+            return None;
+        };
+    };
+    let body_len = match request.header("content-length") {
+        Some(x) => x.parse::<usize>().ok()?,
+        None => 0,
+    };
+    if body_len > MAX_BODY_LEN {
+        return None;
+    };
+    let mut body = data.split_off(body_start);
+    while body.len() < body_len {
+        let bytes_read = stream.read(&mut buf).await.ok()?;
+        if bytes_read == 0 {
+            return None;
+        };
+        body.extend_from_slice(&buf[..bytes_read]);
+    }
+    body.truncate(body_len);
+    request.body = body;
+    Some(request)
+}
+
+/// Answer a single request on this connection, then close it.
+async fn handle_connection<H, F>(mut stream: TcpStream, handler: H)
+where
+    H: Fn(Request) -> F,
+    F: Future<Output = Response>,
+{
+    let request = smol::future::or(read_request(&mut stream), async {
+        smol::Timer::after(REQUEST_TIMEOUT).await;
+        None
+    })
+    .await;
+    let response = match request {
+        Some(request) => {
+            trace!("HTTP request: {} {}", request.method, request.path);
+            handler(request).await
+        }
+        None => Response::text(400, "Bad Request\n"),
+    };
+    if let Err(e) = stream.write_all(&response.into_bytes()).await {
+        debug!("Unable to write HTTP response: {e}");
+    };
+}
+
+/// Accept connections forever, answering each one in its own task.
+pub async fn serve<H, F>(listener: TcpListener, handler: H)
+where
+    H: Fn(Request) -> F + Clone + Send + 'static,
+    F: Future<Output = Response> + Send + 'static,
+{
+    loop {
+        match listener.accept().await {
+            Ok((stream, _addr)) => {
+                smol::spawn(handle_connection(stream, handler.clone())).detach();
+            }
+            Err(e) => {
+                warn!("Unable to accept HTTP connection: {e}");
+            }
+        };
+    }
+}
@@ -0,0 +1,330 @@
+//! Append-only journal of all alarm events, for audits
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use time::{
+    format_description::well_known::{Iso8601, Rfc3339},
+    Date, OffsetDateTime,
+};
+use tracing::warn;
+
+use crate::{
+    alarm::{Event, ALL_ALARMS},
+    maintenance::parse_datetime,
+};
+
+/// Where to keep the journal
+#[derive(Debug, PartialEq, Eq)]
+pub struct JournalConfig {
+    pub file: PathBuf,
+}
+impl From<JournalConfigData> for JournalConfig {
+    fn from(value: JournalConfigData) -> Self {
+        Self {
+            file: value.file.into(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct JournalConfigData {
+    /// every event is appended as a line of JSON, e.g. `/var/lib/ta-asterisk-alarm/journal.jsonl`
+    pub file: String,
+}
+
+/// Append a single event as a line of JSON
+pub fn append(path: &Path, event: &Event) -> Result<(), Box<dyn std::error::Error>> {
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    // a single write, so concurrent readers never see half a line
+    file.write_all(&line)?;
+    Ok(())
+}
+
+/// A point in time given by the user: RFC 3339, a date (midnight UTC) or unix time
+pub fn parse_time(value: &str) -> Result<u64, String> {
+    if let Ok(x) = value.parse::<u64>() {
+        return Ok(x);
+    };
+    let at = match Date::parse(value, &Iso8601::DATE) {
+        Ok(x) => x.midnight().assume_utc(),
+        Err(_) => parse_datetime(value).map_err(|_| {
+            format!(
+                "{value:?} is not a time, e.g. 2026-10-01, 2026-10-01T08:00:00+02:00 or 1790000000"
+            )
+        })?,
+    };
+    Ok(u64::try_from(at.unix_timestamp()).unwrap_or_default())
+}
+
+/// Which events to read from the journal. Every given filter needs to match.
+#[derive(Debug, Default)]
+pub struct Query {
+    /// unix time, inclusive
+    pub from: Option<u64>,
+    /// unix time, exclusive
+    pub to: Option<u64>,
+    /// the name of the alarm input. Events concerning all alarms are included.
+    pub alarm: Option<String>,
+    /// part of the endpoint called or the url notified
+    pub endpoint: Option<String>,
+}
+impl Query {
+    pub fn matches(&self, event: &Event) -> bool {
+        !matches!(self.from, Some(x) if event.time < x)
+            && !matches!(self.to, Some(x) if event.time >= x)
+            && !matches!(&self.alarm, Some(x) if event.alarm != *x && event.alarm != ALL_ALARMS)
+            && !matches!(&self.endpoint, Some(x)
+                if !event.kind.endpoint().is_some_and(|e| e.contains(x.as_str())))
+    }
+}
+
+/// Read the events matching `query`, oldest first. A missing journal has no events.
+///
+/// Lines that are not an event, e.g. cut off by a full disk, are skipped with a warning.
+pub fn read(path: &Path, query: &Query) -> Result<Vec<Event>, Box<dyn std::error::Error>> {
+    let file = match std::fs::File::open(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut res = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        };
+        match serde_json::from_str::<Event>(&line) {
+            Ok(x) if query.matches(&x) => res.push(x),
+            Ok(_) => (),
+            Err(e) => warn!(
+                "Skipping line {} of the journal {}: {e}",
+                i + 1,
+                path.display()
+            ),
+        };
+    }
+    Ok(res)
+}
+
+/// Quote a CSV field if needed (RFC 4180)
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// The events as CSV with a header line: time (RFC 3339, UTC), alarm, kind, endpoint,
+/// action_id and the remaining fields of the event as `name=value` in detail
+pub fn to_csv(events: &[Event]) -> String {
+    let mut res = String::from("time,alarm,kind,endpoint,action_id,detail\r\n");
+    for event in events {
+        let time = OffsetDateTime::from_unix_timestamp(event.time as i64)
+            .ok()
+            .and_then(|x| x.format(&Rfc3339).ok())
+            .unwrap_or_else(|| event.time.to_string());
+        let fields = match serde_json::to_value(&event.kind) {
+            Ok(serde_json::Value::Object(x)) => x,
+            _ => Default::default(),
+        };
+        let kind = fields
+            .get("kind")
+            .and_then(|x| x.as_str())
+            .unwrap_or_default();
+        let detail = fields
+            .iter()
+            .filter(|(name, _)| {
+                !["kind", "endpoint", "target", "action_id"].contains(&name.as_str())
+            })
+            .map(|(name, value)| match value {
+                serde_json::Value::String(x) => format!("{name}={x}"),
+                serde_json::Value::Array(x) => format!(
+                    "{name}={}",
+                    x.iter()
+                        .map(|x| x.as_str().map_or_else(|| x.to_string(), ToOwned::to_owned))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                ),
+                x => format!("{name}={x}"),
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        res.push_str(
+            &[
+                time.as_str(),
+                &event.alarm,
+                kind,
+                event.kind.endpoint().unwrap_or_default(),
+                event.kind.action_id().unwrap_or_default(),
+                &detail,
+            ]
+            .map(csv_field)
+            .join(","),
+        );
+        res.push_str("\r\n");
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alarm::EventKind;
+
+    fn event(time: u64, alarm: &str, kind: EventKind) -> Event {
+        Event {
+            time,
+            alarm: alarm.to_owned(),
+            kind,
+        }
+    }
+
+    fn call(endpoint: &str) -> EventKind {
+        EventKind::CallSucceeded {
+            endpoint: endpoint.to_owned(),
+            action_id: "a1".to_owned(),
+        }
+    }
+
+    #[test]
+    fn parse_times() {
+        assert_eq!(parse_time("1790000000"), Ok(1_790_000_000));
+        assert_eq!(parse_time("2026-10-01"), Ok(1_790_812_800));
+        assert_eq!(parse_time("2026-10-01T02:00:00+02:00"), Ok(1_790_812_800));
+        for invalid in ["2026-13-01", "2026-10", "yesterday", "-1"] {
+            assert!(parse_time(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn queries_match_the_time_from_inclusive_to_exclusive() {
+        let query = Query {
+            from: Some(100),
+            to: Some(200),
+            ..Query::default()
+        };
+        let matches = |time| query.matches(&event(time, "fire", EventKind::Acknowledged));
+        assert!(!matches(99));
+        assert!(matches(100));
+        assert!(matches(199));
+        assert!(!matches(200));
+        assert!(Query::default().matches(&event(0, "fire", EventKind::Acknowledged)));
+    }
+
+    #[test]
+    fn queries_for_an_alarm_include_events_of_all_alarms() {
+        let query = Query {
+            alarm: Some("fire".to_owned()),
+            ..Query::default()
+        };
+        let reload = EventKind::ConfigReloadFailed {
+            error: "invalid".to_owned(),
+        };
+        assert!(query.matches(&event(1, "fire", EventKind::Acknowledged)));
+        assert!(query.matches(&event(1, ALL_ALARMS, reload)));
+        assert!(!query.matches(&event(1, "flood", EventKind::Acknowledged)));
+    }
+
+    #[test]
+    fn queries_match_part_of_the_endpoint_or_target() {
+        let query = Query {
+            endpoint: Some("1111".to_owned()),
+            ..Query::default()
+        };
+        let webhook = EventKind::ClearNotified {
+            target: "https://hooks.example.com/1111".to_owned(),
+            action_id: None,
+        };
+        assert!(query.matches(&event(1, "fire", call("PJSIP/1111@trunk"))));
+        assert!(query.matches(&event(1, "fire", webhook)));
+        assert!(!query.matches(&event(1, "fire", call("PJSIP/2222@trunk"))));
+        // events without an endpoint never match
+        assert!(!query.matches(&event(1, "fire", EventKind::Acknowledged)));
+        let failed_round = EventKind::CallFailed {
+            endpoint: String::new(),
+            action_id: None,
+            error: "1111".to_owned(),
+        };
+        assert!(!query.matches(&event(1, "fire", failed_round)));
+    }
+
+    #[test]
+    fn csv_has_a_header_and_a_line_per_event() {
+        let csv = to_csv(&[
+            event(1_790_812_800, "fire", call("PJSIP/1111@trunk")),
+            event(1_790_812_860, "fire", EventKind::Acknowledged),
+        ]);
+        assert_eq!(
+            csv,
+            "time,alarm,kind,endpoint,action_id,detail\r\n\
+             2026-10-01T00:00:00Z,fire,call_succeeded,PJSIP/1111@trunk,a1,\r\n\
+             2026-10-01T00:01:00Z,fire,acknowledged,,,\r\n"
+        );
+    }
+
+    #[test]
+    fn csv_quotes_fields_with_commas_quotes_and_line_breaks() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+
+        let failed = EventKind::CallFailed {
+            endpoint: "PJSIP/1111@trunk".to_owned(),
+            action_id: Some("a2".to_owned()),
+            error: "Response: Error\r\nMessage: \"Originate failed\", busy".to_owned(),
+        };
+        let csv = to_csv(&[event(1_790_812_800, "boiler, north", failed)]);
+        let line = csv.split_once("\r\n").map(|x| x.1).unwrap_or_default();
+        assert_eq!(
+            line,
+            "2026-10-01T00:00:00Z,\"boiler, north\",call_failed,PJSIP/1111@trunk,a2,\
+             \"error=Response: Error\r\nMessage: \"\"Originate failed\"\", busy\"\r\n"
+        );
+    }
+
+    #[test]
+    fn csv_detail_lists_the_remaining_fields() {
+        let csv = to_csv(&[
+            event(
+                1_790_812_800,
+                "fire",
+                EventKind::Cleared {
+                    trigger: "cmi 192.168.10.123:5442".to_owned(),
+                    duration_seconds: 90,
+                },
+            ),
+            event(
+                1_790_812_800,
+                ALL_ALARMS,
+                EventKind::ConfigReloaded {
+                    added: vec!["flood".to_owned(), "frost".to_owned()],
+                    changed: Vec::new(),
+                    removed: vec!["fire".to_owned()],
+                },
+            ),
+        ]);
+        let details = csv
+            .lines()
+            .skip(1)
+            .map(|x| x.rsplit_once(',').map(|x| x.1).unwrap_or_default())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            details,
+            [
+                "duration_seconds=90; trigger=cmi 192.168.10.123:5442",
+                "added=flood frost; changed=; removed=fire",
+            ]
+        );
+    }
+}
@@ -0,0 +1,71 @@
+//! Reads COE packets from Technische Alternative CMIs and tells asterisk to make outgoing
+//! calls when an alarm input reports the alarm state.
+//!
+//! The `ta-asterisk-alarm` binary is a thin wrapper around this library. The library exposes
+//! the parts needed to embed the alarm engine in another service:
+//!
+//! - [`Config`]: [`Config::create`] reads and checks a YAML file, or deserialize a
+//!   [`ConfigData`] and convert it with `try_into`. [`Config::alarms`] and
+//!   [`Config::asterisk`] read the alarms, the endpoints and the AMI settings.
+//! - [`Engine`]: evaluates COE datagrams against a config and calls asterisk, and reports
+//!   the active alarms, the [`Event`]s and the metrics
+//! - [`AmiConnection`]: the client of the asterisk manager interface (AMI), see
+//!   [`Config::asterisk_connection`]
+//! - [`run`]: the whole service, receiving COE on the configured UDP sockets
+//! - [`cli::run`]: the command line of the binary, with the service and the diagnostic
+//!   subcommands
+//!
+//! ```no_run
+//! use std::{net::UdpSocket, sync::Arc};
+//!
+//! use ta_asterisk_alarm::{Config, Engine};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let config = Config::create("/etc/ta-asterisk-alarm/config.yaml".as_ref())?;
+//! let engine = Engine::new(Arc::new(config));
+//! let socket = UdpSocket::bind("0.0.0.0:5442")?;
+//! let mut buf = [0_u8; 252];
+//! loop {
+//!     let (len, from) = socket.recv_from(&mut buf)?;
+//!     engine.handle_datagram(&buf[..len], from);
+//!     for event in engine.take_events() {
+//!         println!("{}: {:?}", event.alarm, event.kind);
+//!     }
+//! }
+//! # }
+//! ```
+
+#![warn(missing_docs)]
+
+pub(crate) mod admin;
+pub(crate) mod alarm;
+pub(crate) mod ami;
+pub(crate) mod capture;
+pub mod cli;
+pub(crate) mod clock;
+pub(crate) mod config;
+pub(crate) mod engine;
+pub(crate) mod health;
+pub(crate) mod http;
+pub(crate) mod journal;
+pub(crate) mod logging;
+pub(crate) mod maintenance;
+pub(crate) mod metrics;
+pub(crate) mod notify;
+pub(crate) mod persist;
+pub(crate) mod reload;
+pub(crate) mod rule;
+pub(crate) mod schedule;
+pub(crate) mod service;
+pub(crate) mod signal;
+pub(crate) mod simulate;
+pub(crate) mod source;
+pub(crate) mod vote;
+
+pub use alarm::{Event, EventKind};
+pub use ami::{AmiConnection, AmiError};
+pub use config::{AlarmConfig, AsteriskConfig, Config, ConfigData};
+pub use engine::Engine;
+pub use maintenance::MaintenanceSource;
+pub use schedule::Priority;
+pub use service::run;
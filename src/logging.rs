@@ -0,0 +1,546 @@
+//! Setup of the tracing outputs (stdout, log files, syslog, journald)
+
+use std::{
+    io::Write,
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    os::unix::net::UnixDatagram,
+    sync::{
+        mpsc::{Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::{Level, Metadata};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    fmt::{format::FmtSpan, MakeWriter},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    EnvFilter, Layer,
+};
+
+/// Name used as APP-NAME in syslog and as SYSLOG_IDENTIFIER in journald
+const APP_NAME: &str = "ta-asterisk-alarm";
+/// Give up connecting or writing to a syslog server via TCP after this long
+const SYSLOG_TCP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait at most this long before connecting to a syslog server via TCP again
+const SYSLOG_TCP_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Queue at most this many messages for a syslog server via TCP; newer ones are dropped
+const SYSLOG_TCP_QUEUE_LEN: usize = 1024;
+
+/// Configuration of the tracing outputs
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct LoggingConfig {
+    /// log events of this service at this level and above
+    /// (one of trace, debug, info, warn, error).
+    /// Ignored if RUST_LOG is set.
+    /// Default: info
+    pub level: Option<String>,
+    /// additional filter directives in `RUST_LOG` syntax, e.g. `ta_asterisk_alarm::ami=trace`.
+    /// Ignored if RUST_LOG is set.
+    #[serde(default)]
+    pub filters: Vec<String>,
+    /// format of the lines written to stdout and log files
+    /// Default: compact
+    #[serde(default)]
+    pub format: LogFormat,
+    /// log to stdout
+    /// Default: true
+    #[serde(default = "default_true")]
+    pub stdout: bool,
+    /// log to the local journald with structured fields
+    /// Default: false
+    #[serde(default)]
+    pub journald: bool,
+    /// log to a syslog server in RFC 5424 format
+    pub syslog: Option<SyslogConfig>,
+    /// log to rotating files
+    pub file: Option<LogFileConfig>,
+}
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: None,
+            filters: Vec::new(),
+            format: LogFormat::default(),
+            stdout: true,
+            journald: false,
+            syslog: None,
+            file: None,
+        }
+    }
+}
+
+/// Format of log lines
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// human readable, one line per event
+    #[default]
+    Compact,
+    /// one JSON object per event
+    Json,
+}
+
+/// When to start a new log file
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+impl From<LogRotation> for Rotation {
+    fn from(value: LogRotation) -> Self {
+        match value {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+/// Configuration for logging to rotating files
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct LogFileConfig {
+    /// write the log files to this directory
+    pub directory: String,
+    /// log files are named `<prefix>.<date>`
+    /// Default: "ta-asterisk-alarm.log"
+    pub prefix: Option<String>,
+    /// Default: daily
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// delete the oldest files if there are more than this many.
+    /// Default: keep all files
+    pub max_files: Option<usize>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// How to reach the syslog server
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogTransport {
+    /// the local unix datagram socket (`/dev/log` unless `address` is set)
+    Local,
+    /// plain UDP (RFC 5426)
+    Udp,
+    /// plain TCP with octet-counting framing (RFC 6587)
+    Tcp,
+}
+
+/// Configuration for logging to syslog
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct SyslogConfig {
+    pub transport: SyslogTransport,
+    /// `host:port` for udp/tcp, path of the socket for local.
+    /// Default: `/dev/log` for local, required otherwise
+    pub address: Option<String>,
+    /// Syslog facility name (e.g. daemon, local0..local7).
+    /// Default: daemon
+    pub facility: Option<String>,
+    /// HOSTNAME sent in the syslog header.
+    /// Default: the kernel hostname
+    pub hostname: Option<String>,
+}
+
+/// Everything that can go wrong while setting up the tracing outputs
+#[derive(Debug)]
+pub enum LoggingError {
+    /// unknown syslog facility name
+    UnknownFacility(String),
+    /// udp/tcp syslog without an address
+    NoSyslogAddress,
+    /// unable to connect to the syslog socket
+    SyslogConnect(std::io::Error),
+    /// unable to connect to journald
+    Journald(std::io::Error),
+    /// the level or a filter directive could not be parsed
+    Filter(tracing_subscriber::filter::ParseError),
+    /// unable to open the log file
+    File(tracing_appender::rolling::InitError),
+}
+impl core::fmt::Display for LoggingError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::UnknownFacility(x) => write!(f, "Unknown syslog facility {x}"),
+            Self::NoSyslogAddress => write!(f, "Syslog via udp or tcp needs an address"),
+            Self::SyslogConnect(x) => write!(f, "Unable to connect to syslog: {x}"),
+            Self::Journald(x) => write!(f, "Unable to connect to journald: {x}"),
+            Self::Filter(x) => write!(f, "Invalid log level or filter: {x}"),
+            Self::File(x) => write!(f, "Unable to open log file: {x}"),
+        }
+    }
+}
+impl std::error::Error for LoggingError {}
+
+/// Convert a syslog facility name to its numerical code
+pub fn facility_code(name: &str) -> Option<u8> {
+    Some(match name {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => return None,
+    })
+}
+
+/// Convert a tracing level to the syslog severity
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// The open connection to a syslog server
+enum SyslogSocket {
+    Local(UnixDatagram),
+    Udp(UdpSocket),
+    /// frames for the thread writing to the TCP stream, see [`forward_tcp`]
+    Tcp(SyncSender<Vec<u8>>),
+}
+
+/// Connect to the first address of `address` accepting a connection
+fn connect_tcp(address: &str) -> std::io::Result<TcpStream> {
+    let mut error = None;
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, SYSLOG_TCP_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(SYSLOG_TCP_TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(e) => error = Some(e),
+        };
+    }
+    Err(error.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{address} has no address"),
+        )
+    }))
+}
+
+/// Write the frames to the syslog server, reconnecting with a backoff.
+///
+/// Runs in its own thread, so a slow or unreachable server never blocks logging; frames
+/// arriving while it is unreachable are dropped.
+fn forward_tcp(address: String, mut stream: Option<TcpStream>, frames: Receiver<Vec<u8>>) {
+    let mut backoff = Duration::from_secs(1);
+    let mut retry_at = Instant::now();
+    for frame in frames {
+        // reconnect once if the server closed the stream
+        for _ in 0..2 {
+            if stream.is_none() {
+                if Instant::now() < retry_at {
+                    break;
+                };
+                match connect_tcp(&address) {
+                    Ok(x) => {
+                        stream = Some(x);
+                        backoff = Duration::from_secs(1);
+                    }
+                    Err(_) => {
+                        retry_at = Instant::now() + backoff;
+                        backoff = (backoff * 2).min(SYSLOG_TCP_MAX_BACKOFF);
+                        break;
+                    }
+                };
+            };
+            if let Some(s) = &mut stream {
+                if s.write_all(&frame).is_ok() {
+                    break;
+                };
+                stream = None;
+            };
+        }
+    }
+}
+
+struct SyslogSink {
+    socket: SyslogSocket,
+    facility: u8,
+    hostname: String,
+    pid: u32,
+}
+impl SyslogSink {
+    fn connect(config: &SyslogConfig) -> Result<Self, LoggingError> {
+        let facility_name = config.facility.as_deref().unwrap_or("daemon");
+        let facility = facility_code(facility_name)
+            .ok_or_else(|| LoggingError::UnknownFacility(facility_name.to_owned()))?;
+        let address = match (config.transport, &config.address) {
+            (_, Some(x)) => x.clone(),
+            (SyslogTransport::Local, None) => "/dev/log".to_owned(),
+            (_, None) => return Err(LoggingError::NoSyslogAddress),
+        };
+        let socket = match config.transport {
+            SyslogTransport::Local => {
+                let sock = UnixDatagram::unbound().map_err(LoggingError::SyslogConnect)?;
+                sock.connect(&address).map_err(LoggingError::SyslogConnect)?;
+                SyslogSocket::Local(sock)
+            }
+            SyslogTransport::Udp => {
+                // bind to the family of the server, also if a hostname resolves to IPv6
+                let server = address
+                    .to_socket_addrs()
+                    .map_err(LoggingError::SyslogConnect)?
+                    .next()
+                    .ok_or_else(|| {
+                        LoggingError::SyslogConnect(std::io::Error::new(
+                            std::io::ErrorKind::NotFound,
+                            format!("{address} has no address"),
+                        ))
+                    })?;
+                let bind: SocketAddr = if server.is_ipv6() {
+                    (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+                } else {
+                    (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+                };
+                let sock = UdpSocket::bind(bind).map_err(LoggingError::SyslogConnect)?;
+                sock.connect(server).map_err(LoggingError::SyslogConnect)?;
+                SyslogSocket::Udp(sock)
+            }
+            SyslogTransport::Tcp => {
+                let stream = connect_tcp(&address).map_err(LoggingError::SyslogConnect)?;
+                let (tx, rx) = std::sync::mpsc::sync_channel(SYSLOG_TCP_QUEUE_LEN);
+                let address = address.clone();
+                std::thread::Builder::new()
+                    .name("syslog-tcp".to_owned())
+                    .spawn(move || forward_tcp(address, Some(stream), rx))
+                    .map_err(LoggingError::SyslogConnect)?;
+                SyslogSocket::Tcp(tx)
+            }
+        };
+        let hostname = config.hostname.clone().unwrap_or_else(|| {
+            std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|x| x.trim().to_owned())
+                .unwrap_or_else(|_| "-".to_owned())
+        });
+        Ok(Self {
+            socket,
+            facility,
+            hostname,
+            pid: std::process::id(),
+        })
+    }
+
+    /// Send a single message with the given severity.
+    ///
+    /// Errors are ignored; there is nowhere left to report them to.
+    fn send(&mut self, severity: u8, msg: &[u8]) {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_else(|_| "-".to_owned());
+        let mut frame = format!(
+            "<{}>1 {timestamp} {} {APP_NAME} {} - - ",
+            self.facility * 8 + severity,
+            self.hostname,
+            self.pid,
+        )
+        .into_bytes();
+        // the fmt layer terminates each event with a newline
+        frame.extend_from_slice(msg.strip_suffix(b"\n").unwrap_or(msg));
+        match &mut self.socket {
+            SyslogSocket::Local(sock) => {
+                let _ = sock.send(&frame);
+            }
+            SyslogSocket::Udp(sock) => {
+                let _ = sock.send(&frame);
+            }
+            SyslogSocket::Tcp(frames) => {
+                let mut counted = format!("{} ", frame.len()).into_bytes();
+                counted.extend_from_slice(&frame);
+                // dropped if the queue is full
+                let _ = frames.try_send(counted);
+            }
+        }
+    }
+}
+/// Creates a [`SyslogWriter`] for every event
+#[derive(Clone)]
+struct SyslogMakeWriter {
+    sink: Arc<Mutex<SyslogSink>>,
+}
+impl<'a> MakeWriter<'a> for SyslogMakeWriter {
+    type Writer = SyslogWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogWriter {
+            sink: self.sink.clone(),
+            severity: severity(&Level::INFO),
+            buf: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SyslogWriter {
+            sink: self.sink.clone(),
+            severity: severity(meta.level()),
+            buf: Vec::new(),
+        }
+    }
+}
+
+/// Collects one formatted event and sends it to syslog when dropped
+struct SyslogWriter {
+    sink: Arc<Mutex<SyslogSink>>,
+    severity: u8,
+    buf: Vec<u8>,
+}
+impl Write for SyslogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl Drop for SyslogWriter {
+    fn drop(&mut self) {
+        if self.buf.is_empty() {
+            return;
+        };
+        if let Ok(mut sink) = self.sink.lock() {
+            sink.send(self.severity, &self.buf);
+        };
+    }
+}
+
+type BoxedLayer<S> = Box<dyn Layer<S> + Send + Sync + 'static>;
+
+/// A fmt layer writing to `writer` in the configured format
+fn fmt_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer<S>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+        .with_line_number(true);
+    match format {
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+/// The layers that write to stdout, files, syslog and journald as configured
+fn output_layers<S>(config: &LoggingConfig) -> Result<Vec<BoxedLayer<S>>, LoggingError>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    let mut layers: Vec<BoxedLayer<S>> = Vec::new();
+    if config.stdout {
+        layers.push(fmt_layer(config.format, std::io::stdout, true));
+    };
+    if let Some(file_config) = &config.file {
+        let mut builder = RollingFileAppender::builder()
+            .rotation(file_config.rotation.into())
+            .filename_prefix(file_config.prefix.as_deref().unwrap_or("ta-asterisk-alarm.log"));
+        if let Some(max_files) = file_config.max_files {
+            builder = builder.max_log_files(max_files);
+        };
+        let appender = builder
+            .build(&file_config.directory)
+            .map_err(LoggingError::File)?;
+        layers.push(fmt_layer(config.format, appender, false));
+    };
+    if let Some(syslog_config) = &config.syslog {
+        let sink = SyslogSink::connect(syslog_config)?;
+        layers.push(
+            tracing_subscriber::fmt::layer()
+                .compact()
+                .without_time()
+                .with_ansi(false)
+                .with_level(false)
+                .with_writer(SyslogMakeWriter {
+                    sink: Arc::new(Mutex::new(sink)),
+                })
+                .boxed(),
+        );
+    };
+    if config.journald {
+        layers.push(
+            tracing_journald::layer()
+                .map_err(LoggingError::Journald)?
+                .with_field_prefix(None)
+                .with_syslog_identifier(APP_NAME.to_owned())
+                .boxed(),
+        );
+    };
+    Ok(layers)
+}
+
+/// The filter deciding which events are logged.
+///
+/// RUST_LOG takes precedence over the config.
+fn env_filter(config: &LoggingConfig) -> Result<EnvFilter, LoggingError> {
+    if let Ok(directives) = std::env::var(EnvFilter::DEFAULT_ENV) {
+        return EnvFilter::builder()
+            .parse(directives)
+            .map_err(LoggingError::Filter);
+    };
+    let mut directives = vec![format!(
+        "ta_asterisk_alarm={}",
+        config.level.as_deref().unwrap_or("info")
+    )];
+    directives.extend(config.filters.iter().cloned());
+    EnvFilter::builder()
+        .parse(directives.join(","))
+        .map_err(LoggingError::Filter)
+}
+
+/// The subscriber used before the config is read.
+pub fn bootstrap_subscriber() -> impl tracing::Subscriber + Send + Sync {
+    let config = LoggingConfig::default();
+    tracing_subscriber::registry()
+        .with(env_filter(&config).unwrap_or_else(|_| EnvFilter::new("ta_asterisk_alarm=info")))
+        .with(output_layers(&config).expect("stdout layer is infallible"))
+}
+
+/// Like [`bootstrap_subscriber`], but logs to stderr, for subcommands printing data to stdout
+pub fn stderr_subscriber() -> impl tracing::Subscriber + Send + Sync {
+    let config = LoggingConfig::default();
+    tracing_subscriber::registry()
+        .with(env_filter(&config).unwrap_or_else(|_| EnvFilter::new("ta_asterisk_alarm=info")))
+        .with(fmt_layer(config.format, std::io::stderr, true))
+}
+
+/// Install the global tracing subscriber with the outputs given in the config.
+pub fn init(config: &LoggingConfig) -> Result<(), LoggingError> {
+    let subscriber = tracing_subscriber::registry()
+        .with(env_filter(config)?)
+        .with(output_layers(config)?);
+    tracing::subscriber::set_global_default(subscriber).expect("static tracing config");
+    Ok(())
+}
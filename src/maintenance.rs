@@ -0,0 +1,225 @@
+//! Maintenance mode: no calls while technicians work on the heating system
+
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{config::OneOrMore, source::Sources};
+
+/// Maintenance started at runtime ends after this long, unless configured otherwise
+const DEFAULT_MAX_DURATION: Duration = Duration::from_secs(4 * 60 * 60);
+
+/// An RFC 3339 date and time, e.g. `2026-11-02T08:00:00+01:00`
+pub fn parse_datetime(value: &str) -> Result<OffsetDateTime, String> {
+    OffsetDateTime::parse(value, &Rfc3339).map_err(|e| {
+        format!("{value:?} is not a date and time ({e}), e.g. 2026-11-02T08:00:00+01:00")
+    })
+}
+
+/// Planned maintenance, given in the config
+#[derive(Debug, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    pub from: OffsetDateTime,
+    /// exclusive
+    pub to: OffsetDateTime,
+    /// empty means all alarms
+    pub alarms: Vec<String>,
+}
+impl MaintenanceWindow {
+    fn applies(&self, alarm: &str, at: OffsetDateTime) -> bool {
+        self.from <= at
+            && at < self.to
+            && (self.alarms.is_empty() || self.alarms.iter().any(|x| x == alarm))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MaintenanceWindowData {
+    /// RFC 3339, e.g. `2026-11-02T08:00:00+01:00`
+    pub from: String,
+    /// RFC 3339, exclusive
+    pub to: String,
+    /// only these alarms are in maintenance. Default: all alarms
+    #[serde(default)]
+    pub alarms: Vec<String>,
+}
+
+/// A digital value sent by a CMI that switches maintenance on and off
+#[derive(Debug, PartialEq, Eq)]
+pub struct MaintenanceInput {
+    pub expect_from_addr: Sources,
+    pub expect_index: u8,
+    /// numbered as in the web-gui
+    pub expect_pdo: u8,
+    /// IF true: ON starts maintenance, OFF ends it
+    /// IF false: OFF starts maintenance, ON ends it
+    pub maintenance_when_on: bool,
+    /// empty means all alarms
+    pub alarms: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MaintenanceInputData {
+    /// Expect the packet to arrive from this address, subnet or hostname, or any of a list
+    /// of them.
+    pub expect_from_addr: OneOrMore,
+    /// expect this CAN-ID
+    pub expect_index: u8,
+    /// expect this PDO
+    pub expect_pdo: u8,
+    /// Default: true
+    pub maintenance_when_on: Option<bool>,
+    /// only these alarms are in maintenance. Default: all alarms
+    #[serde(default)]
+    pub alarms: Vec<String>,
+}
+
+/// How maintenance is started and when it ends
+#[derive(Debug, PartialEq, Eq)]
+pub struct MaintenanceConfig {
+    /// maintenance started via the admin API or the input ends after this long at the latest
+    pub max_duration: Duration,
+    pub windows: Vec<MaintenanceWindow>,
+    pub input: Option<MaintenanceInput>,
+}
+impl TryFrom<MaintenanceConfigData> for MaintenanceConfig {
+    type Error = String;
+    fn try_from(value: MaintenanceConfigData) -> Result<Self, Self::Error> {
+        let windows = value
+            .windows
+            .into_iter()
+            .map(|x| {
+                Ok(MaintenanceWindow {
+                    from: parse_datetime(&x.from)?,
+                    to: parse_datetime(&x.to)?,
+                    alarms: x.alarms,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let input = value
+            .input
+            .map(|x| {
+                Ok::<_, String>(MaintenanceInput {
+                    expect_from_addr: x.expect_from_addr.as_slice().try_into()?,
+                    expect_index: x.expect_index,
+                    expect_pdo: x.expect_pdo,
+                    maintenance_when_on: x.maintenance_when_on.unwrap_or(true),
+                    alarms: x.alarms,
+                })
+            })
+            .transpose()?;
+        let max_duration = match value.max_minutes {
+            Some(x) => Duration::from_secs(
+                x.checked_mul(60)
+                    .ok_or_else(|| format!("{x} minutes is too long"))?,
+            ),
+            None => DEFAULT_MAX_DURATION,
+        };
+        Ok(Self {
+            max_duration,
+            windows,
+            input,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct MaintenanceConfigData {
+    /// maintenance started via the admin API or the input ends after this many minutes at
+    /// the latest, even if the input still reports maintenance.
+    /// Default: 240
+    pub max_minutes: Option<u64>,
+    /// planned maintenance
+    #[serde(default)]
+    pub windows: Vec<MaintenanceWindowData>,
+    /// a digital value from the CMI, e.g. a key switch in the boiler room
+    pub input: Option<MaintenanceInputData>,
+}
+
+impl MaintenanceConfig {
+    /// The planned window covering this alarm at `at`, if any
+    pub fn window_at(&self, alarm: &str, at: OffsetDateTime) -> Option<&MaintenanceWindow> {
+        self.windows.iter().find(|x| x.applies(alarm, at))
+    }
+}
+
+/// How maintenance was started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum MaintenanceSource {
+    /// started via the admin API
+    AdminApi,
+    /// started by the maintenance input of a CMI
+    Input,
+}
+impl core::fmt::Display for MaintenanceSource {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::AdminApi => write!(f, "admin api"),
+            Self::Input => write!(f, "maintenance input"),
+        }
+    }
+}
+
+/// Maintenance started at runtime
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Maintenance {
+    /// unix time it ends
+    pub until: u64,
+    pub source: MaintenanceSource,
+}
+
+/// All maintenance started at runtime
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MaintenanceState {
+    /// for all alarms
+    pub global: Option<Maintenance>,
+    /// for single alarms, by name
+    pub alarms: BTreeMap<String, Maintenance>,
+    /// the last value of the input was the maintenance state
+    #[serde(skip)]
+    pub input_on: bool,
+}
+impl MaintenanceState {
+    /// The maintenance of a single alarm (`Some(name)`) or of all alarms (`None`)
+    pub fn get(&self, alarm: Option<&str>) -> Option<&Maintenance> {
+        match alarm {
+            Some(name) => self.alarms.get(name),
+            None => self.global.as_ref(),
+        }
+    }
+
+    /// Start or replace maintenance, or end it with `None`. Returns the previous one.
+    pub fn set(&mut self, alarm: Option<&str>, value: Option<Maintenance>) -> Option<Maintenance> {
+        match (alarm, value) {
+            (Some(name), Some(x)) => self.alarms.insert(name.to_owned(), x),
+            (Some(name), None) => self.alarms.remove(name),
+            (None, x) => core::mem::replace(&mut self.global, x),
+        }
+    }
+
+    /// Remove and return everything ending at or before `now`, `None` standing for all alarms
+    pub fn expire(&mut self, now: u64) -> Vec<(Option<String>, Maintenance)> {
+        let mut res = Vec::new();
+        if self.global.as_ref().is_some_and(|x| x.until <= now) {
+            res.extend(self.global.take().map(|x| (None, x)));
+        };
+        self.alarms.retain(|name, x| {
+            if x.until <= now {
+                res.push((Some(name.clone()), x.clone()));
+            };
+            x.until > now
+        });
+        res
+    }
+
+    /// The maintenance covering this alarm, the longer one if there are two
+    pub fn covering(&self, alarm: &str) -> Option<&Maintenance> {
+        [self.global.as_ref(), self.alarms.get(alarm)]
+            .into_iter()
+            .flatten()
+            .max_by_key(|x| x.until)
+    }
+}
@@ -0,0 +1,348 @@
+//! Counters and gauges exported in the prometheus text format
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Prefix of all metric names
+const PREFIX: &str = "ta_asterisk_alarm";
+/// Count at most this many rejected sources separately, the rest as "other"
+const MAX_REJECTED_SOURCES: usize = 100;
+
+/// Why a COE payload (or the whole packet) was ignored
+#[derive(Debug, Clone, Copy)]
+pub enum DropReason {
+    /// the packet came from an unexpected IP
+    SourceIp,
+    /// the payload was sent to an unexpected CAN-ID
+    Node,
+    /// the payload was sent to an unexpected PDO
+    Pdo,
+    /// the payload had the expected node and PDO, but was not Digital ON/OFF
+    ValueType,
+}
+impl DropReason {
+    fn label(self) -> &'static str {
+        match self {
+            Self::SourceIp => "source_ip",
+            Self::Node => "node",
+            Self::Pdo => "pdo",
+            Self::ValueType => "value_type",
+        }
+    }
+}
+
+/// A family of values, distinguished by the value of a single label
+#[derive(Debug, Default)]
+struct Labeled<T> {
+    values: Mutex<BTreeMap<String, T>>,
+}
+impl<T: Copy + Default + core::fmt::Display> Labeled<T> {
+    fn update(&self, label: &str, f: impl FnOnce(&mut T)) {
+        let mut values = self.values.lock().expect("metrics mutex poisoned");
+        f(values.entry(label.to_owned()).or_default());
+    }
+
+    /// Like `update`, but labels beyond the first `max` are counted as "other"
+    fn update_capped(&self, label: &str, max: usize, f: impl FnOnce(&mut T)) {
+        let mut values = self.values.lock().expect("metrics mutex poisoned");
+        let label = if values.contains_key(label) || values.len() < max {
+            label
+        } else {
+            "other"
+        };
+        f(values.entry(label.to_owned()).or_default());
+    }
+
+    fn remove(&self, label: &str) {
+        self.values
+            .lock()
+            .expect("metrics mutex poisoned")
+            .remove(label);
+    }
+
+    fn write(&self, out: &mut String, name: &str, label_name: &str) {
+        let values = self.values.lock().expect("metrics mutex poisoned");
+        for (label, value) in values.iter() {
+            let _ = writeln!(
+                out,
+                "{PREFIX}_{name}{{{label_name}=\"{}\"}} {value}",
+                escape_label(label)
+            );
+        }
+    }
+}
+
+/// Escape a label value as required by the text exposition format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// All metrics of this service
+#[derive(Debug, Default)]
+pub struct Metrics {
+    udp_packets_received: AtomicU64,
+    parse_errors: AtomicU64,
+    packets_dropped: Labeled<u64>,
+    rejected_sources: Labeled<u64>,
+    alarm_raises: Labeled<u64>,
+    alarm_clears: Labeled<u64>,
+    alarm_active: Labeled<u8>,
+    originate_attempts: Labeled<u64>,
+    originate_successes: Labeled<u64>,
+    originate_failures: Labeled<u64>,
+    ami_reconnects: AtomicU64,
+    ami_connected: AtomicU64,
+    udp_socket_bound: AtomicU64,
+    last_packet_timestamp: Labeled<f64>,
+    config_reloads: Labeled<u64>,
+    /// when the last packet from any expected CMI arrived
+    last_packet: Mutex<Option<Instant>>,
+}
+impl Metrics {
+    pub fn udp_packet_received(&self) {
+        self.udp_packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn packet_dropped(&self, reason: DropReason) {
+        self.packets_dropped.update(reason.label(), |x| *x += 1);
+    }
+
+    /// A packet came from an address no input accepts
+    pub fn source_rejected(&self, source: &str) {
+        self.rejected_sources
+            .update_capped(source, MAX_REJECTED_SOURCES, |x| *x += 1);
+    }
+
+    /// Remember when we last heard from this CMI
+    pub fn packet_from(&self, cmi: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs_f64())
+            .unwrap_or_default();
+        self.last_packet_timestamp.update(cmi, |x| *x = now);
+        *self.last_packet.lock().expect("metrics mutex poisoned") = Some(Instant::now());
+    }
+
+    /// Time since we last heard from any expected CMI, if we ever did
+    pub fn since_last_packet(&self) -> Option<Duration> {
+        self.last_packet
+            .lock()
+            .expect("metrics mutex poisoned")
+            .map(|x| x.elapsed())
+    }
+
+    pub fn alarm_raised(&self, alarm: &str) {
+        self.alarm_raises.update(alarm, |x| *x += 1);
+        self.alarm_active.update(alarm, |x| *x = 1);
+    }
+
+    pub fn alarm_cleared(&self, alarm: &str) {
+        self.alarm_clears.update(alarm, |x| *x += 1);
+        self.alarm_active.update(alarm, |x| *x = 0);
+    }
+
+    /// The alarm was active when the service stopped and is restored as active
+    pub fn alarm_restored(&self, alarm: &str) {
+        self.alarm_active.update(alarm, |x| *x = 1);
+    }
+
+    /// Make the alarm show up with state 0 before it was ever raised
+    pub fn register_alarm(&self, alarm: &str) {
+        self.alarm_active.update(alarm, |_| ());
+        self.alarm_raises.update(alarm, |_| ());
+        self.alarm_clears.update(alarm, |_| ());
+    }
+
+    /// The alarm was removed from the config. Its counters are kept.
+    pub fn unregister_alarm(&self, alarm: &str) {
+        self.alarm_active.remove(alarm);
+    }
+
+    /// An attempt to reload the config succeeded or failed
+    pub fn config_reload(&self, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        self.config_reloads.update(result, |x| *x += 1);
+    }
+
+    pub fn originate_attempt(&self, endpoint: &str) {
+        self.originate_attempts.update(endpoint, |x| *x += 1);
+    }
+
+    pub fn originate_success(&self, endpoint: &str) {
+        self.originate_successes.update(endpoint, |x| *x += 1);
+    }
+
+    pub fn originate_failure(&self, endpoint: &str) {
+        self.originate_failures.update(endpoint, |x| *x += 1);
+    }
+
+    pub fn ami_reconnect(&self) {
+        self.ami_reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Set whether the last attempt to connect and login to AMI succeeded
+    pub fn set_ami_connected(&self, connected: bool) {
+        self.ami_connected.store(connected.into(), Ordering::Relaxed);
+    }
+
+    pub fn ami_connected(&self) -> bool {
+        self.ami_connected.load(Ordering::Relaxed) == 1
+    }
+
+    pub fn set_udp_socket_bound(&self, bound: bool) {
+        self.udp_socket_bound.store(bound.into(), Ordering::Relaxed);
+    }
+
+    pub fn udp_socket_bound(&self) -> bool {
+        self.udp_socket_bound.load(Ordering::Relaxed) == 1
+    }
+
+    /// Render all metrics in the prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+            let _ = writeln!(out, "# TYPE {PREFIX}_{name} counter");
+            let _ = writeln!(out, "{PREFIX}_{name} {value}");
+        };
+        let header = |out: &mut String, name: &str, help: &str, kind: &str| {
+            let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+            let _ = writeln!(out, "# TYPE {PREFIX}_{name} {kind}");
+        };
+
+        counter(
+            &mut out,
+            "udp_packets_received_total",
+            "UDP packets received on the CMI listen socket.",
+            self.udp_packets_received.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "coe_parse_errors_total",
+            "UDP packets that could not be parsed as COE.",
+            self.parse_errors.load(Ordering::Relaxed),
+        );
+        header(
+            &mut out,
+            "packets_dropped_total",
+            "COE packets or payloads ignored by the IP, node, PDO or value type filter.",
+            "counter",
+        );
+        self.packets_dropped
+            .write(&mut out, "packets_dropped_total", "reason");
+        header(
+            &mut out,
+            "rejected_packets_total",
+            "UDP packets from addresses no input accepts, by address (the first 100, the rest as other).",
+            "counter",
+        );
+        self.rejected_sources
+            .write(&mut out, "rejected_packets_total", "source");
+        header(
+            &mut out,
+            "alarm_raises_total",
+            "Transitions from the good state to the alarm state.",
+            "counter",
+        );
+        self.alarm_raises
+            .write(&mut out, "alarm_raises_total", "alarm");
+        header(
+            &mut out,
+            "alarm_clears_total",
+            "Transitions from the alarm state to the good state.",
+            "counter",
+        );
+        self.alarm_clears
+            .write(&mut out, "alarm_clears_total", "alarm");
+        header(
+            &mut out,
+            "alarm_active",
+            "1 if the last relevant packet was in the alarm state.",
+            "gauge",
+        );
+        self.alarm_active.write(&mut out, "alarm_active", "alarm");
+        header(
+            &mut out,
+            "originate_attempts_total",
+            "Originate actions sent to asterisk.",
+            "counter",
+        );
+        self.originate_attempts
+            .write(&mut out, "originate_attempts_total", "endpoint");
+        header(
+            &mut out,
+            "originate_successes_total",
+            "Originate actions acknowledged by asterisk.",
+            "counter",
+        );
+        self.originate_successes
+            .write(&mut out, "originate_successes_total", "endpoint");
+        header(
+            &mut out,
+            "originate_failures_total",
+            "Originate actions that failed or were rejected by asterisk.",
+            "counter",
+        );
+        self.originate_failures
+            .write(&mut out, "originate_failures_total", "endpoint");
+        counter(
+            &mut out,
+            "ami_reconnects_total",
+            "Connections opened to AMI after startup.",
+            self.ami_reconnects.load(Ordering::Relaxed),
+        );
+        header(
+            &mut out,
+            "ami_connected",
+            "1 if the last connection and login to AMI succeeded.",
+            "gauge",
+        );
+        let _ = writeln!(
+            out,
+            "{PREFIX}_ami_connected {}",
+            self.ami_connected.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "udp_socket_bound",
+            "1 if the UDP socket for the CMI is bound.",
+            "gauge",
+        );
+        let _ = writeln!(
+            out,
+            "{PREFIX}_udp_socket_bound {}",
+            self.udp_socket_bound.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "last_packet_timestamp_seconds",
+            "Unix time of the last UDP packet received from each CMI.",
+            "gauge",
+        );
+        self.last_packet_timestamp
+            .write(&mut out, "last_packet_timestamp_seconds", "cmi");
+        header(
+            &mut out,
+            "config_reloads_total",
+            "Attempts to reload the config, by result.",
+            "counter",
+        );
+        self.config_reloads
+            .write(&mut out, "config_reloads_total", "result");
+        out
+    }
+}
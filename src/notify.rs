@@ -0,0 +1,230 @@
+//! "All clear" notifications when an alarm returns to the good state: a call with its own
+//! extension and a webhook
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    time::Duration,
+};
+
+use rustls::{ClientConnection, StreamOwned};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+
+use crate::config::Config;
+
+/// Give up on a webhook that does not connect or answer within this time
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where to POST events to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookUrl {
+    pub tls: bool,
+    /// without brackets for IPv6 addresses
+    pub host: String,
+    pub port: u16,
+    /// including the query string
+    pub path: String,
+}
+impl WebhookUrl {
+    /// `host` or `host:port` for the `Host` header, with brackets for IPv6 addresses
+    fn authority(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        match (self.tls, self.port) {
+            (true, 443) | (false, 80) => host,
+            (_, port) => format!("{host}:{port}"),
+        }
+    }
+}
+/// Leaves out the query string, which often carries a token
+impl core::fmt::Display for WebhookUrl {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+        let path = self.path.split('?').next().unwrap_or_default();
+        if self.host.contains(':') {
+            write!(f, "{scheme}://[{}]:{}{path}", self.host, self.port)
+        } else {
+            write!(f, "{scheme}://{}:{}{path}", self.host, self.port)
+        }
+    }
+}
+
+/// `http://host[:port][/path]` or `https://host[:port][/path]`
+pub fn parse_url(value: &str) -> Result<WebhookUrl, String> {
+    let err = |reason: &str| {
+        format!("{value:?} is not a webhook url ({reason}), e.g. https://hooks.example.com/alarm")
+    };
+    let (tls, rest) = if let Some(x) = value.strip_prefix("https://") {
+        (true, x)
+    } else if let Some(x) = value.strip_prefix("http://") {
+        (false, x)
+    } else {
+        return Err(err("needs to start with http:// or https://"));
+    };
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+        Some(i) => (&rest[..i], rest[i..].to_owned()),
+        None => (rest, "/".to_owned()),
+    };
+    if authority.contains('@') {
+        return Err(err("user info is not supported"));
+    };
+    let (host, port) = if let Some(x) = authority.strip_prefix('[') {
+        let (host, port) = x.split_once(']').ok_or_else(|| err("unclosed ["))?;
+        (host, port.strip_prefix(':'))
+    } else {
+        match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(err("no host"));
+    };
+    let port = match port {
+        Some(x) => x.parse::<u16>().map_err(|_| err("invalid port"))?,
+        None if tls => 443,
+        None => 80,
+    };
+    if path.contains(|c: char| c.is_ascii_whitespace() || c.is_ascii_control()) {
+        return Err(err("whitespace in the path"));
+    };
+    Ok(WebhookUrl {
+        tls,
+        host: host.to_owned(),
+        port,
+        path,
+    })
+}
+
+/// What to do when an alarm clears
+#[derive(Debug, PartialEq, Eq)]
+pub struct OnClearConfig {
+    /// call the endpoints that were called for the alarm with this extension
+    pub exten: Option<String>,
+    /// Default: `asterisk.execute_context`
+    pub context: Option<String>,
+    pub webhook: Option<WebhookUrl>,
+}
+impl TryFrom<OnClearConfigData> for OnClearConfig {
+    type Error = String;
+    fn try_from(value: OnClearConfigData) -> Result<Self, Self::Error> {
+        Ok(Self {
+            exten: value.exten,
+            context: value.context,
+            webhook: value.webhook.as_deref().map(parse_url).transpose()?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OnClearConfigData {
+    /// call everyone who was called for the alarm again, with this extension.
+    /// The channel variables ALARM_NAME and ALARM_DURATION (in seconds) are set.
+    /// Default: no all-clear call
+    pub exten: Option<String>,
+    /// Default: `asterisk.execute_context`
+    pub context: Option<String>,
+    /// POST a JSON event to this http:// or https:// url. Default: no webhook
+    pub webhook: Option<String>,
+}
+
+/// Sent to the webhook when an alarm clears
+#[derive(Debug, Serialize)]
+pub struct ClearEvent<'a> {
+    /// always "cleared"
+    pub event: &'static str,
+    pub alarm: &'a str,
+    /// unix time
+    pub raised_at: u64,
+    /// unix time
+    pub cleared_at: u64,
+    pub duration_seconds: u64,
+    /// the endpoints that were called for the alarm
+    pub called_endpoints: &'a [String],
+}
+
+/// Either a plain or a TLS stream to the webhook
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(x) => x.read(buf),
+            Self::Tls(x) => x.read(buf),
+        }
+    }
+}
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(x) => x.write(buf),
+            Self::Tls(x) => x.write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(x) => x.flush(),
+            Self::Tls(x) => x.flush(),
+        }
+    }
+}
+
+/// POST `body` as JSON to `url`, failing on anything but a 2xx status.
+///
+/// https uses the same trusted CAs as the connection to asterisk.
+pub fn post_json(
+    config: &Config,
+    url: &WebhookUrl,
+    body: &impl Serialize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let body = serde_json::to_string(body)?;
+    let addr = std::net::ToSocketAddrs::to_socket_addrs(&(url.host.as_str(), url.port))?
+        .next()
+        .ok_or_else(|| format!("{} does not resolve to an address", url.host))?;
+    let tcp = TcpStream::connect_timeout(&addr, WEBHOOK_TIMEOUT)?;
+    tcp.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+    tcp.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+    let mut stream = if url.tls {
+        let conn = ClientConnection::new(
+            Arc::new(config.tls_client_config()?),
+            url.host.clone().try_into()?,
+        )?;
+        Stream::Tls(Box::new(StreamOwned::new(conn, tcp)))
+    } else {
+        Stream::Plain(tcp)
+    };
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: ta-asterisk-alarm\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        url.path,
+        url.authority(),
+        body.len()
+    );
+    trace!("Sending to the webhook at {url}: {body}");
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+    // only the status line is of interest
+    let mut response = Vec::new();
+    let mut buf = [0_u8; 512];
+    while !response.contains(&b'\n') {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        };
+        response.extend_from_slice(&buf[..n]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    debug!("The webhook at {url} answered {status_line}");
+    match status_line.split(' ').nth(1) {
+        Some(x) if x.starts_with('2') && x.len() == 3 => Ok(()),
+        _ => Err(format!("the webhook answered {status_line:?}").into()),
+    }
+}
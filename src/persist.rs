@@ -0,0 +1,158 @@
+//! Keeping the alarm state in a file across restarts
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{alarm::AlarmState, maintenance::MaintenanceState};
+
+/// What to do with alarms that were active when the service stopped
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnRestart {
+    /// continue where it stopped: calls sent, acknowledgement and escalation are kept
+    #[default]
+    Resume,
+    /// forget them; the next alarm packet from the CMI raises them again and calls everyone
+    Reset,
+    /// restore them acknowledged, so there are no more calls until they clear
+    Acknowledge,
+}
+impl core::fmt::Display for OnRestart {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Resume => write!(f, "resume"),
+            Self::Reset => write!(f, "reset"),
+            Self::Acknowledge => write!(f, "acknowledge"),
+        }
+    }
+}
+
+/// Where and how to keep the alarm state
+#[derive(Debug, PartialEq, Eq)]
+pub struct StateConfig {
+    pub file: PathBuf,
+    pub on_restart: OnRestart,
+    /// reset active alarms if the state is older than this
+    pub max_age: Option<Duration>,
+}
+impl From<StateConfigData> for StateConfig {
+    fn from(value: StateConfigData) -> Self {
+        Self {
+            file: value.file.into(),
+            on_restart: value.on_restart,
+            // too large values are reported by the validation
+            max_age: value
+                .max_age_minutes
+                .map(|x| Duration::from_secs(x.saturating_mul(60))),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StateConfigData {
+    /// written after every change, e.g. `/var/lib/ta-asterisk-alarm/state.json`
+    pub file: String,
+    /// resume, reset or acknowledge. Default: resume
+    #[serde(default)]
+    pub on_restart: OnRestart,
+    /// active alarms in a state saved longer ago than this are reset.
+    /// Default: always apply `on_restart`
+    pub max_age_minutes: Option<u64>,
+}
+
+/// The content of the state file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedState {
+    /// unix time
+    pub saved_at: u64,
+    pub alarms: Vec<AlarmState>,
+    #[serde(default)]
+    pub maintenance: MaintenanceState,
+}
+
+/// Write the state to a temporary file next to `path`, then move it over `path`, so a crash
+/// never leaves a partially written file behind
+pub fn save(path: &Path, state: &SavedState) -> Result<(), Box<dyn std::error::Error>> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec_pretty(state)?)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Writes snapshots of the state one at a time, skipping those older than the one on disk
+#[derive(Debug, Default)]
+pub struct Writer {
+    /// number of the latest snapshot taken
+    taken: AtomicU64,
+    /// number of the latest snapshot written; locked while writing
+    written: Mutex<u64>,
+}
+impl Writer {
+    /// Number the next snapshot. Take the snapshots in order, e.g. under a lock.
+    pub fn next_number(&self) -> u64 {
+        self.taken.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Write the snapshot `number` to `path`, unless a newer one was written already.
+    /// This blocks while writing.
+    pub fn write(&self, path: &Path, state: &SavedState, number: u64) {
+        let mut written = self.written.lock().expect("state writer mutex poisoned");
+        if *written >= number {
+            return;
+        };
+        if let Err(e) = save(path, state) {
+            warn!("Unable to save the alarm state to {}: {e}", path.display());
+        };
+        *written = number;
+    }
+}
+
+/// Read the saved state. `Ok(None)` if there is no state file yet.
+pub fn load(path: &Path) -> Result<Option<SavedState>, Box<dyn std::error::Error>> {
+    match std::fs::read(path) {
+        Ok(x) => Ok(Some(serde_json::from_slice(&x)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved_at(saved_at: u64) -> SavedState {
+        SavedState {
+            saved_at,
+            alarms: Vec::new(),
+            maintenance: MaintenanceState::default(),
+        }
+    }
+
+    #[test]
+    fn older_snapshots_are_not_written_over_newer_ones() {
+        let dir = tempfile::tempdir().expect("temporary directory");
+        let path = dir.path().join("state.json");
+        let writer = Writer::default();
+        let (first, second) = (writer.next_number(), writer.next_number());
+
+        writer.write(&path, &saved_at(2), second);
+        writer.write(&path, &saved_at(1), first);
+        let saved = load(&path).expect("readable state").expect("saved state");
+        assert_eq!(saved.saved_at, 2);
+        assert!(!dir.path().join("state.json.tmp").exists());
+    }
+}
@@ -0,0 +1,117 @@
+//! Replacing the config at runtime, on SIGHUP or via the admin API
+
+use std::sync::Arc;
+
+use async_signal::Signals;
+use smol::stream::StreamExt;
+use tracing::{error, info, warn};
+
+use crate::{
+    alarm::{Alarms, EventKind, ALL_ALARMS},
+    config::{Config, SharedConfig},
+    metrics::Metrics,
+};
+
+/// Re-read and validate the config file, then swap it in.
+///
+/// If the new config is invalid, the old one stays in use.
+/// Listen addresses and logging outputs are only changed by a restart.
+pub fn reload(
+    shared: &SharedConfig,
+    metrics: &Metrics,
+    alarms: &Alarms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let new = match Config::create(&shared.path) {
+        Ok(x) => Arc::new(x),
+        Err(e) => {
+            metrics.config_reload(false);
+            error!("Unable to reload the config, keeping the old one: {e}");
+            alarms.record(
+                ALL_ALARMS,
+                EventKind::ConfigReloadFailed {
+                    error: e.to_string(),
+                },
+            );
+            return Err(e);
+        }
+    };
+    let (old, changes) = alarms.swap_config(shared, new.clone());
+    metrics.config_reload(true);
+
+    for (section, changed) in [
+        (
+            "cmi.listen_addr and cmi.port",
+            old.cmi.listen != new.cmi.listen,
+        ),
+        (
+            "http",
+            old.http.as_ref().map(|x| x.listen) != new.http.as_ref().map(|x| x.listen),
+        ),
+        (
+            "admin",
+            old.admin.as_ref().map(|x| x.listen) != new.admin.as_ref().map(|x| x.listen),
+        ),
+        ("logging", old.logging != new.logging),
+        (
+            "state.file",
+            old.state.as_ref().map(|x| &x.file) != new.state.as_ref().map(|x| &x.file),
+        ),
+        (
+            "journal.file",
+            old.journal.as_ref().map(|x| &x.file) != new.journal.as_ref().map(|x| &x.file),
+        ),
+    ] {
+        if changed {
+            warn!("The config changed in {section}, this only takes effect after a restart.");
+        };
+    }
+    for name in &changes.cleared {
+        metrics.alarm_cleared(name);
+    }
+    for name in &changes.added {
+        metrics.register_alarm(name);
+    }
+    for name in &changes.removed {
+        metrics.unregister_alarm(name);
+    }
+    info!(
+        added = ?changes.added,
+        changed = ?changes.changed,
+        removed = ?changes.removed,
+        "Reloaded the config from {}.",
+        shared.path.display()
+    );
+    alarms.record(
+        ALL_ALARMS,
+        EventKind::ConfigReloaded {
+            added: changes.added,
+            changed: changes.changed,
+            removed: changes.removed,
+        },
+    );
+    Ok(())
+}
+
+/// Reload the config whenever SIGHUP is received on `signals`.
+///
+/// `signals` is registered before the rest of the startup, so an early SIGHUP is handled once
+/// this runs instead of terminating the process.
+pub async fn reload_on_sighup(
+    mut signals: Signals,
+    shared: Arc<SharedConfig>,
+    metrics: Arc<Metrics>,
+    alarms: Arc<Alarms>,
+) {
+    while let Some(signal) = signals.next().await {
+        if let Err(e) = signal {
+            warn!("Error while receiving SIGHUP: {e}");
+            continue;
+        };
+        info!("Got SIGHUP, reloading the config.");
+        let (shared, metrics, alarms) = (shared.clone(), metrics.clone(), alarms.clone());
+        // reading the config and the pem files blocks; errors are already logged
+        let _ =
+            smol::unblock(move || reload(&shared, &metrics, &alarms).map_err(|e| e.to_string()))
+                .await;
+    }
+}
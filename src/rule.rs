@@ -0,0 +1,623 @@
+//! Derived alarm conditions combining several signals, e.g. `door and armed` or
+//! `pump and flow < 2.5 for 2m`
+//!
+//! ```text
+//! expr       := and ("or" and)*
+//! and        := unary ("and" unary)*
+//! unary      := "not" unary | primary
+//! primary    := ("(" expr ")" | comparison) ["for" duration]
+//! comparison := operand [("<" | "<=" | ">" | ">=" | "==" | "!=") operand]
+//! operand    := signal | number
+//! duration   := number ("s" | "m" | "h")
+//! ```
+//!
+//! A signal alone is true if its value is not 0, e.g. a digital value that is ON.
+//! A signal not received yet is unknown; so is everything depending on it,
+//! unless the result is decided anyway, e.g. `unknown or true`.
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use serde::Deserialize;
+
+use crate::{
+    alarm::Alarms, config::SharedConfig, engine, metrics::Metrics, notify::OnClearConfigData,
+    schedule::Priority,
+};
+
+/// Evaluate rules with timers this often, so they fire without waiting for a packet
+const TIMER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A value compared in a rule
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Signal(String),
+    Number(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+/// A parsed rule condition
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// true if the value is not 0
+    Signal(String),
+    Compare(Operand, CompareOp, Operand),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    /// true once `expr` was true for `duration` without interruption
+    For {
+        expr: Box<Expr>,
+        duration: Duration,
+        /// numbers the timers of a rule
+        timer: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Duration(Duration),
+    Op(CompareOp),
+    Open,
+    Close,
+}
+
+/// Split a condition into tokens, each with its position
+fn tokenize(value: &str) -> Result<Vec<(usize, Token)>, String> {
+    let chars = value.char_indices().collect::<Vec<_>>();
+    let mut res = Vec::new();
+    let mut i = 0;
+    while let Some(&(pos, c)) = chars.get(i) {
+        let next = chars.get(i + 1).map(|x| x.1);
+        let (len, token) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('(', _) => (1, Token::Open),
+            (')', _) => (1, Token::Close),
+            ('<', Some('=')) => (2, Token::Op(CompareOp::Le)),
+            ('<', _) => (1, Token::Op(CompareOp::Lt)),
+            ('>', Some('=')) => (2, Token::Op(CompareOp::Ge)),
+            ('>', _) => (1, Token::Op(CompareOp::Gt)),
+            ('=', Some('=')) => (2, Token::Op(CompareOp::Eq)),
+            ('!', Some('=')) => (2, Token::Op(CompareOp::Ne)),
+            (c, next)
+                if c.is_ascii_digit() || (c == '-' && next.is_some_and(|x| x.is_ascii_digit())) =>
+            {
+                let len = chars[i + 1..]
+                    .iter()
+                    .take_while(|x| x.1.is_ascii_alphanumeric() || x.1 == '.')
+                    .count()
+                    + 1;
+                let text = chars[i..i + len].iter().map(|x| x.1).collect::<String>();
+                let split = text
+                    .find(|c: char| c.is_ascii_alphabetic())
+                    .unwrap_or(text.len());
+                let number = text[..split]
+                    .parse::<f64>()
+                    .map_err(|_| format!("{text:?} at {pos} is not a number"))?;
+                let token = match &text[split..] {
+                    "" => Token::Number(number),
+                    unit => {
+                        let factor = match unit {
+                            "s" => 1.0,
+                            "m" | "min" => 60.0,
+                            "h" => 3600.0,
+                            _ => {
+                                return Err(format!(
+                                    "{text:?} at {pos} has an unknown unit, use s, m or h, e.g. 90s"
+                                ))
+                            }
+                        };
+                        Token::Duration(
+                            Duration::try_from_secs_f64(number * factor)
+                                .map_err(|_| format!("{text:?} at {pos} is not a duration"))?,
+                        )
+                    }
+                };
+                (len, token)
+            }
+            (c, _) if c.is_alphabetic() || c == '_' => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|x| x.1.is_alphanumeric() || x.1 == '_' || x.1 == '-')
+                    .count();
+                (
+                    len,
+                    Token::Ident(chars[i..i + len].iter().map(|x| x.1).collect()),
+                )
+            }
+            (c, _) => return Err(format!("unexpected {c:?} at {pos}")),
+        };
+        res.push((pos, token));
+        i += len;
+    }
+    Ok(res)
+}
+
+/// Recursive descent over the tokens of a condition
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    /// length of the condition, reported as position of its end
+    len: usize,
+    timers: usize,
+}
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|x| &x.1)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.next).map_or(self.len, |x| x.0)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Ident(x)) if x.eq_ignore_ascii_case(keyword));
+        if found {
+            self.next += 1;
+        };
+        found
+    }
+
+    fn expected(&self, what: &str) -> String {
+        match self.peek() {
+            Some(_) => format!("expected {what} at {}", self.position()),
+            None => format!("expected {what} at the end"),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut res = vec![self.and()?];
+        while self.keyword("or") {
+            res.push(self.and()?);
+        }
+        Ok(if res.len() == 1 {
+            res.remove(0)
+        } else {
+            Expr::Or(res)
+        })
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut res = vec![self.unary()?];
+        while self.keyword("and") {
+            res.push(self.unary()?);
+        }
+        Ok(if res.len() == 1 {
+            res.remove(0)
+        } else {
+            Expr::And(res)
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        };
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let expr = if self.peek() == Some(&Token::Open) {
+            self.next += 1;
+            let expr = self.expr()?;
+            if self.peek() != Some(&Token::Close) {
+                return Err(self.expected("\")\""));
+            };
+            self.next += 1;
+            expr
+        } else {
+            self.comparison()?
+        };
+        if !self.keyword("for") {
+            return Ok(expr);
+        };
+        let Some(Token::Duration(duration)) = self.peek().cloned() else {
+            return Err(self.expected("a duration, e.g. 90s, 2m or 1h,"));
+        };
+        self.next += 1;
+        self.timers += 1;
+        Ok(Expr::For {
+            expr: Box::new(expr),
+            duration,
+            timer: self.timers - 1,
+        })
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let res = match self.peek() {
+            Some(Token::Number(x)) => Operand::Number(*x),
+            Some(Token::Ident(x))
+                if !["and", "or", "not", "for"]
+                    .iter()
+                    .any(|k| x.eq_ignore_ascii_case(k)) =>
+            {
+                Operand::Signal(x.clone())
+            }
+            _ => return Err(self.expected("a signal or a number")),
+        };
+        self.next += 1;
+        Ok(res)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.operand()?;
+        let Some(Token::Op(op)) = self.peek().cloned() else {
+            return match left {
+                Operand::Signal(x) => Ok(Expr::Signal(x)),
+                Operand::Number(_) => Err(self.expected("a comparison after the number")),
+            };
+        };
+        self.next += 1;
+        Ok(Expr::Compare(left, op, self.operand()?))
+    }
+}
+
+/// Parse a rule condition, e.g. `pump and flow < 2.5 for 2m`
+pub fn parse(value: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(value)?,
+        next: 0,
+        len: value.len(),
+        timers: 0,
+    };
+    if parser.tokens.is_empty() {
+        return Err("the condition is empty".to_owned());
+    };
+    let res = parser.expr()?;
+    if parser.peek().is_some() {
+        return Err(parser.expected("\"and\", \"or\" or the end"));
+    };
+    Ok(res)
+}
+
+/// The last value of every signal and the running timers of the rules
+#[derive(Debug, Default)]
+pub struct RuleState {
+    values: BTreeMap<String, f64>,
+    /// since when the condition of a timer holds, by rule and timer
+    /// in milliseconds since the unix epoch, see [`crate::clock`]
+    timers: BTreeMap<(String, usize), u64>,
+    /// the last known result of every rule
+    results: BTreeMap<String, bool>,
+}
+impl RuleState {
+    pub fn set(&mut self, signal: &str, value: f64) {
+        self.values.insert(signal.to_owned(), value);
+    }
+
+    /// Forget the timers and results of rules that changed or were removed
+    pub fn forget(&mut self, rules: &[String]) {
+        self.timers.retain(|(rule, _), _| !rules.contains(rule));
+        self.results.retain(|rule, _| !rules.contains(rule));
+    }
+
+    fn value(&self, operand: &Operand) -> Option<f64> {
+        match operand {
+            Operand::Signal(x) => self.values.get(x).copied(),
+            Operand::Number(x) => Some(*x),
+        }
+    }
+
+    /// Record the result of `rule`; true if it changed
+    pub fn changed(&mut self, rule: &str, result: bool) -> bool {
+        self.results.insert(rule.to_owned(), result) != Some(result)
+    }
+}
+
+impl Expr {
+    /// The names of the signals used
+    pub fn signals(&self) -> Vec<&str> {
+        match self {
+            Self::Signal(x) => vec![x.as_str()],
+            Self::Compare(left, _, right) => [left, right]
+                .into_iter()
+                .filter_map(|x| match x {
+                    Operand::Signal(x) => Some(x.as_str()),
+                    Operand::Number(_) => None,
+                })
+                .collect(),
+            Self::Not(x) | Self::For { expr: x, .. } => x.signals(),
+            Self::And(x) | Self::Or(x) => x.iter().flat_map(Self::signals).collect(),
+        }
+    }
+
+    pub fn has_timer(&self) -> bool {
+        match self {
+            Self::Signal(_) | Self::Compare(..) => false,
+            Self::For { .. } => true,
+            Self::Not(x) => x.has_timer(),
+            Self::And(x) | Self::Or(x) => x.iter().any(Self::has_timer),
+        }
+    }
+
+    /// Evaluate the condition of `rule` at `now` (milliseconds since the unix epoch),
+    /// `None` if unknown. Updates its timers.
+    pub fn eval(&self, rule: &str, state: &mut RuleState, now: u64) -> Option<bool> {
+        match self {
+            Self::Signal(x) => state.values.get(x).map(|x| *x != 0.0),
+            Self::Compare(left, op, right) => {
+                let (left, right) = (state.value(left)?, state.value(right)?);
+                Some(match op {
+                    CompareOp::Lt => left < right,
+                    CompareOp::Le => left <= right,
+                    CompareOp::Gt => left > right,
+                    CompareOp::Ge => left >= right,
+                    CompareOp::Eq => left == right,
+                    CompareOp::Ne => left != right,
+                })
+            }
+            Self::Not(x) => x.eval(rule, state, now).map(|x| !x),
+            // evaluate all operands, so every timer is updated
+            Self::And(x) => {
+                let results = x
+                    .iter()
+                    .map(|x| x.eval(rule, state, now))
+                    .collect::<Vec<_>>();
+                if results.contains(&Some(false)) {
+                    Some(false)
+                } else if results.contains(&None) {
+                    None
+                } else {
+                    Some(true)
+                }
+            }
+            Self::Or(x) => {
+                let results = x
+                    .iter()
+                    .map(|x| x.eval(rule, state, now))
+                    .collect::<Vec<_>>();
+                if results.contains(&Some(true)) {
+                    Some(true)
+                } else if results.contains(&None) {
+                    None
+                } else {
+                    Some(false)
+                }
+            }
+            Self::For {
+                expr,
+                duration,
+                timer,
+            } => {
+                let key = (rule.to_owned(), *timer);
+                match expr.eval(rule, state, now) {
+                    Some(true) => {
+                        let since = *state.timers.entry(key).or_insert(now);
+                        Some(Duration::from_millis(now.saturating_sub(since)) >= *duration)
+                    }
+                    x => {
+                        state.timers.remove(&key);
+                        x
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A derived alarm condition
+#[derive(Debug, Clone)]
+pub struct RuleConfig {
+    /// the condition as configured
+    pub when: String,
+    pub expr: Expr,
+}
+// rules are compared by their text, the parsed condition contains floats
+impl PartialEq for RuleConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.when == other.when
+    }
+}
+impl Eq for RuleConfig {}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RuleConfigData {
+    /// name of the alarm raised while the condition holds
+    pub name: String,
+    /// the condition, e.g. `door and armed` or `pump and flow < 2.5 for 2m`
+    pub when: String,
+    /// Default: normal
+    #[serde(default)]
+    pub priority: Priority,
+    /// Default: no notification when the alarm clears
+    pub on_clear: Option<OnClearConfigData>,
+}
+/// Evaluate the rules with timers every second, forever. A rule is only processed if its
+/// result changed, packets repeat the alarm state.
+pub async fn evaluate_periodically(
+    shared: Arc<SharedConfig>,
+    metrics: Arc<Metrics>,
+    alarms: Arc<Alarms>,
+) {
+    loop {
+        smol::Timer::after(TIMER_INTERVAL).await;
+        engine::tick(&shared.get(), &metrics, &alarms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(x: &str) -> Expr {
+        Expr::Signal(x.to_owned())
+    }
+
+    fn eval(condition: &str, values: &[(&str, f64)]) -> Option<bool> {
+        let mut state = RuleState::default();
+        for (name, value) in values {
+            state.set(name, *value);
+        }
+        parse(condition)
+            .expect("valid condition")
+            .eval("rule", &mut state, 0)
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            parse("a or b and not c"),
+            Ok(Expr::Or(vec![
+                signal("a"),
+                Expr::And(vec![signal("b"), Expr::Not(Box::new(signal("c")))]),
+            ]))
+        );
+        assert_eq!(
+            parse("(a OR b) and c"),
+            Ok(Expr::And(vec![
+                Expr::Or(vec![signal("a"), signal("b")]),
+                signal("c"),
+            ]))
+        );
+        assert_eq!(parse("((a))"), Ok(signal("a")));
+        assert_eq!(
+            eval("a or b and c", &[("a", 1.0), ("b", 0.0), ("c", 0.0)]),
+            Some(true)
+        );
+        assert_eq!(
+            eval("(a or b) and c", &[("a", 1.0), ("b", 0.0), ("c", 0.0)]),
+            Some(false)
+        );
+        assert_eq!(eval("not not a", &[("a", 2.0)]), Some(true));
+    }
+
+    #[test]
+    fn comparisons_numbers_and_units() {
+        assert_eq!(
+            parse("flow <= -2.5"),
+            Ok(Expr::Compare(
+                Operand::Signal("flow".to_owned()),
+                CompareOp::Le,
+                Operand::Number(-2.5)
+            ))
+        );
+        assert_eq!(
+            parse("-1 != temp-1"),
+            Ok(Expr::Compare(
+                Operand::Number(-1.0),
+                CompareOp::Ne,
+                Operand::Signal("temp-1".to_owned())
+            ))
+        );
+        for (condition, secs) in [
+            ("a for 90s", 90.0),
+            ("a for 2m", 120.0),
+            ("a for 2min", 120.0),
+            ("a for 1.5h", 5400.0),
+            ("a for 0.5s", 0.5),
+        ] {
+            let Ok(Expr::For { duration, .. }) = parse(condition) else {
+                panic!("{condition} has no timer");
+            };
+            assert_eq!(duration, Duration::from_secs_f64(secs), "{condition}");
+        }
+        assert_eq!(eval("t < -3", &[("t", -3.5)]), Some(true));
+        assert_eq!(eval("t >= -3", &[("t", -3.5)]), Some(false));
+        assert_eq!(eval("t == 2", &[("t", 2.0)]), Some(true));
+        assert_eq!(eval("a > b", &[("a", 2.0), ("b", 1.0)]), Some(true));
+    }
+
+    #[test]
+    fn errors_report_the_position() {
+        for (condition, error) in [
+            ("", "the condition is empty"),
+            ("a and", "expected a signal or a number at the end"),
+            ("a and or b", "expected a signal or a number at 6"),
+            ("(a or b", "expected \")\" at the end"),
+            ("a or b)", "expected \"and\", \"or\" or the end at 6"),
+            ("a b", "expected \"and\", \"or\" or the end at 2"),
+            ("2 and a", "expected a comparison after the number at 2"),
+            (
+                "a for",
+                "expected a duration, e.g. 90s, 2m or 1h, at the end",
+            ),
+            ("a for 2", "expected a duration, e.g. 90s, 2m or 1h, at 6"),
+            ("a for 2d", "\"2d\" at 6 has an unknown unit"),
+            ("a < 1.2.3", "\"1.2.3\" at 4 is not a number"),
+            ("a = 1", "unexpected '=' at 2"),
+            ("a < -", "unexpected '-' at 4"),
+        ] {
+            assert_eq!(
+                parse(condition).map_err(|e| e.starts_with(error)),
+                Err(true),
+                "{condition}: {:?}",
+                parse(condition)
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_signals_propagate_unless_decided() {
+        assert_eq!(eval("unknown", &[]), None);
+        assert_eq!(eval("not unknown", &[]), None);
+        assert_eq!(eval("unknown < 2", &[]), None);
+        assert_eq!(eval("unknown or on", &[("on", 1.0)]), Some(true));
+        assert_eq!(eval("unknown or off", &[("off", 0.0)]), None);
+        assert_eq!(eval("unknown and off", &[("off", 0.0)]), Some(false));
+        assert_eq!(eval("unknown and on", &[("on", 1.0)]), None);
+        assert_eq!(eval("unknown for 1s or on", &[("on", 1.0)]), Some(true));
+    }
+
+    #[test]
+    fn timer_needs_the_condition_without_interruption() {
+        let expr = parse("pump and flow < 2.5 for 2m").expect("valid condition");
+        let mut state = RuleState::default();
+        state.set("pump", 1.0);
+        state.set("flow", 1.0);
+        assert_eq!(expr.eval("rule", &mut state, 0), Some(false));
+        assert_eq!(expr.eval("rule", &mut state, 119_999), Some(false));
+        // interrupted: the timer starts over
+        state.set("flow", 3.0);
+        assert_eq!(expr.eval("rule", &mut state, 100_000), Some(false));
+        state.set("flow", 1.0);
+        assert_eq!(expr.eval("rule", &mut state, 110_000), Some(false));
+        assert_eq!(expr.eval("rule", &mut state, 229_999), Some(false));
+        assert_eq!(expr.eval("rule", &mut state, 230_000), Some(true));
+        // becoming unknown also interrupts it
+        let expr = parse("a for 1s").expect("valid condition");
+        let mut state = RuleState::default();
+        state.set("a", 1.0);
+        assert_eq!(expr.eval("rule", &mut state, 0), Some(false));
+        state.values.clear();
+        assert_eq!(expr.eval("rule", &mut state, 500), None);
+        state.set("a", 1.0);
+        assert_eq!(expr.eval("rule", &mut state, 1_000), Some(false));
+        assert_eq!(expr.eval("rule", &mut state, 2_000), Some(true));
+    }
+
+    #[test]
+    fn timers_are_kept_per_rule_and_forgotten() {
+        let expr = parse("a for 1s or b for 1s").expect("valid condition");
+        let mut state = RuleState::default();
+        state.set("a", 1.0);
+        state.set("b", 1.0);
+        assert_eq!(expr.eval("one", &mut state, 0), Some(false));
+        assert_eq!(expr.eval("two", &mut state, 500), Some(false));
+        assert_eq!(expr.eval("one", &mut state, 1_000), Some(true));
+        assert_eq!(expr.eval("two", &mut state, 1_000), Some(false));
+        state.forget(&["one".to_owned()]);
+        assert_eq!(expr.eval("one", &mut state, 1_500), Some(false));
+        assert_eq!(expr.eval("two", &mut state, 1_500), Some(true));
+    }
+
+    #[test]
+    fn signals_and_timers() {
+        let expr = parse("a and (b > 1 or not c) and 2 < d for 5s").expect("valid condition");
+        assert_eq!(expr.signals(), ["a", "b", "c", "d"]);
+        assert!(expr.has_timer());
+        assert!(!parse("a or b").expect("valid condition").has_timer());
+    }
+}
@@ -0,0 +1,850 @@
+//! Time-based routing of calls: weekly rules, holidays and escalation
+
+use std::time::Duration;
+
+use serde::Deserialize;
+use time::{Date, Month, OffsetDateTime, Time, Weekday};
+use time_tz::{OffsetDateTimeExt, Tz};
+
+use crate::config::Config;
+
+/// A day a rule applies on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Day {
+    Weekday(Weekday),
+    /// any holiday, no matter the weekday
+    Holiday,
+}
+
+/// `mon`..`sun` (or the full english name) and `holiday`
+pub fn parse_day(value: &str) -> Result<Day, String> {
+    let day = match value.to_ascii_lowercase().as_str() {
+        "mon" | "monday" => Day::Weekday(Weekday::Monday),
+        "tue" | "tuesday" => Day::Weekday(Weekday::Tuesday),
+        "wed" | "wednesday" => Day::Weekday(Weekday::Wednesday),
+        "thu" | "thursday" => Day::Weekday(Weekday::Thursday),
+        "fri" | "friday" => Day::Weekday(Weekday::Friday),
+        "sat" | "saturday" => Day::Weekday(Weekday::Saturday),
+        "sun" | "sunday" => Day::Weekday(Weekday::Sunday),
+        "holiday" => Day::Holiday,
+        _ => {
+            return Err(format!(
+                "{value:?} is not a day, use mon, tue, wed, thu, fri, sat, sun or holiday"
+            ))
+        }
+    };
+    Ok(day)
+}
+
+/// `HH:MM` in 24h format
+pub fn parse_time(value: &str) -> Result<Time, String> {
+    let err = || format!("{value:?} is not a time of day, e.g. 07:30 or 22:00");
+    let (hour, minute) = value.split_once(':').ok_or_else(err)?;
+    let hour = hour.parse::<u8>().map_err(|_| err())?;
+    let minute = minute.parse::<u8>().map_err(|_| err())?;
+    Time::from_hms(hour, minute, 0).map_err(|_| err())
+}
+
+/// An IANA time zone name, e.g. `Europe/Berlin`
+pub fn parse_timezone(value: &str) -> Result<&'static Tz, String> {
+    time_tz::timezones::get_by_name(value)
+        .ok_or_else(|| format!("{value:?} is not a time zone, e.g. Europe/Berlin or UTC"))
+}
+
+/// One or more consecutive days without calls, or with different rules
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Holiday {
+    pub start: Date,
+    /// at least 1
+    pub days: u16,
+    /// repeats every year on the same date, starting in the year of `start`
+    pub yearly: bool,
+}
+impl Holiday {
+    fn contains(&self, date: Date) -> bool {
+        (0..self.days).any(|offset| {
+            let Some(day) = date.checked_sub(time::Duration::days(offset.into())) else {
+                return false;
+            };
+            if self.yearly {
+                day.year() >= self.start.year()
+                    && day.month() == self.start.month()
+                    && day.day() == self.start.day()
+            } else {
+                day == self.start
+            }
+        })
+    }
+}
+
+fn date(year: i32, month: u8, day: u8) -> Option<Date> {
+    Date::from_calendar_date(year, Month::try_from(month).ok()?, day).ok()
+}
+
+/// `YYYY-MM-DD` for a single day, `MM-DD` for every year
+pub fn parse_holiday(value: &str) -> Result<Holiday, String> {
+    let err = || format!("{value:?} is not a date, use YYYY-MM-DD or MM-DD for every year");
+    let parts = value
+        .split('-')
+        .map(|x| x.parse::<i32>().map_err(|_| err()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (year, month, day, yearly) = match parts.as_slice() {
+        [year, month, day] => (*year, *month, *day, false),
+        // a leap year, so that 02-29 is valid
+        [month, day] => (4, *month, *day, true),
+        _ => return Err(err()),
+    };
+    let start = date(
+        year,
+        u8::try_from(month).map_err(|_| err())?,
+        u8::try_from(day).map_err(|_| err())?,
+    )
+    .ok_or_else(err)?;
+    Ok(Holiday {
+        start,
+        days: 1,
+        yearly,
+    })
+}
+
+/// The date of a `DTSTART`/`DTEND` value and whether it had a time after midnight
+fn parse_ical_date(value: &str) -> Option<(Date, bool)> {
+    let year = value.get(0..4)?.parse().ok()?;
+    let month = value.get(4..6)?.parse().ok()?;
+    let day = value.get(6..8)?.parse().ok()?;
+    let after_midnight = value
+        .get(9..15)
+        .is_some_and(|x| x.bytes().any(|b| b != b'0'));
+    Some((date(year, month, day)?, after_midnight))
+}
+
+/// The number of whole days of a `DURATION` like `P1D`, `P2W` or `PT8H`
+fn parse_ical_duration_days(value: &str) -> Option<i64> {
+    let value = value.strip_prefix('P')?;
+    if let Some(weeks) = value.strip_suffix('W') {
+        return Some(weeks.parse::<i64>().ok()? * 7);
+    };
+    // durations of less than a day, e.g. `PT8H`, still cover the day they start on
+    match value.split('T').next()? {
+        "" => Some(0),
+        days => days.strip_suffix('D')?.parse().ok(),
+    }
+}
+
+/// The fields of a `VEVENT` used for holidays
+#[derive(Debug, Default)]
+struct IcalEvent {
+    start: Option<Date>,
+    /// exclusive
+    end: Option<Date>,
+    duration_days: Option<i64>,
+    yearly: bool,
+}
+
+/// Read every event of an iCalendar file as a holiday.
+///
+/// Only the dates are used. Recurring events need `RRULE:FREQ=YEARLY`.
+pub fn parse_ical(text: &str) -> Result<Vec<Holiday>, String> {
+    // long lines are folded by starting the next line with a space or tab
+    let unfolded = text
+        .replace("\r\n", "\n")
+        .replace("\n ", "")
+        .replace("\n\t", "");
+    let mut res = Vec::new();
+    let mut event: Option<IcalEvent> = None;
+    let mut summary = String::new();
+    for (i, line) in unfolded.lines().enumerate() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let name = name.split(';').next().unwrap_or(name).to_ascii_uppercase();
+        match (name.as_str(), event.as_mut()) {
+            ("BEGIN", None) if value == "VEVENT" => {
+                event = Some(IcalEvent::default());
+                summary.clear();
+            }
+            ("END", Some(x)) if value == "VEVENT" => {
+                let Some(start) = x.start else {
+                    return Err(format!("event {summary:?} has no DTSTART"));
+                };
+                let days = match (x.end, x.duration_days) {
+                    (Some(end), _) => (end - start).whole_days(),
+                    (None, Some(days)) => days,
+                    (None, None) => 1,
+                };
+                res.push(Holiday {
+                    start,
+                    days: u16::try_from(days.max(1)).unwrap_or(u16::MAX),
+                    yearly: x.yearly,
+                });
+                event = None;
+            }
+            ("SUMMARY", Some(_)) => value.clone_into(&mut summary),
+            ("DTSTART", Some(x)) => {
+                let (date, _) = parse_ical_date(value)
+                    .ok_or_else(|| format!("line {}: invalid DTSTART {value:?}", i + 1))?;
+                x.start = Some(date);
+            }
+            ("DTEND", Some(x)) => {
+                let (date, after_midnight) = parse_ical_date(value)
+                    .ok_or_else(|| format!("line {}: invalid DTEND {value:?}", i + 1))?;
+                // DTEND is exclusive; an event ending during a day includes that day
+                x.end = Some(if after_midnight {
+                    date.next_day().unwrap_or(date)
+                } else {
+                    date
+                });
+            }
+            ("DURATION", Some(x)) => {
+                x.duration_days = Some(
+                    parse_ical_duration_days(value)
+                        .ok_or_else(|| format!("line {}: invalid DURATION {value:?}", i + 1))?,
+                );
+            }
+            ("RRULE", Some(x)) => {
+                if value
+                    .split(';')
+                    .any(|x| x.eq_ignore_ascii_case("FREQ=YEARLY"))
+                {
+                    x.yearly = true;
+                } else {
+                    return Err(format!(
+                        "line {}: only RRULE:FREQ=YEARLY is supported, got {value:?}",
+                        i + 1
+                    ));
+                };
+            }
+            _ => (),
+        };
+    }
+    Ok(res)
+}
+
+/// Read and parse an iCalendar file
+pub fn read_ical(path: &str) -> Result<Vec<Holiday>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("unable to read {path}: {e}"))?;
+    parse_ical(&text).map_err(|e| format!("{path}: {e}"))
+}
+
+/// Priority of an alarm
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Priority {
+    /// `low`
+    Low,
+    /// `normal`, the default
+    #[default]
+    Normal,
+    /// `high`
+    High,
+    /// `critical`
+    Critical,
+}
+impl core::fmt::Display for Priority {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Low => write!(f, "low"),
+            Self::Normal => write!(f, "normal"),
+            Self::High => write!(f, "high"),
+            Self::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+/// How alarms of a priority are treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PriorityPolicy {
+    /// escalation steps apply after this percentage of their `after_minutes`
+    pub escalate_after_percent: u32,
+    /// a new call round cancels the running rounds of alarms with a lower priority
+    pub preempt: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct PriorityPolicyData {
+    /// e.g. 50 escalates twice as fast. Default: 100 for low and normal, 50 for high, 0 for
+    /// critical
+    pub escalate_after_percent: Option<u32>,
+    /// Default: false for low and normal, true for high and critical
+    pub preempt: Option<bool>,
+}
+
+/// The policy of every priority
+#[derive(Debug, PartialEq, Eq)]
+pub struct PrioritiesConfig {
+    pub low: PriorityPolicy,
+    pub normal: PriorityPolicy,
+    pub high: PriorityPolicy,
+    pub critical: PriorityPolicy,
+}
+impl PrioritiesConfig {
+    pub fn get(&self, priority: Priority) -> &PriorityPolicy {
+        match priority {
+            Priority::Low => &self.low,
+            Priority::Normal => &self.normal,
+            Priority::High => &self.high,
+            Priority::Critical => &self.critical,
+        }
+    }
+}
+impl From<PrioritiesConfigData> for PrioritiesConfig {
+    fn from(value: PrioritiesConfigData) -> Self {
+        let policy =
+            |data: Option<PriorityPolicyData>, escalate_after_percent, preempt| PriorityPolicy {
+                escalate_after_percent: data
+                    .and_then(|x| x.escalate_after_percent)
+                    .unwrap_or(escalate_after_percent),
+                preempt: data.and_then(|x| x.preempt).unwrap_or(preempt),
+            };
+        Self {
+            low: policy(value.low, 100, false),
+            normal: policy(value.normal, 100, false),
+            high: policy(value.high, 50, true),
+            critical: policy(value.critical, 0, true),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct PrioritiesConfigData {
+    pub low: Option<PriorityPolicyData>,
+    pub normal: Option<PriorityPolicyData>,
+    pub high: Option<PriorityPolicyData>,
+    pub critical: Option<PriorityPolicyData>,
+}
+
+/// Call more endpoints if the alarm is still active after some time
+#[derive(Debug, Clone)]
+pub struct EscalationStep {
+    pub after: Duration,
+    pub call_external_endpoints: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct EscalationStepData {
+    /// minutes since the alarm was raised
+    pub after_minutes: u64,
+    pub call_external_endpoints: Vec<String>,
+}
+
+/// Where to call during some hours of some days
+#[derive(Debug)]
+pub struct ScheduleRule {
+    pub name: String,
+    /// empty means every day
+    pub days: Vec<Day>,
+    pub from: Time,
+    /// if `to` is before `from`, the rule continues on the next day.
+    /// If they are equal, the rule applies all day.
+    pub to: Time,
+    /// empty means `asterisk.call_external_endpoints`
+    pub call_external_endpoints: Vec<String>,
+    /// sorted by `after`
+    pub escalate: Vec<EscalationStep>,
+    /// alarms with a lower priority are not called while this rule applies
+    pub min_priority: Priority,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScheduleRuleData {
+    /// used in logs
+    pub name: String,
+    /// any of mon, tue, wed, thu, fri, sat, sun and holiday.
+    /// On holidays, only rules listing `holiday` apply.
+    /// Default: every day, including holidays
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// `HH:MM`. Default: "00:00"
+    pub from: Option<String>,
+    /// `HH:MM`, exclusive. Default: "00:00", i.e. until the end of the day
+    pub to: Option<String>,
+    /// call these endpoints while this rule applies.
+    /// Default: `asterisk.call_external_endpoints`
+    #[serde(default)]
+    pub call_external_endpoints: Vec<String>,
+    /// also call these endpoints if the alarm is still active after some minutes
+    #[serde(default)]
+    pub escalate: Vec<EscalationStepData>,
+    /// do not call for alarms with a lower priority while this rule applies, e.g. `critical`
+    /// for quiet hours. They are called once another rule applies and the CMI still sends
+    /// the alarm.
+    /// Default: low, i.e. call for all alarms
+    #[serde(default = "lowest_priority")]
+    pub min_priority: Priority,
+}
+
+fn lowest_priority() -> Priority {
+    Priority::Low
+}
+
+/// Calendar-aware routing of calls
+#[derive(Debug)]
+pub struct ScheduleConfig {
+    pub timezone: &'static Tz,
+    pub holidays: Vec<Holiday>,
+    /// the first matching rule applies
+    pub rules: Vec<ScheduleRule>,
+}
+impl TryFrom<ScheduleConfigData> for ScheduleConfig {
+    type Error = String;
+    fn try_from(value: ScheduleConfigData) -> Result<Self, Self::Error> {
+        let mut holidays = value
+            .holidays
+            .iter()
+            .map(|x| parse_holiday(x))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(path) = &value.holidays_ical {
+            holidays.extend(read_ical(path)?);
+        };
+        let mut rules = Vec::new();
+        for rule in value.rules {
+            let mut escalate = rule
+                .escalate
+                .into_iter()
+                .map(|x| {
+                    let after = x
+                        .after_minutes
+                        .checked_mul(60)
+                        .ok_or_else(|| format!("{} minutes is too long", x.after_minutes))?;
+                    Ok(EscalationStep {
+                        after: Duration::from_secs(after),
+                        call_external_endpoints: x.call_external_endpoints,
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            escalate.sort_by_key(|x| x.after);
+            rules.push(ScheduleRule {
+                name: rule.name,
+                days: rule
+                    .days
+                    .iter()
+                    .map(|x| parse_day(x))
+                    .collect::<Result<_, _>>()?,
+                from: rule
+                    .from
+                    .as_deref()
+                    .map(parse_time)
+                    .transpose()?
+                    .unwrap_or(Time::MIDNIGHT),
+                to: rule
+                    .to
+                    .as_deref()
+                    .map(parse_time)
+                    .transpose()?
+                    .unwrap_or(Time::MIDNIGHT),
+                call_external_endpoints: rule.call_external_endpoints,
+                escalate,
+                min_priority: rule.min_priority,
+            });
+        }
+        Ok(Self {
+            timezone: parse_timezone(value.timezone.as_deref().unwrap_or("UTC"))?,
+            holidays,
+            rules,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScheduleConfigData {
+    /// IANA name of the time zone the rules and holidays are in.
+    /// Default: "UTC"
+    pub timezone: Option<String>,
+    /// `YYYY-MM-DD` for a single day, `MM-DD` for every year
+    #[serde(default)]
+    pub holidays: Vec<String>,
+    /// also read holidays from this iCalendar file. Every event is a holiday.
+    pub holidays_ical: Option<String>,
+    /// the first matching rule applies. If none matches, all endpoints in
+    /// `asterisk.call_external_endpoints` are called.
+    #[serde(default)]
+    pub rules: Vec<ScheduleRuleData>,
+}
+
+impl ScheduleConfig {
+    fn is_holiday(&self, date: Date) -> bool {
+        self.holidays.iter().any(|x| x.contains(date))
+    }
+
+    fn applies_on(&self, rule: &ScheduleRule, date: Date) -> bool {
+        if rule.days.is_empty() {
+            return true;
+        };
+        if self.is_holiday(date) {
+            rule.days.contains(&Day::Holiday)
+        } else {
+            rule.days.contains(&Day::Weekday(date.weekday()))
+        }
+    }
+
+    fn matches(&self, rule: &ScheduleRule, local: OffsetDateTime) -> bool {
+        let (date, time) = (local.date(), local.time());
+        if rule.from == rule.to {
+            self.applies_on(rule, date)
+        } else if rule.from < rule.to {
+            self.applies_on(rule, date) && rule.from <= time && time < rule.to
+        } else {
+            // continues past midnight; the part after midnight belongs to the previous day
+            (self.applies_on(rule, date) && rule.from <= time)
+                || (time < rule.to
+                    && date
+                        .previous_day()
+                        .is_some_and(|x| self.applies_on(rule, x)))
+        }
+    }
+
+    /// The first rule matching this point in time
+    pub fn rule_at(&self, at: OffsetDateTime) -> Option<&ScheduleRule> {
+        let local = at.to_timezone(self.timezone);
+        self.rules.iter().find(|x| self.matches(x, local))
+    }
+}
+
+/// Whom to call for an alarm, decided by the schedule
+#[derive(Debug)]
+pub struct Route<'a> {
+    /// the name of the schedule rule that applies, if any
+    pub rule: Option<&'a str>,
+    pub endpoints: Vec<&'a str>,
+    /// alarms with a lower priority are not called
+    pub min_priority: Priority,
+}
+
+/// Decide whom to call at `at` for an alarm with `priority` that has been active for
+/// `active_for`
+pub fn route(
+    config: &Config,
+    at: OffsetDateTime,
+    priority: Priority,
+    active_for: Duration,
+) -> Route<'_> {
+    let default = || {
+        config
+            .asterisk
+            .call_external_endpoints
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+    };
+    let Some(rule) = config.schedule.as_ref().and_then(|x| x.rule_at(at)) else {
+        return Route {
+            rule: None,
+            endpoints: default(),
+            min_priority: Priority::Low,
+        };
+    };
+    let mut endpoints = if rule.call_external_endpoints.is_empty() {
+        default()
+    } else {
+        rule.call_external_endpoints
+            .iter()
+            .map(String::as_str)
+            .collect()
+    };
+    let percent = config.priorities.get(priority).escalate_after_percent;
+    for step in rule.escalate.iter().filter(|x| {
+        x.after
+            .checked_mul(percent)
+            .is_some_and(|after| after / 100 <= active_for)
+    }) {
+        for endpoint in &step.call_external_endpoints {
+            if !endpoints.contains(&endpoint.as_str()) {
+                endpoints.push(endpoint);
+            };
+        }
+    }
+    Route {
+        rule: Some(&rule.name),
+        endpoints,
+        min_priority: rule.min_priority,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use time::format_description::well_known::Rfc3339;
+
+    use super::*;
+
+    fn at(value: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(value, &Rfc3339).expect("RFC 3339 time")
+    }
+
+    fn day(value: &str) -> Date {
+        let (date, _) = parse_ical_date(&value.replace('-', "")).expect("date");
+        date
+    }
+
+    fn rule(name: &str, days: &[&str], from: &str, to: &str) -> ScheduleRule {
+        ScheduleRule {
+            name: name.to_owned(),
+            days: days.iter().map(|x| parse_day(x).expect("day")).collect(),
+            from: parse_time(from).expect("time"),
+            to: parse_time(to).expect("time"),
+            call_external_endpoints: Vec::new(),
+            escalate: Vec::new(),
+            min_priority: Priority::Low,
+        }
+    }
+
+    fn schedule(timezone: &str, holidays: &[&str], rules: Vec<ScheduleRule>) -> ScheduleConfig {
+        ScheduleConfig {
+            timezone: parse_timezone(timezone).expect("time zone"),
+            holidays: holidays
+                .iter()
+                .map(|x| parse_holiday(x).expect("holiday"))
+                .collect(),
+            rules,
+        }
+    }
+
+    fn rule_name<'a>(schedule: &'a ScheduleConfig, value: &str) -> Option<&'a str> {
+        schedule.rule_at(at(value)).map(|x| x.name.as_str())
+    }
+
+    #[test]
+    fn overnight_rule_continues_on_the_next_day() {
+        // 2024-06-07 is a friday
+        let schedule = schedule("UTC", &[], vec![rule("night", &["fri"], "22:00", "06:00")]);
+        assert_eq!(rule_name(&schedule, "2024-06-07T21:59:59Z"), None);
+        assert_eq!(rule_name(&schedule, "2024-06-07T22:00:00Z"), Some("night"));
+        assert_eq!(rule_name(&schedule, "2024-06-08T05:59:59Z"), Some("night"));
+        assert_eq!(rule_name(&schedule, "2024-06-08T06:00:00Z"), None);
+        // thursday night is not friday night
+        assert_eq!(rule_name(&schedule, "2024-06-07T03:00:00Z"), None);
+        assert_eq!(rule_name(&schedule, "2024-06-08T22:00:00Z"), None);
+    }
+
+    #[test]
+    fn equal_from_and_to_is_all_day() {
+        let schedule = schedule("UTC", &[], vec![rule("sat", &["sat"], "00:00", "00:00")]);
+        assert_eq!(rule_name(&schedule, "2024-06-08T00:00:00Z"), Some("sat"));
+        assert_eq!(rule_name(&schedule, "2024-06-08T23:59:59Z"), Some("sat"));
+        assert_eq!(rule_name(&schedule, "2024-06-09T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let schedule = schedule(
+            "UTC",
+            &[],
+            vec![
+                rule("office", &["mon", "tue"], "08:00", "17:00"),
+                rule("always", &[], "00:00", "00:00"),
+            ],
+        );
+        assert_eq!(rule_name(&schedule, "2024-06-10T09:00:00Z"), Some("office"));
+        assert_eq!(rule_name(&schedule, "2024-06-10T17:00:00Z"), Some("always"));
+    }
+
+    #[test]
+    fn rules_are_in_local_time_across_dst_changes() {
+        let night = schedule(
+            "Europe/Berlin",
+            &[],
+            vec![rule("night", &["sat"], "22:00", "06:00")],
+        );
+        // 2024-03-30 is a saturday; 22:00 CET is 21:00 UTC
+        assert_eq!(rule_name(&night, "2024-03-30T20:59:59Z"), None);
+        assert_eq!(rule_name(&night, "2024-03-30T21:00:00Z"), Some("night"));
+        // the clocks jump from 02:00 CET to 03:00 CEST, so 06:00 local is 04:00 UTC
+        assert_eq!(rule_name(&night, "2024-03-31T03:59:59Z"), Some("night"));
+        assert_eq!(rule_name(&night, "2024-03-31T04:00:00Z"), None);
+        // 02:00 to 03:00 local does not exist on that day
+        let skipped = schedule(
+            "Europe/Berlin",
+            &[],
+            vec![rule("two", &["sun"], "02:00", "03:00")],
+        );
+        assert_eq!(rule_name(&skipped, "2024-03-31T00:59:59Z"), None);
+        assert_eq!(rule_name(&skipped, "2024-03-31T01:00:00Z"), None);
+        assert_eq!(rule_name(&skipped, "2024-04-07T00:00:00Z"), Some("two"));
+    }
+
+    #[test]
+    fn repeated_hour_matches_twice_when_dst_ends() {
+        // 2024-10-27 is a sunday; 02:00 to 03:00 local happens in CEST and again in CET
+        let schedule = schedule(
+            "Europe/Berlin",
+            &[],
+            vec![rule("two", &["sun"], "02:00", "03:00")],
+        );
+        assert_eq!(rule_name(&schedule, "2024-10-26T23:59:59Z"), None);
+        assert_eq!(rule_name(&schedule, "2024-10-27T00:00:00Z"), Some("two"));
+        assert_eq!(rule_name(&schedule, "2024-10-27T01:30:00Z"), Some("two"));
+        assert_eq!(rule_name(&schedule, "2024-10-27T02:00:00Z"), None);
+    }
+
+    #[test]
+    fn holidays_only_match_holiday_rules() {
+        // 2024-12-25 is a wednesday
+        let days = schedule(
+            "UTC",
+            &["12-25"],
+            vec![
+                rule(
+                    "weekday",
+                    &["mon", "tue", "wed", "thu", "fri"],
+                    "00:00",
+                    "00:00",
+                ),
+                rule("holiday", &["holiday"], "00:00", "00:00"),
+            ],
+        );
+        assert_eq!(rule_name(&days, "2024-12-24T12:00:00Z"), Some("weekday"));
+        assert_eq!(rule_name(&days, "2024-12-25T12:00:00Z"), Some("holiday"));
+        // the night after a holiday belongs to the holiday
+        let night = schedule(
+            "UTC",
+            &["12-25"],
+            vec![rule("holiday night", &["holiday"], "22:00", "06:00")],
+        );
+        assert_eq!(
+            rule_name(&night, "2024-12-26T05:00:00Z"),
+            Some("holiday night")
+        );
+        assert_eq!(rule_name(&night, "2024-12-27T05:00:00Z"), None);
+    }
+
+    #[test]
+    fn multi_day_holidays() {
+        let holiday = Holiday {
+            start: day("2024-12-30"),
+            days: 3,
+            yearly: false,
+        };
+        assert!(!holiday.contains(day("2024-12-29")));
+        assert!(holiday.contains(day("2024-12-30")));
+        assert!(holiday.contains(day("2025-01-01")));
+        assert!(!holiday.contains(day("2025-01-02")));
+        assert!(!holiday.contains(day("2025-12-31")));
+    }
+
+    #[test]
+    fn yearly_holidays() {
+        let holiday = Holiday {
+            start: day("2023-12-31"),
+            days: 2,
+            yearly: true,
+        };
+        assert!(!holiday.contains(day("2022-12-31")));
+        assert!(holiday.contains(day("2023-12-31")));
+        assert!(holiday.contains(day("2030-12-31")));
+        assert!(holiday.contains(day("2031-01-01")));
+        assert!(!holiday.contains(day("2031-01-02")));
+
+        let leap = parse_holiday("02-29").expect("holiday");
+        assert!(leap.yearly);
+        assert!(leap.contains(day("2028-02-29")));
+        assert!(!leap.contains(day("2027-02-28")));
+        assert!(!leap.contains(day("2027-03-01")));
+    }
+
+    #[test]
+    fn parse_holidays() {
+        assert_eq!(
+            parse_holiday("2024-05-01"),
+            Ok(Holiday {
+                start: day("2024-05-01"),
+                days: 1,
+                yearly: false,
+            })
+        );
+        for invalid in ["2024-02-30", "13-01", "05", "may-01", "2024-05-01-1"] {
+            assert!(parse_holiday(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn ical_duration_days() {
+        assert_eq!(parse_ical_duration_days("P1D"), Some(1));
+        assert_eq!(parse_ical_duration_days("P2W"), Some(14));
+        assert_eq!(parse_ical_duration_days("PT8H"), Some(0));
+        assert_eq!(parse_ical_duration_days("P3DT12H"), Some(3));
+        assert_eq!(parse_ical_duration_days("1D"), None);
+        assert_eq!(parse_ical_duration_days("PXD"), None);
+    }
+
+    const ICAL: &str = "BEGIN:VCALENDAR\r\n\
+        BEGIN:VEVENT\r\n\
+        SUMMARY:New year\r\n\
+        DTSTART;VALUE=DATE:20240101\r\n\
+        DTEND;VALUE=DATE:20240102\r\n\
+        RRULE:FREQ=YEARLY\r\n\
+        END:VEVENT\r\n\
+        BEGIN:VEVENT\r\n\
+        SUMMARY:Company\r\n\
+        \x20outing\r\n\
+        DTSTART:20240610T080000\r\n\
+        DTEND:20240611T120000\r\n\
+        END:VEVENT\r\n\
+        BEGIN:VEVENT\r\n\
+        DTSTART;VALUE=DATE:20240701\r\n\
+        DURATION:P2W\r\n\
+        END:VEVENT\r\n\
+        BEGIN:VEVENT\r\n\
+        DTSTART:20240801T090000\r\n\
+        DURATION:PT8H\r\n\
+        END:VEVENT\r\n\
+        END:VCALENDAR\r\n";
+
+    #[test]
+    fn ical_end_and_duration() {
+        let holidays = parse_ical(ICAL).expect("valid iCalendar");
+        assert_eq!(
+            holidays,
+            [
+                Holiday {
+                    start: day("2024-01-01"),
+                    days: 1,
+                    yearly: true,
+                },
+                // ends during the 11th, which is included
+                Holiday {
+                    start: day("2024-06-10"),
+                    days: 2,
+                    yearly: false,
+                },
+                Holiday {
+                    start: day("2024-07-01"),
+                    days: 14,
+                    yearly: false,
+                },
+                // less than a day still covers the day it starts on
+                Holiday {
+                    start: day("2024-08-01"),
+                    days: 1,
+                    yearly: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn ical_errors() {
+        let event = |lines: &str| format!("BEGIN:VEVENT\n{lines}\nEND:VEVENT\n");
+        assert!(parse_ical(&event("SUMMARY:x"))
+            .expect_err("no DTSTART")
+            .contains("\"x\" has no DTSTART"));
+        assert!(parse_ical(&event("DTSTART:2024"))
+            .expect_err("invalid DTSTART")
+            .starts_with("line 2:"));
+        assert!(parse_ical(&event("DTSTART:20240101\nDURATION:1D")).is_err());
+        assert!(parse_ical(&event("DTSTART:20240101\nRRULE:FREQ=WEEKLY")).is_err());
+    }
+
+    #[test]
+    fn read_ical_file() {
+        let mut file = tempfile::NamedTempFile::new().expect("temporary file");
+        file.write_all(ICAL.as_bytes()).expect("write");
+        let path = file.path().to_str().expect("utf-8 path");
+        assert_eq!(read_ical(path).expect("valid file").len(), 4);
+        let missing = format!("{path}.missing");
+        assert!(read_ical(&missing)
+            .expect_err("missing file")
+            .starts_with("unable to read"));
+    }
+}
@@ -0,0 +1,245 @@
+//! The service: receiving COE on the configured UDP sockets, the HTTP endpoints, reloads and
+//! the shutdown on SIGINT/SIGTERM
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_signal::{Signal, Signals};
+use smol::{net::UdpSocket, stream::StreamExt};
+use tracing::{error, info, trace, warn};
+
+use crate::{
+    admin,
+    alarm::Alarms,
+    capture::{self, Datagram},
+    clock,
+    config::{Config, SharedConfig},
+    engine::{self, Engine},
+    health, http, logging,
+    metrics::Metrics,
+    reload, rule,
+    source::{self, Resolver},
+};
+
+async fn handle_packet(
+    config: &SharedConfig,
+    metrics: &Arc<Metrics>,
+    alarms: &Arc<Alarms>,
+    resolver: &Resolver,
+    cmi_listen_socket: &UdpSocket,
+    buf: &mut [u8],
+) {
+    match cmi_listen_socket.recv_from(buf).await {
+        Ok((len, addr)) => {
+            // on a dual-stack socket listening on `::`, IPv4 CMIs send from ::ffff:a.b.c.d
+            let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+            trace!("Received UDP packet of {len} bytes on CMI listen socket.");
+            metrics.udp_packet_received();
+            // use the same config for the whole packet, even if it is reloaded meanwhile
+            let config = config.get();
+            if let Some(capture) = &config.capture {
+                let datagram = Datagram::new(clock::now_ms(), addr, &buf[0..len]);
+                if let Err(e) = capture::append(&capture.file, &datagram) {
+                    warn!(
+                        "Unable to capture the UDP packet to {}: {e}",
+                        capture.file.display()
+                    );
+                };
+            };
+            engine::handle_datagram(&config, metrics, alarms, resolver, &buf[0..len], addr);
+        }
+        Err(e) => {
+            warn!("Error receiving UDP packet on CMI listen socket: {e}.");
+        }
+    };
+}
+
+async fn shutdown(alarms: &Alarms, shutdown_chan: &smol::channel::Receiver<()>) {
+    match shutdown_chan.recv().await {
+        Ok(()) => {
+            info!("Shutting down.");
+            alarms.persist_now();
+            std::process::exit(0);
+        }
+        Err(e) => {
+            warn!("Error while receiving shutdown signal: {e}. Shutting down.");
+            std::process::exit(1);
+        }
+    };
+}
+
+/// Receive and process the packets arriving on a single listen socket, forever
+async fn receive_loop(
+    config: Arc<SharedConfig>,
+    metrics: Arc<Metrics>,
+    alarms: Arc<Alarms>,
+    resolver: Arc<Resolver>,
+    cmi_listen_socket: UdpSocket,
+) {
+    let mut buf = [0_u8; 252];
+    #[allow(clippy::infinite_loop)]
+    loop {
+        handle_packet(
+            &config,
+            &metrics,
+            &alarms,
+            &resolver,
+            &cmi_listen_socket,
+            &mut buf,
+        )
+        .await;
+    }
+}
+
+async fn main_loop(
+    config: &Arc<SharedConfig>,
+    metrics: &Arc<Metrics>,
+    alarms: &Arc<Alarms>,
+    resolver: &Arc<Resolver>,
+    cmi_listen_sockets: Vec<UdpSocket>,
+    shutdown_chan: &smol::channel::Receiver<()>,
+) {
+    // This is the main loop: receive UDP; process and potentially send commands to AMI.
+    // Every listen socket is received on in its own task, until shutdown.
+    for socket in cmi_listen_sockets {
+        smol::spawn(receive_loop(
+            config.clone(),
+            metrics.clone(),
+            alarms.clone(),
+            resolver.clone(),
+            socket,
+        ))
+        .detach();
+    }
+    shutdown(alarms, shutdown_chan).await;
+}
+
+/// Run the service until SIGINT or SIGTERM, reloading `config_path` on SIGHUP.
+///
+/// This sets up the configured logging outputs, so it is meant for a process of its own.
+pub fn run(config_path: PathBuf, config: Arc<Config>) -> Result<(), Box<dyn std::error::Error>> {
+    // setup tracing
+    if let Err(e) = logging::init(&config.logging) {
+        tracing::subscriber::with_default(logging::bootstrap_subscriber(), || {
+            error!("Unable to setup the configured logging outputs: {e}");
+        });
+        Err(e)?;
+    };
+
+    // SIGHUP reloads the config, see `reload::reload_on_sighup`. Registered right away, so a
+    // SIGHUP during the startup does not terminate the process.
+    let sighup = match Signals::new([Signal::Hup]) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            warn!("Unable to listen for SIGHUP, reloading is only possible via the admin API: {e}");
+            None
+        }
+    };
+    let (tx, rx) = smol::channel::bounded(1);
+    let mut signals =
+        Signals::new([Signal::Int, Signal::Term]).expect("Could not install signal handler.");
+    smol::spawn(async move {
+        if signals.next().await.is_some() {
+            tx.send(()).await.expect("Could not send shutdown message.");
+        };
+    })
+    .detach();
+
+    // UDP socket listening for CMI input
+    let cmi_listen_sockets = smol::block_on(config.cmi_listen_sockets())?;
+    // this also resolves the hostnames of allowed sources before the first packet arrives
+    let engine = Engine::new(config.clone());
+    let (metrics, alarms, resolver) = (
+        engine.metrics().clone(),
+        engine.alarms().clone(),
+        engine.resolver().clone(),
+    );
+    metrics.set_udp_socket_bound(true);
+    let shared_config = Arc::new(SharedConfig::new(config_path, config.clone()));
+    smol::spawn(source::resolve_periodically(
+        shared_config.clone(),
+        resolver.clone(),
+    ))
+    .detach();
+    // also for rules added by a reload
+    smol::spawn(rule::evaluate_periodically(
+        shared_config.clone(),
+        metrics.clone(),
+        alarms.clone(),
+    ))
+    .detach();
+    // force the opening of a TLS stream. This makes error messages available immediately on
+    // startup.
+    let ami_conn = config.asterisk_connection();
+    metrics.set_ami_connected(ami_conn.is_ok());
+    match ami_conn {
+        Ok(_conn) => info!("Connection to asterisk could be established."),
+        Err(e) => {
+            error!("Unable to connect to asterisk: {e}");
+            Err(e)?;
+        }
+    };
+
+    info!(
+        "Got UDP sockets and made sure that asterisk is reachable. Now listening for COE packets on {}",
+        cmi_listen_sockets
+            .iter()
+            .map(|x| x.local_addr().map(|x| x.to_string()))
+            .collect::<Result<Vec<_>, _>>()?
+            .join(", ")
+    );
+    if let Some(http_config) = &config.http {
+        let listener = smol::block_on(smol::net::TcpListener::bind(http_config.listen))?;
+        info!(
+            "Serving metrics and health endpoints on http://{}",
+            listener.local_addr()?
+        );
+        smol::spawn(http::serve(listener, {
+            let config = shared_config.clone();
+            let metrics = metrics.clone();
+            move |request| {
+                let response = http::status_route(&request, &config.get(), &metrics);
+                async move { response }
+            }
+        }))
+        .detach();
+        if let Some(interval) = http_config.readiness_ami_check_interval {
+            smol::spawn(health::check_ami_periodically(
+                shared_config.clone(),
+                metrics.clone(),
+                interval,
+            ))
+            .detach();
+        };
+    };
+    if let Some(admin_config) = &config.admin {
+        let listener = smol::block_on(smol::net::TcpListener::bind(admin_config.listen))?;
+        info!("Serving the admin API on http://{}", listener.local_addr()?);
+        smol::spawn(http::serve(listener, {
+            let config = shared_config.clone();
+            let metrics = metrics.clone();
+            let alarms = alarms.clone();
+            move |request| admin::route(request, config.clone(), metrics.clone(), alarms.clone())
+        }))
+        .detach();
+    };
+    if let Some(sighup) = sighup {
+        smol::spawn(reload::reload_on_sighup(
+            sighup,
+            shared_config.clone(),
+            metrics.clone(),
+            alarms.clone(),
+        ))
+        .detach();
+    };
+    smol::block_on(main_loop(
+        &shared_config,
+        &metrics,
+        &alarms,
+        &resolver,
+        cmi_listen_sockets,
+        &rx,
+    ));
+    Ok(())
+}
@@ -0,0 +1,164 @@
+//! Named COE values, digital or analog, combined by rules
+
+use coe::{COEValue, DigitalCOEValue, Packet, Payload};
+use serde::Deserialize;
+
+use crate::config::OneOrMore;
+use crate::source::Sources;
+
+/// A single COE value used in rules
+#[derive(Debug, PartialEq, Eq)]
+pub struct SignalConfig {
+    /// name of the signal, used in rules
+    pub name: String,
+    /// Expect the packet to arrive from any of these sources. Ignore all other packets.
+    pub expect_from_addr: Sources,
+    /// expect this CAN-ID in messages we get (ignore others)
+    pub expect_index: u8,
+    /// expect this PDO in messages we get (ignore others)
+    pub expect_pdo: u8,
+}
+impl TryFrom<SignalConfigData> for SignalConfig {
+    type Error = String;
+    fn try_from(value: SignalConfigData) -> Result<Self, Self::Error> {
+        Ok(Self {
+            name: value.name,
+            expect_from_addr: value.expect_from_addr.as_slice().try_into()?,
+            expect_index: value.expect_index,
+            expect_pdo: value.expect_pdo,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SignalConfigData {
+    pub name: String,
+    /// an address, a subnet or a hostname, or a list of them
+    pub expect_from_addr: OneOrMore,
+    pub expect_index: u8,
+    pub expect_pdo: u8,
+}
+
+/// Analog values are sent as integers; the unit decides how many decimals they have
+pub fn decimals(unit_id: u8) -> i32 {
+    match unit_id {
+        // tenths, e.g. °C, %, kWh
+        1 | 6 | 7 | 8 | 11 | 14 | 26 | 40 | 41 | 42 | 46 | 52 | 54 | 57 | 58 | 63 | 65 | 71
+        | 72 | 73 | 75 => 1,
+        // hundredths, e.g. kW, V, bar
+        10 | 13 | 18 | 21 | 23 | 24 | 50 | 51 | 70 => 2,
+        29..=34 | 53 => 5,
+        56 => 6,
+        _ => 0,
+    }
+}
+
+/// The symbol of a unit, as shown by the CMI, e.g. `°C` for 1
+pub fn unit_symbol(unit_id: u8) -> &'static str {
+    match unit_id {
+        1 | 46 => "°C",
+        2 => "W/m²",
+        3 => "l/h",
+        4 | 57 => "s",
+        5 => "min",
+        6 | 34 => "l/pulse",
+        7 | 74 => "K",
+        8 => "%",
+        10 => "kW",
+        11 => "kWh",
+        12 => "MWh",
+        13 => "V",
+        14 => "mA",
+        15 => "h",
+        16 => "d",
+        17 => "pulses",
+        18 => "kΩ",
+        19 => "l",
+        20 => "km/h",
+        21 => "Hz",
+        22 => "l/min",
+        23 => "bar",
+        25 => "km",
+        26 => "m",
+        27 => "mm",
+        28 => "m³",
+        29 => "Hz/km/h",
+        30 => "Hz/m/s",
+        31 => "kWh/pulse",
+        32 => "m³/pulse",
+        33 => "mm/pulse",
+        35 => "l/d",
+        36 => "m/s",
+        37 => "m³/min",
+        38 => "m³/h",
+        39 => "m³/d",
+        40 => "mm/min",
+        41 => "mm/h",
+        42 => "mm/d",
+        43 => "on/off",
+        44 => "yes/no",
+        45 => "RAS",
+        47 => "mixer",
+        50 => "€",
+        51 => "$",
+        52 => "g/m³",
+        54 | 56 => "°",
+        60 => "hh:mm",
+        63 => "A",
+        65 => "mbar",
+        66 => "Pa",
+        67 => "ppm",
+        69 => "W",
+        70 => "t",
+        71 => "kg",
+        72 => "g",
+        73 => "cm",
+        75 => "lx",
+        _ => "",
+    }
+}
+
+/// The kind of value a payload carries, e.g. `analog` or `digital on/off`
+pub fn value_type(payload: &Payload) -> &'static str {
+    match payload.value() {
+        COEValue::Analogue(_) => "analog",
+        COEValue::Digital(DigitalCOEValue::OnOff(_)) => "digital on/off",
+        COEValue::Digital(DigitalCOEValue::YesNo(_)) => "digital yes/no",
+        COEValue::Digital(DigitalCOEValue::RASMode(_)) => "digital RAS",
+        COEValue::Digital(DigitalCOEValue::Mixer(_)) => "digital mixer",
+    }
+}
+
+/// The value of a payload for humans: `on`, `no` or e.g. `21.5` for 215 tenths of °C
+pub fn display_value(payload: &Payload) -> String {
+    match payload.value() {
+        COEValue::Digital(DigitalCOEValue::OnOff(x)) => (if x { "on" } else { "off" }).to_owned(),
+        COEValue::Digital(DigitalCOEValue::YesNo(x)) => (if x { "yes" } else { "no" }).to_owned(),
+        COEValue::Digital(_) | COEValue::Analogue(_) => {
+            let decimals = usize::try_from(decimals(payload.unit_id())).unwrap_or_default();
+            format!("{:.decimals$}", numeric(payload))
+        }
+    }
+}
+
+/// The number a payload stands for: 1 or 0 for digital values, analog values with their
+/// decimals, e.g. 21.5 for a temperature sent as 215 tenths of °C
+pub fn numeric(payload: &Payload) -> f64 {
+    match payload.value() {
+        COEValue::Digital(
+            DigitalCOEValue::OnOff(x)
+            | DigitalCOEValue::YesNo(x)
+            | DigitalCOEValue::RASMode(x)
+            | DigitalCOEValue::Mixer(x),
+        ) => f64::from(u8::from(x)),
+        COEValue::Analogue(_) => {
+            // the coe crate has no accessor for the raw value, so read it from the wire format:
+            // a 4 byte header, then node, pdo, format, unit and the value as i32
+            let raw = Packet::try_from_payloads(&[*payload])
+                .map(|x| x.serialize_into_vec())
+                .and_then(|x| x.get(8..12).and_then(|x| x.try_into().ok()))
+                .map_or(0, i32::from_le_bytes);
+            f64::from(raw) / 10_f64.powi(decimals(payload.unit_id()))
+        }
+    }
+}
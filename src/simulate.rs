@@ -0,0 +1,345 @@
+//! A simulated CMI, sending COE from a scripted scenario, for testing without flipping contacts
+
+use std::{
+    collections::BTreeMap,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use coe::{AnalogueCOEValue, COEValue, DigitalCOEValue, Payload};
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use crate::signal;
+
+/// Send to this address unless the scenario or the command line says otherwise
+pub const DEFAULT_TARGET: &str = "127.0.0.1:5442";
+/// Like a CMI, send all current values again after this long, unless configured otherwise
+const DEFAULT_RESEND: Duration = Duration::from_secs(60);
+
+/// A value sent to a CAN-ID and PDO
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Digital(bool),
+    Analog { value: f64, unit_id: u8 },
+}
+
+/// A single step of a scenario
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// set a value and send it right away
+    Set { node: u8, pdo: u8, value: Value },
+    /// change an analog value linearly, sending it every `step`
+    Ramp {
+        node: u8,
+        pdo: u8,
+        unit_id: u8,
+        from: f64,
+        to: f64,
+        duration: Duration,
+        step: Duration,
+    },
+    /// keep sending the current values periodically
+    Wait(Duration),
+    /// send nothing, like a CMI that is offline
+    Silence(Duration),
+}
+
+/// What to send, and when
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    pub target: Option<String>,
+    pub resend: Duration,
+    pub steps: Vec<Step>,
+}
+impl TryFrom<ScenarioData> for Scenario {
+    type Error = String;
+    fn try_from(value: ScenarioData) -> Result<Self, Self::Error> {
+        let seconds = |path: &str, x: f64| {
+            Duration::try_from_secs_f64(x)
+                .map_err(|_| format!("{path}: {x} is not a number of seconds"))
+        };
+        let node_and_pdo = |path: &str, node: u8, pdo: u8| {
+            if !(1..=62).contains(&node) {
+                return Err(format!("{path}.node: {node} is not a CAN-ID from 1 to 62"));
+            };
+            if !(1..=64).contains(&pdo) {
+                return Err(format!(
+                    "{path}.pdo: {pdo} is not a PDO from 1 to 64, as in the web-GUI"
+                ));
+            };
+            Ok(())
+        };
+        let mut steps = Vec::new();
+        for (i, step) in value.steps.into_iter().enumerate() {
+            let path = format!("steps[{i}]");
+            steps.push(match step {
+                StepData::Set(x) => {
+                    node_and_pdo(&path, x.node, x.pdo)?;
+                    let value = match (x.digital, x.analog) {
+                        (Some(x), None) => Value::Digital(x),
+                        (None, Some(value)) => Value::Analog {
+                            value,
+                            unit_id: x.unit.unwrap_or(0),
+                        },
+                        _ => {
+                            return Err(format!(
+                                "{path}.set needs exactly one of digital and analog"
+                            ))
+                        }
+                    };
+                    payload(x.node, x.pdo, value).map_err(|e| format!("{path}.set: {e}"))?;
+                    Step::Set {
+                        node: x.node,
+                        pdo: x.pdo,
+                        value,
+                    }
+                }
+                StepData::Ramp(x) => {
+                    node_and_pdo(&path, x.node, x.pdo)?;
+                    let step = seconds(&path, x.step_seconds.unwrap_or(1.0))?;
+                    if step.is_zero() {
+                        return Err(format!("{path}.step_seconds needs to be more than 0"));
+                    };
+                    let unit_id = x.unit.unwrap_or(0);
+                    for value in [x.from, x.to] {
+                        payload(x.node, x.pdo, Value::Analog { value, unit_id })
+                            .map_err(|e| format!("{path}.ramp: {e}"))?;
+                    }
+                    Step::Ramp {
+                        node: x.node,
+                        pdo: x.pdo,
+                        unit_id,
+                        from: x.from,
+                        to: x.to,
+                        duration: seconds(&path, x.seconds)?,
+                        step,
+                    }
+                }
+                StepData::WaitSeconds(x) => Step::Wait(seconds(&path, x)?),
+                StepData::SilenceSeconds(x) => Step::Silence(seconds(&path, x)?),
+            });
+        }
+        let resend = match value.resend_seconds {
+            Some(x) => seconds("resend_seconds", x)?,
+            None => DEFAULT_RESEND,
+        };
+        if resend.is_zero() {
+            return Err("resend_seconds needs to be more than 0".to_owned());
+        };
+        Ok(Self {
+            target: value.target,
+            resend,
+            steps,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScenarioData {
+    /// `host:port` to send to, e.g. `127.0.0.1:5442`. Default: 127.0.0.1:5442
+    pub target: Option<String>,
+    /// send all current values again after this many seconds without a change. Default: 60
+    pub resend_seconds: Option<f64>,
+    /// e.g. `- wait_seconds: 5` rather than `- !wait_seconds 5`
+    #[serde(with = "serde_yaml::with::singleton_map_recursive")]
+    pub steps: Vec<StepData>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum StepData {
+    Set(SetData),
+    Ramp(RampData),
+    WaitSeconds(f64),
+    SilenceSeconds(f64),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SetData {
+    pub node: u8,
+    /// as in the web-GUI
+    pub pdo: u8,
+    /// sent as digital on/off (unit 43)
+    pub digital: Option<bool>,
+    /// sent with the decimals of the unit, e.g. 21.5 with unit 1 (°C) as 215
+    pub analog: Option<f64>,
+    /// unit of analog values. Default: 0 (dimensionless)
+    pub unit: Option<u8>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RampData {
+    pub node: u8,
+    /// as in the web-GUI
+    pub pdo: u8,
+    /// Default: 0 (dimensionless)
+    pub unit: Option<u8>,
+    pub from: f64,
+    pub to: f64,
+    pub seconds: f64,
+    /// send the value this often. Default: 1
+    pub step_seconds: Option<f64>,
+}
+
+impl Scenario {
+    pub fn read(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let data: ScenarioData = serde_yaml::from_reader(std::fs::File::open(path)?)?;
+        Ok(data.try_into()?)
+    }
+}
+
+/// The payload for a value; analog values are rounded to the decimals of their unit
+fn payload(node: u8, pdo: u8, value: Value) -> Result<Payload, String> {
+    let value = match value {
+        Value::Digital(x) => COEValue::Digital(DigitalCOEValue::OnOff(x)),
+        Value::Analog { value, unit_id } => {
+            let raw = (value * 10_f64.powi(signal::decimals(unit_id))).round();
+            if !(f64::from(i32::MIN)..=f64::from(i32::MAX)).contains(&raw) {
+                return Err(format!("{value} is too large for unit {unit_id}"));
+            };
+            let raw = raw as i32;
+            COEValue::Analogue(
+                AnalogueCOEValue::try_from((&unit_id, &raw.to_le_bytes()[..]))
+                    .map_err(|e| format!("unit {unit_id}: {e}"))?,
+            )
+        }
+    };
+    // NOTE: the web-gui shows the pdo one higher than it is on-wire
+    Ok(Payload::new(node, pdo - 1, value))
+}
+
+/// The current values and where they are sent
+struct Simulator {
+    socket: UdpSocket,
+    target: SocketAddr,
+    values: BTreeMap<(u8, u8), Payload>,
+    last_sent: Instant,
+}
+impl Simulator {
+    /// Send all current values, in as few packets as possible
+    fn send_all(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let payloads = self.values.values().copied().collect::<Vec<_>>();
+        for packet in coe::packets_from_payloads(&payloads) {
+            self.socket
+                .send_to(&packet.serialize_into_vec(), self.target)?;
+        }
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn set(&mut self, node: u8, pdo: u8, value: Value) -> Result<(), Box<dyn std::error::Error>> {
+        let payload = payload(node, pdo, value)?;
+        let unit = match value {
+            Value::Digital(_) => String::new(),
+            Value::Analog { unit_id, .. } => format!(" {}", signal::unit_symbol(unit_id)),
+        };
+        println!(
+            "{} CAN-ID {node} PDO {pdo}: {}{unit}",
+            timestamp(),
+            signal::display_value(&payload),
+        );
+        self.values.insert((node, pdo), payload);
+        self.send_all()
+    }
+
+    /// Sleep for `duration`, sending all values every `resend` unless silent
+    fn wait(
+        &mut self,
+        duration: Duration,
+        resend: Duration,
+        silent: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let end = Instant::now() + duration;
+        loop {
+            let now = Instant::now();
+            if now >= end {
+                return Ok(());
+            };
+            if silent || self.values.is_empty() {
+                std::thread::sleep(end - now);
+                continue;
+            };
+            let next = self.last_sent + resend;
+            if next > end {
+                std::thread::sleep(end - now);
+                continue;
+            };
+            std::thread::sleep(next.saturating_duration_since(now));
+            self.send_all()?;
+        }
+    }
+}
+
+fn timestamp() -> String {
+    let now = OffsetDateTime::now_utc();
+    format!("{:02}:{:02}:{:02}Z", now.hour(), now.minute(), now.second())
+}
+
+/// Run the scenario once, sending to `target` or the target of the scenario
+pub fn run(scenario: &Scenario, target: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let target = target
+        .or(scenario.target.as_deref())
+        .unwrap_or(DEFAULT_TARGET);
+    let target = target
+        .to_socket_addrs()
+        .map_err(|e| format!("Unable to resolve {target}: {e}"))?
+        .next()
+        .ok_or_else(|| format!("{target} has no address"))?;
+    let bind: SocketAddr = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    }
+    .parse()?;
+    let mut simulator = Simulator {
+        socket: UdpSocket::bind(bind)?,
+        target,
+        values: BTreeMap::new(),
+        last_sent: Instant::now(),
+    };
+    println!("Sending COE v2 to {target}");
+    for step in &scenario.steps {
+        match step {
+            Step::Set { node, pdo, value } => simulator.set(*node, *pdo, *value)?,
+            Step::Ramp {
+                node,
+                pdo,
+                unit_id,
+                from,
+                to,
+                duration,
+                step,
+            } => {
+                let steps = (duration.as_secs_f64() / step.as_secs_f64())
+                    .ceil()
+                    .max(1.0);
+                for i in 0..=steps as u64 {
+                    if i > 0 {
+                        std::thread::sleep(duration.div_f64(steps));
+                    };
+                    let value = from + (to - from) * (i as f64 / steps);
+                    simulator.set(
+                        *node,
+                        *pdo,
+                        Value::Analog {
+                            value,
+                            unit_id: *unit_id,
+                        },
+                    )?;
+                }
+            }
+            Step::Wait(x) => {
+                println!("{} waiting {}s", timestamp(), x.as_secs_f64());
+                simulator.wait(*x, scenario.resend, false)?;
+            }
+            Step::Silence(x) => {
+                println!("{} silent for {}s", timestamp(), x.as_secs_f64());
+                simulator.wait(*x, scenario.resend, true)?;
+            }
+        };
+    }
+    println!("{} scenario done", timestamp());
+    Ok(())
+}
@@ -0,0 +1,457 @@
+//! Which hosts may send COE to an input: addresses, subnets and hostnames
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, ToSocketAddrs},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::config::{Config, SharedConfig};
+
+/// Hostnames are resolved again after this long, unless configured otherwise
+pub const DEFAULT_RESOLVE_INTERVAL: Duration = Duration::from_secs(300);
+/// Check this often for hostnames that are due, e.g. added by a reload
+const RESOLVE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Log a rejected source at most this often
+const REJECTED_LOG_INTERVAL: Duration = Duration::from_secs(600);
+/// Remember at most this many rejected sources for rate limiting the log
+const MAX_REJECTED: usize = 1000;
+
+/// A single allowed source of COE packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Addr(IpAddr),
+    /// all addresses sharing the first `prefix` bits with `addr`
+    Net {
+        addr: IpAddr,
+        prefix: u8,
+    },
+    /// resolved periodically
+    Host(String),
+}
+impl core::fmt::Display for Source {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Addr(x) => write!(f, "{x}"),
+            Self::Net { addr, prefix } => write!(f, "{addr}/{prefix}"),
+            Self::Host(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+/// The first `prefix` bits of `addr` as a number, and the number of bits of the family
+fn masked(addr: IpAddr, prefix: u8) -> (u128, u8) {
+    let (bits, len): (u128, u8) = match addr {
+        IpAddr::V4(x) => (u128::from(u32::from(x)), 32),
+        IpAddr::V6(x) => (u128::from(x), 128),
+    };
+    let host_bits = u32::from(len.saturating_sub(prefix));
+    (bits.checked_shr(host_bits).unwrap_or(0), len)
+}
+
+impl Source {
+    /// The subnet this source stands for, `None` for hostnames
+    fn net(&self) -> Option<(IpAddr, u8)> {
+        match self {
+            Self::Addr(x) => Some((*x, if x.is_ipv4() { 32 } else { 128 })),
+            Self::Net { addr, prefix } => Some((*addr, *prefix)),
+            Self::Host(_) => None,
+        }
+    }
+
+    fn contains(&self, ip: IpAddr, resolver: &Resolver) -> bool {
+        match (self, self.net()) {
+            (Self::Host(x), _) => resolver.addrs(x).contains(&ip),
+            (_, Some((addr, prefix))) => {
+                addr.is_ipv4() == ip.is_ipv4() && masked(addr, prefix) == masked(ip, prefix)
+            }
+            (_, None) => false,
+        }
+    }
+
+    /// Some address could match both, e.g. an address inside a subnet.
+    /// Hostnames only overlap with the same hostname.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        match (self.net(), other.net()) {
+            (Some((a, a_prefix)), Some((b, b_prefix))) => {
+                let prefix = a_prefix.min(b_prefix);
+                a.is_ipv4() == b.is_ipv4() && masked(a, prefix) == masked(b, prefix)
+            }
+            _ => self == other,
+        }
+    }
+}
+
+/// An IP address, a subnet in CIDR notation or a hostname,
+/// e.g. `192.168.1.10`, `192.168.1.0/24`, `fd00:10::/64` or `cmi-boiler.lan`
+pub fn parse_source(value: &str) -> Result<Source, String> {
+    if let Ok(x) = value.parse::<IpAddr>() {
+        return Ok(Source::Addr(x));
+    };
+    if let Some((addr, prefix)) = value.split_once('/') {
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("{value:?} is not a subnet ({e}), e.g. 192.168.1.0/24"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix
+            .parse::<u8>()
+            .ok()
+            .filter(|x| *x <= max)
+            .ok_or_else(|| format!("{value:?} needs a prefix length from 0 to {max}"))?;
+        if network(addr, prefix) != addr {
+            return Err(format!(
+                "{value:?} has bits set after the prefix, did you mean {}/{prefix}?",
+                network(addr, prefix)
+            ));
+        };
+        return Ok(Source::Net { addr, prefix });
+    };
+    let is_hostname = !value.is_empty()
+        && value.len() <= 253
+        && value.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !is_hostname {
+        return Err(format!(
+            "{value:?} is neither an IP address, a subnet nor a hostname, e.g. 192.168.1.10, 192.168.1.0/24 or cmi.lan"
+        ));
+    };
+    Ok(Source::Host(value.to_ascii_lowercase()))
+}
+
+/// `addr` with all bits after the prefix cleared
+fn network(addr: IpAddr, prefix: u8) -> IpAddr {
+    let (bits, len) = masked(addr, prefix);
+    let bits = bits.checked_shl(u32::from(len - prefix)).unwrap_or(0);
+    match addr {
+        IpAddr::V4(_) => IpAddr::from(u32::try_from(bits).unwrap_or_default().to_be_bytes()),
+        IpAddr::V6(_) => IpAddr::from(bits.to_be_bytes()),
+    }
+}
+
+/// The allowed sources of an input; a packet needs to come from any of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sources(pub Vec<Source>);
+impl Sources {
+    pub fn contains(&self, ip: IpAddr, resolver: &Resolver) -> bool {
+        self.0.iter().any(|x| x.contains(ip, resolver))
+    }
+
+    /// The hostnames to resolve
+    pub fn hosts(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().filter_map(|x| match x {
+            Source::Host(x) => Some(x.as_str()),
+            _ => None,
+        })
+    }
+}
+impl core::fmt::Display for Sources {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for (i, x) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            };
+            write!(f, "{x}")?;
+        }
+        Ok(())
+    }
+}
+impl TryFrom<&[String]> for Sources {
+    type Error = String;
+    fn try_from(value: &[String]) -> Result<Self, Self::Error> {
+        if value.is_empty() {
+            return Err("no source address given".to_owned());
+        };
+        Ok(Self(
+            value
+                .iter()
+                .map(|x| parse_source(x))
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+/// The addresses of a hostname and when they were looked up
+#[derive(Debug)]
+struct Resolved {
+    at: Instant,
+    addrs: Vec<IpAddr>,
+}
+
+/// Resolves the hostnames of the allowed sources and rate limits the log of rejected sources
+#[derive(Debug, Default)]
+pub struct Resolver {
+    hosts: RwLock<BTreeMap<String, Resolved>>,
+    /// when a rejected source was logged last
+    rejected: Mutex<BTreeMap<IpAddr, Instant>>,
+}
+impl Resolver {
+    /// The addresses `host` resolved to last, empty if it never resolved
+    fn addrs(&self, host: &str) -> Vec<IpAddr> {
+        self.hosts
+            .read()
+            .expect("resolver lock poisoned")
+            .get(host)
+            .map(|x| x.addrs.clone())
+            .unwrap_or_default()
+    }
+
+    /// All hostnames in the config
+    fn configured_hosts(config: &Config) -> Vec<String> {
+        let mut res = config
+            .alarms
+            .iter()
+            .flat_map(|x| x.inputs())
+            .flat_map(|(sources, ..)| sources.hosts())
+            .chain(
+                config
+                    .signals
+                    .iter()
+                    .flat_map(|x| x.expect_from_addr.hosts()),
+            )
+            .chain(
+                config
+                    .maintenance
+                    .input
+                    .iter()
+                    .flat_map(|x| x.expect_from_addr.hosts()),
+            )
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>();
+        res.sort();
+        res.dedup();
+        res
+    }
+
+    /// Resolve the hostnames of the config that were never resolved or not within
+    /// `cmi.resolve_interval`, and forget those no longer configured.
+    ///
+    /// If a lookup fails, the previous addresses are kept. This blocks.
+    pub fn resolve_due(&self, config: &Config) {
+        self.resolve(Self::configured_hosts(config), config.cmi.resolve_interval);
+    }
+
+    /// Resolve those of `hosts` that were never resolved or not within `interval`, and forget
+    /// all others. This blocks.
+    pub fn resolve(&self, hosts: Vec<String>, interval: Duration) {
+        let due = {
+            let mut resolved = self.hosts.write().expect("resolver lock poisoned");
+            resolved.retain(|host, _| hosts.contains(host));
+            hosts
+                .into_iter()
+                .filter(|x| match resolved.get(x) {
+                    Some(x) => x.at.elapsed() >= interval,
+                    None => true,
+                })
+                .collect::<Vec<_>>()
+        };
+        for host in due {
+            // the port is required by the lookup, but not used
+            let addrs = match (host.as_str(), 0).to_socket_addrs() {
+                Ok(x) => {
+                    let mut addrs = x.map(|x| x.ip().to_canonical()).collect::<Vec<_>>();
+                    addrs.sort();
+                    addrs.dedup();
+                    addrs
+                }
+                Err(e) => {
+                    warn!("Unable to resolve the CMI source {host}, keeping its previous addresses: {e}");
+                    Vec::new()
+                }
+            };
+            let mut resolved = self.hosts.write().expect("resolver lock poisoned");
+            let previous = resolved.get(&host).map(|x| x.addrs.clone());
+            let addrs = if addrs.is_empty() {
+                previous.clone().unwrap_or_default()
+            } else {
+                addrs
+            };
+            if previous.as_ref() != Some(&addrs) {
+                info!(addrs = ?addrs, "The CMI source {host} resolved to new addresses.");
+            } else {
+                debug!(addrs = ?addrs, "Resolved the CMI source {host}.");
+            };
+            resolved.insert(
+                host,
+                Resolved {
+                    at: Instant::now(),
+                    addrs,
+                },
+            );
+        }
+    }
+
+    /// Whether to log a packet from this rejected source, at most every 10 minutes per source
+    pub fn log_rejected(&self, ip: IpAddr) -> bool {
+        let mut rejected = self.rejected.lock().expect("resolver mutex poisoned");
+        if rejected
+            .get(&ip)
+            .is_some_and(|x| x.elapsed() < REJECTED_LOG_INTERVAL)
+        {
+            return false;
+        };
+        if rejected.len() >= MAX_REJECTED {
+            rejected.retain(|_, x| x.elapsed() < REJECTED_LOG_INTERVAL);
+        };
+        rejected.insert(ip, Instant::now());
+        true
+    }
+}
+
+/// Keep the hostnames of the allowed sources resolved, forever
+pub async fn resolve_periodically(shared: Arc<SharedConfig>, resolver: Arc<Resolver>) {
+    loop {
+        let (config, resolver2) = (shared.get(), resolver.clone());
+        smol::unblock(move || resolver2.resolve_due(&config)).await;
+        smol::Timer::after(RESOLVE_POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(value: &str) -> Source {
+        parse_source(value).expect("valid source")
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().expect("IP address")
+    }
+
+    #[test]
+    fn parse_addresses_subnets_and_hostnames() {
+        assert_eq!(source("192.168.1.10"), Source::Addr(ip("192.168.1.10")));
+        assert_eq!(source("fd00::1"), Source::Addr(ip("fd00::1")));
+        assert_eq!(
+            source("192.168.1.0/24"),
+            Source::Net {
+                addr: ip("192.168.1.0"),
+                prefix: 24
+            }
+        );
+        assert_eq!(
+            source("0.0.0.0/0"),
+            Source::Net {
+                addr: ip("0.0.0.0"),
+                prefix: 0
+            }
+        );
+        assert_eq!(
+            source("fd00:10::/64"),
+            Source::Net {
+                addr: ip("fd00:10::"),
+                prefix: 64
+            }
+        );
+        assert_eq!(
+            source("CMI-Boiler.lan"),
+            Source::Host("cmi-boiler.lan".to_owned())
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (value, error) in [
+            ("192.168.1.0/33", "prefix length from 0 to 32"),
+            ("fd00::/129", "prefix length from 0 to 128"),
+            ("192.168.1.0/x", "prefix length"),
+            ("192.168.1.1/24", "did you mean 192.168.1.0/24?"),
+            ("fd00::1/64", "did you mean fd00::/64?"),
+            ("cmi/24", "is not a subnet"),
+            ("-cmi.lan", "neither"),
+            ("cmi..lan", "neither"),
+            ("", "neither"),
+        ] {
+            let e = parse_source(value).expect_err(value);
+            assert!(e.contains(error), "{value}: {e}");
+        }
+    }
+
+    #[test]
+    fn masked_and_network() {
+        assert_eq!(masked(ip("10.1.2.3"), 8), (10, 32));
+        assert_eq!(masked(ip("10.1.2.3"), 0), (0, 32));
+        assert_eq!(masked(ip("10.1.2.3"), 32), (0x0a01_0203, 32));
+        assert_eq!(masked(ip("::1"), 128), (1, 128));
+        assert_eq!(masked(ip("ffff::1"), 0), (0, 128));
+        assert_eq!(masked(ip("ffff::1"), 16), (0xffff, 128));
+        assert_eq!(network(ip("10.1.2.3"), 16), ip("10.1.0.0"));
+        assert_eq!(network(ip("10.1.2.3"), 0), ip("0.0.0.0"));
+        assert_eq!(network(ip("10.1.2.3"), 32), ip("10.1.2.3"));
+        assert_eq!(network(ip("fd00:1:2:3::4"), 48), ip("fd00:1:2::"));
+        assert_eq!(network(ip("fd00::4"), 0), ip("::"));
+        assert_eq!(network(ip("fd00::4"), 128), ip("fd00::4"));
+    }
+
+    #[test]
+    fn contains_respects_prefix_and_family() {
+        let resolver = Resolver::default();
+        let sources = Sources(vec![source("192.168.1.0/24"), source("fd00::7")]);
+        assert!(sources.contains(ip("192.168.1.0"), &resolver));
+        assert!(sources.contains(ip("192.168.1.255"), &resolver));
+        assert!(!sources.contains(ip("192.168.2.1"), &resolver));
+        assert!(sources.contains(ip("fd00::7"), &resolver));
+        assert!(!sources.contains(ip("fd00::8"), &resolver));
+        // the same bits in the other family
+        assert!(!sources.contains(ip("c0a8:100::"), &resolver));
+
+        let all_v4 = Sources(vec![source("0.0.0.0/0")]);
+        assert!(all_v4.contains(ip("203.0.113.9"), &resolver));
+        assert!(!all_v4.contains(ip("::"), &resolver));
+        let all_v6 = Sources(vec![source("::/0")]);
+        assert!(all_v6.contains(ip("2001:db8::1"), &resolver));
+        assert!(!all_v6.contains(ip("0.0.0.0"), &resolver));
+    }
+
+    #[test]
+    fn hostnames_match_their_resolved_addresses() {
+        let resolver = Resolver::default();
+        let sources = Sources(vec![source("cmi.lan")]);
+        assert!(!sources.contains(ip("192.168.1.10"), &resolver));
+        resolver.hosts.write().expect("lock").insert(
+            "cmi.lan".to_owned(),
+            Resolved {
+                at: Instant::now(),
+                addrs: vec![ip("192.168.1.10")],
+            },
+        );
+        assert!(sources.contains(ip("192.168.1.10"), &resolver));
+        assert!(!sources.contains(ip("192.168.1.11"), &resolver));
+        assert_eq!(sources.hosts().collect::<Vec<_>>(), ["cmi.lan"]);
+    }
+
+    #[test]
+    fn overlaps() {
+        let overlap = |a: &str, b: &str| {
+            let res = source(a).overlaps(&source(b));
+            assert_eq!(res, source(b).overlaps(&source(a)), "{a} {b}");
+            res
+        };
+        assert!(overlap("192.168.1.10", "192.168.1.10"));
+        assert!(!overlap("192.168.1.10", "192.168.1.11"));
+        assert!(overlap("192.168.1.10", "192.168.1.0/24"));
+        assert!(overlap("192.168.1.10/32", "192.168.1.10"));
+        assert!(!overlap("192.168.1.10/32", "192.168.1.11/32"));
+        assert!(overlap("192.168.0.0/16", "192.168.1.0/24"));
+        assert!(!overlap("192.168.2.0/24", "192.168.1.0/24"));
+        assert!(overlap("0.0.0.0/0", "10.0.0.1"));
+        assert!(overlap("fd00::/8", "fd00:10::/64"));
+        assert!(overlap("::/0", "fd00::1"));
+        assert!(overlap("fd00::1/128", "fd00::1"));
+        assert!(!overlap("fd00::1/128", "fd00::2/128"));
+        // different families never overlap, not even with prefix 0
+        assert!(!overlap("0.0.0.0/0", "::/0"));
+        assert!(!overlap("0.0.0.0", "::"));
+        assert!(overlap("cmi.lan", "cmi.lan"));
+        assert!(!overlap("cmi.lan", "other.lan"));
+        assert!(!overlap("cmi.lan", "0.0.0.0/0"));
+    }
+}
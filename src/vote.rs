@@ -0,0 +1,351 @@
+//! Redundant inputs of a single alarm, e.g. the same contact wired into two CMIs
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::OneOrMore;
+use crate::schedule::Priority;
+use crate::source::Sources;
+
+/// Raise the disagreement alarm after the inputs disagreed this long, unless configured otherwise
+pub const DEFAULT_DISAGREEMENT_AFTER: Duration = Duration::from_secs(60);
+
+/// How the values of the inputs are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VotePolicy {
+    /// alarm if any input reports the alarm state
+    Any,
+    /// alarm if all inputs report the alarm state
+    All,
+    /// alarm if at least this many inputs report the alarm state
+    AtLeast(usize),
+}
+impl core::fmt::Display for VotePolicy {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Any => write!(f, "any"),
+            Self::All => write!(f, "all"),
+            Self::AtLeast(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+/// `any`, `all` or the number of inputs needed
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum VotePolicyData {
+    Count(usize),
+    Name(String),
+}
+impl TryFrom<VotePolicyData> for VotePolicy {
+    type Error = String;
+    fn try_from(value: VotePolicyData) -> Result<Self, Self::Error> {
+        match value {
+            VotePolicyData::Count(x) => Ok(Self::AtLeast(x)),
+            VotePolicyData::Name(x) => match x.as_str() {
+                "any" => Ok(Self::Any),
+                "all" => Ok(Self::All),
+                x => x.parse().map(Self::AtLeast).map_err(|_| {
+                    format!("{x:?} is not a vote, use any, all or the number of inputs needed")
+                }),
+            },
+        }
+    }
+}
+
+/// Another input of the same contact
+#[derive(Debug, PartialEq, Eq)]
+pub struct VotingInput {
+    pub expect_from_addr: Sources,
+    pub expect_index: u8,
+    pub expect_pdo: u8,
+}
+impl TryFrom<VotingInputData> for VotingInput {
+    type Error = String;
+    fn try_from(value: VotingInputData) -> Result<Self, Self::Error> {
+        Ok(Self {
+            expect_from_addr: value.expect_from_addr.as_slice().try_into()?,
+            expect_index: value.expect_index,
+            expect_pdo: value.expect_pdo,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct VotingInputData {
+    /// an address, a subnet or a hostname, or a list of them
+    pub expect_from_addr: OneOrMore,
+    pub expect_index: u8,
+    pub expect_pdo: u8,
+}
+
+/// A separate alarm raised while the inputs disagree
+#[derive(Debug, PartialEq, Eq)]
+pub struct DisagreementConfig {
+    /// name of the disagreement alarm
+    pub alarm: String,
+    pub after: Duration,
+    pub priority: Priority,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DisagreementConfigData {
+    /// name of the disagreement alarm, e.g. `fire-disagreement`
+    pub alarm: String,
+    /// raise it after the inputs disagreed this many seconds. Default: 60
+    pub after_seconds: Option<u64>,
+    /// Default: normal
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+/// Redundant inputs of an alarm and how their values are combined
+#[derive(Debug, PartialEq, Eq)]
+pub struct VotingConfig {
+    /// the inputs besides the one of the alarm itself
+    pub inputs: Vec<VotingInput>,
+    pub vote: VotePolicy,
+    /// an input not heard from within this time has failed
+    pub input_timeout: Option<Duration>,
+    pub disagreement: Option<DisagreementConfig>,
+}
+impl TryFrom<VotingConfigData> for VotingConfig {
+    type Error = String;
+    fn try_from(value: VotingConfigData) -> Result<Self, Self::Error> {
+        Ok(Self {
+            inputs: value
+                .inputs
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_, _>>()?,
+            vote: value.vote.map_or(Ok(VotePolicy::Any), TryInto::try_into)?,
+            input_timeout: value.input_timeout_seconds.map(Duration::from_secs),
+            disagreement: value.disagreement.map(|x| DisagreementConfig {
+                alarm: x.alarm,
+                after: x
+                    .after_seconds
+                    .map_or(DEFAULT_DISAGREEMENT_AFTER, Duration::from_secs),
+                priority: x.priority,
+            }),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct VotingConfigData {
+    /// the other inputs the contact is wired into
+    pub inputs: Vec<VotingInputData>,
+    /// `any`, `all` or how many inputs need to report the alarm state. Default: any
+    pub vote: Option<VotePolicyData>,
+    /// An input not heard from for this many seconds has failed: it is left out of the vote
+    /// and counts as a disagreement. Default: inputs never fail
+    pub input_timeout_seconds: Option<u64>,
+    /// Default: no alarm when the inputs disagree
+    pub disagreement: Option<DisagreementConfigData>,
+}
+
+/// The last value of an input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputValue {
+    /// the input reported the alarm state
+    pub alarm: bool,
+    /// unix time it was received
+    pub at: u64,
+}
+
+impl VotingConfig {
+    /// How many inputs there are, including the one of the alarm itself
+    pub fn input_count(&self) -> usize {
+        self.inputs.len() + 1
+    }
+
+    /// The values of all inputs at `now`, `None` if never heard from or failed
+    pub fn current(&self, values: &[Option<InputValue>], now: u64) -> Vec<Option<bool>> {
+        (0..self.input_count())
+            .map(|i| {
+                values
+                    .get(i)
+                    .copied()
+                    .flatten()
+                    .and_then(|x| match self.input_timeout {
+                        Some(timeout) if now.saturating_sub(x.at) > timeout.as_secs() => None,
+                        _ => Some(x.alarm),
+                    })
+            })
+            .collect()
+    }
+
+    /// The combined value, `None` if no input has a value
+    pub fn combine(&self, values: &[Option<bool>]) -> Option<bool> {
+        let known = values.iter().flatten().count();
+        let alarms = values.iter().filter(|x| **x == Some(true)).count();
+        if known == 0 {
+            return None;
+        };
+        Some(match self.vote {
+            VotePolicy::Any => alarms > 0,
+            VotePolicy::All => alarms == known,
+            VotePolicy::AtLeast(x) => alarms >= x,
+        })
+    }
+
+    /// The inputs report different values, or one of them failed
+    pub fn disagree(&self, values: &[Option<bool>]) -> bool {
+        let known = values.iter().flatten().collect::<Vec<_>>();
+        known.windows(2).any(|x| x[0] != x[1])
+            || (self.input_timeout.is_some() && known.len() < values.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Voting over three inputs in total
+    fn voting(vote: VotePolicy, input_timeout: Option<u64>) -> VotingConfig {
+        let input = |pdo| VotingInput {
+            expect_from_addr: Sources(Vec::new()),
+            expect_index: 12,
+            expect_pdo: pdo,
+        };
+        VotingConfig {
+            inputs: vec![input(2), input(3)],
+            vote,
+            input_timeout: input_timeout.map(Duration::from_secs),
+            disagreement: None,
+        }
+    }
+
+    fn value(alarm: bool, at: u64) -> Option<InputValue> {
+        Some(InputValue { alarm, at })
+    }
+
+    #[test]
+    fn current_leaves_out_unknown_and_failed_inputs() {
+        let values = [value(true, 100), None, value(false, 40)];
+        assert_eq!(
+            voting(VotePolicy::Any, None).current(&values, 1000),
+            [Some(true), None, Some(false)]
+        );
+        // 60s since the last value is not yet a failure, 61s is
+        assert_eq!(
+            voting(VotePolicy::Any, Some(60)).current(&values, 100),
+            [Some(true), None, Some(false)]
+        );
+        assert_eq!(
+            voting(VotePolicy::Any, Some(60)).current(&values, 101),
+            [Some(true), None, None]
+        );
+        // fewer values than inputs, e.g. from a state saved before an input was added
+        assert_eq!(
+            voting(VotePolicy::Any, None).current(&values[..1], 100),
+            [Some(true), None, None]
+        );
+        // values from the future do not fail
+        assert_eq!(
+            voting(VotePolicy::Any, Some(60)).current(&[value(true, 200)], 100),
+            [Some(true), None, None]
+        );
+    }
+
+    #[test]
+    fn combine_by_policy() {
+        let cases = [
+            (
+                [Some(true), Some(false), Some(false)],
+                [true, false, true, false],
+            ),
+            (
+                [Some(true), Some(true), Some(false)],
+                [true, false, true, true],
+            ),
+            (
+                [Some(true), Some(true), Some(true)],
+                [true, true, true, true],
+            ),
+            (
+                [Some(false), Some(false), Some(false)],
+                [false, false, false, false],
+            ),
+            // unknown inputs are left out of `all`, but still count for `at least`
+            ([Some(true), None, Some(true)], [true, true, true, true]),
+            ([Some(true), None, None], [true, true, true, false]),
+        ];
+        for (values, [any, all, one, two]) in cases {
+            assert_eq!(
+                voting(VotePolicy::Any, None).combine(&values),
+                Some(any),
+                "{values:?}"
+            );
+            assert_eq!(
+                voting(VotePolicy::All, None).combine(&values),
+                Some(all),
+                "{values:?}"
+            );
+            assert_eq!(
+                voting(VotePolicy::AtLeast(1), None).combine(&values),
+                Some(one),
+                "{values:?}"
+            );
+            assert_eq!(
+                voting(VotePolicy::AtLeast(2), None).combine(&values),
+                Some(two),
+                "{values:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn combine_without_values() {
+        for vote in [VotePolicy::Any, VotePolicy::All, VotePolicy::AtLeast(0)] {
+            assert_eq!(voting(vote, None).combine(&[None, None, None]), None);
+        }
+        // zero inputs needed is always an alarm once any input is known
+        assert_eq!(
+            voting(VotePolicy::AtLeast(0), None).combine(&[Some(false), None, None]),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn disagree() {
+        let without_timeout = voting(VotePolicy::Any, None);
+        let with_timeout = voting(VotePolicy::Any, Some(60));
+        for (values, disagree, disagree_with_timeout) in [
+            ([Some(true), Some(true), Some(true)], false, false),
+            ([Some(false), Some(false), Some(false)], false, false),
+            ([Some(true), Some(false), Some(true)], true, true),
+            ([Some(true), None, Some(false)], true, true),
+            // a failed input only disagrees if inputs can fail
+            ([Some(true), None, Some(true)], false, true),
+            ([None, None, None], false, true),
+        ] {
+            assert_eq!(without_timeout.disagree(&values), disagree, "{values:?}");
+            assert_eq!(
+                with_timeout.disagree(&values),
+                disagree_with_timeout,
+                "{values:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_vote() {
+        let parse = |x: VotePolicyData| VotePolicy::try_from(x);
+        assert_eq!(parse(VotePolicyData::Count(2)), Ok(VotePolicy::AtLeast(2)));
+        assert_eq!(
+            parse(VotePolicyData::Name("any".to_owned())),
+            Ok(VotePolicy::Any)
+        );
+        assert_eq!(
+            parse(VotePolicyData::Name("all".to_owned())),
+            Ok(VotePolicy::All)
+        );
+        assert_eq!(
+            parse(VotePolicyData::Name("3".to_owned())),
+            Ok(VotePolicy::AtLeast(3))
+        );
+        assert!(parse(VotePolicyData::Name("most".to_owned())).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+//! Integration tests of the AMI client against a mock AMI server, through the command line
+
+mod mock_ami;
+
+use std::{
+    net::UdpSocket,
+    path::Path,
+    process::{Child, Command, Output, Stdio},
+    time::{Duration, Instant},
+};
+
+use coe::{COEValue, DigitalCOEValue, Packet, Payload};
+use mock_ami::{MockAmi, Reply, Script};
+
+const BIN: &str = env!("CARGO_BIN_EXE_ta-asterisk-alarm");
+/// The client waits at most 5 s for a response, so anything longer is a hang
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Run the command line with `config` and `args`, killing it after [`TIMEOUT`]
+fn run_cli(config: &Path, args: &[&str]) -> Output {
+    let mut child = Command::new(BIN)
+        .arg("--config")
+        .arg(config)
+        .args(args)
+        .env("RUST_LOG", "ta_asterisk_alarm=debug")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("start ta-asterisk-alarm");
+    let start = Instant::now();
+    while child
+        .try_wait()
+        .expect("wait for ta-asterisk-alarm")
+        .is_none()
+    {
+        if start.elapsed() > TIMEOUT {
+            child.kill().expect("kill ta-asterisk-alarm");
+            panic!("ta-asterisk-alarm {args:?} did not exit within {TIMEOUT:?}");
+        };
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    child
+        .wait_with_output()
+        .expect("output of ta-asterisk-alarm")
+}
+
+/// stdout and stderr, for assertion messages
+fn output_text(output: &Output) -> String {
+    format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    )
+}
+
+/// A UDP port that was free a moment ago
+fn free_udp_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .expect("free UDP port")
+        .port()
+}
+
+/// Start the mock with `scripts` and write a config for it
+fn setup(scripts: Vec<Script>) -> (MockAmi, tempfile::TempDir, std::path::PathBuf) {
+    let mock = MockAmi::start(scripts);
+    let dir = tempfile::tempdir().expect("temporary directory");
+    let config = mock.write_config(dir.path(), free_udp_port());
+    (mock, dir, config)
+}
+
+#[test]
+fn login_sends_credentials_and_logs_off() {
+    let (mock, _dir, config) = setup(vec![Script::default()]);
+    let output = run_cli(&config, &["test-ami"]);
+    assert!(output.status.success(), "{}", output_text(&output));
+
+    let actions = mock.actions();
+    let names = actions.iter().map(|x| x.name()).collect::<Vec<_>>();
+    assert_eq!(names, ["Login", "Logoff"]);
+    let login = &actions[0];
+    assert_eq!(login.get("Username"), Some("ta-asterisk-alarm"));
+    assert_eq!(login.get("Secret"), Some("test-secret"));
+    assert_eq!(login.get("AuthType"), Some("plain"));
+}
+
+#[test]
+fn rejected_login_fails() {
+    let (mock, _dir, config) = setup(vec![Script {
+        reject_login: true,
+        ..Script::default()
+    }]);
+    let output = run_cli(&config, &["test-ami"]);
+    assert!(!output.status.success(), "{}", output_text(&output));
+    assert!(
+        output_text(&output).contains("LoginFailure"),
+        "{}",
+        output_text(&output)
+    );
+    assert_eq!(mock.actions_named("Login").len(), 1);
+}
+
+#[test]
+fn untrusted_certificate_fails() {
+    let (mock, dir, _) = setup(vec![Script::default()]);
+    // a config trusting another CA than the one of the mock
+    let other = MockAmi::start(vec![Script::default()]);
+    let config = std::fs::read_to_string(mock.write_config(dir.path(), free_udp_port()))
+        .expect("read config")
+        .replace(
+            &mock.ca_file().display().to_string(),
+            &other.ca_file().display().to_string(),
+        );
+    std::fs::write(dir.path().join("config.yaml"), config).expect("write config");
+    let output = run_cli(&dir.path().join("config.yaml"), &["test-ami"]);
+    assert!(!output.status.success(), "{}", output_text(&output));
+    assert!(mock.actions().is_empty());
+}
+
+#[test]
+fn originate_sends_the_configured_call() {
+    let (mock, _dir, config) = setup(vec![Script::default()]);
+    let output = run_cli(&config, &["test-call", "PJSIP/2222@sip_trunk_endpoint"]);
+    assert!(output.status.success(), "{}", output_text(&output));
+
+    let originates = mock.actions_named("Originate");
+    assert_eq!(originates.len(), 1);
+    let originate = &originates[0];
+    assert_eq!(
+        originate.get("Channel"),
+        Some("PJSIP/2222@sip_trunk_endpoint")
+    );
+    assert_eq!(originate.get("Context"), Some("commands"));
+    assert_eq!(originate.get("Exten"), Some("alarm"));
+    assert_eq!(originate.get("Priority"), Some("1"));
+    assert_eq!(originate.get("CallerID"), Some("5555"));
+    assert_eq!(originate.get("Async"), Some("true"));
+    assert!(
+        originate
+            .get("ActionID")
+            .is_some_and(|x| x.starts_with("taaa-")),
+        "{originate:?}"
+    );
+}
+
+#[test]
+fn rejected_originate_fails() {
+    let (mock, _dir, config) = setup(vec![Script {
+        originate: Reply::Error,
+        ..Script::default()
+    }]);
+    let output = run_cli(&config, &["test-call", "PJSIP/2222@sip_trunk_endpoint"]);
+    assert!(!output.status.success(), "{}", output_text(&output));
+    assert_eq!(mock.actions_named("Originate").len(), 1);
+}
+
+#[test]
+fn events_before_the_response_are_skipped() {
+    // the OriginateResponse event has a `Response: Success` line, but is not the response
+    let (_mock, _dir, config) = setup(vec![Script {
+        originate: Reply::Error,
+        events: vec![
+            mock_ami::originate_response("Success"),
+            mock_ami::user_event("AlarmAnswered"),
+            mock_ami::hangup(),
+        ],
+        ..Script::default()
+    }]);
+    let output = run_cli(&config, &["test-call", "PJSIP/2222@sip_trunk_endpoint"]);
+    assert!(!output.status.success(), "{}", output_text(&output));
+
+    let (_mock, _dir, config) = setup(vec![Script {
+        events: vec![mock_ami::originate_response("Failure"), mock_ami::hangup()],
+        ..Script::default()
+    }]);
+    let output = run_cli(&config, &["test-call", "PJSIP/2222@sip_trunk_endpoint"]);
+    assert!(output.status.success(), "{}", output_text(&output));
+}
+
+#[test]
+fn messages_split_into_single_bytes() {
+    let (mock, _dir, config) = setup(vec![Script {
+        events: vec![mock_ami::user_event("AlarmAnswered")],
+        chunk_size: Some(1),
+        ..Script::default()
+    }]);
+    let output = run_cli(&config, &["test-call", "PJSIP/2222@sip_trunk_endpoint"]);
+    assert!(output.status.success(), "{}", output_text(&output));
+    assert_eq!(mock.actions_named("Originate").len(), 1);
+}
+
+#[test]
+fn messages_split_at_the_terminator() {
+    // small pieces split the `\r\n\r\n` ending a message between two reads
+    for chunk_size in [2, 3, 5, 7] {
+        let (_mock, _dir, config) = setup(vec![Script {
+            events: vec![mock_ami::hangup()],
+            chunk_size: Some(chunk_size),
+            ..Script::default()
+        }]);
+        let output = run_cli(&config, &["test-call", "PJSIP/2222@sip_trunk_endpoint"]);
+        assert!(
+            output.status.success(),
+            "chunk size {chunk_size}: {}",
+            output_text(&output)
+        );
+    }
+}
+
+#[test]
+fn disconnect_before_the_version_line_fails() {
+    let (mock, _dir, config) = setup(vec![Script {
+        disconnect_before_version: true,
+        ..Script::default()
+    }]);
+    let output = run_cli(&config, &["test-ami"]);
+    assert!(!output.status.success(), "{}", output_text(&output));
+    assert!(mock.actions().is_empty());
+}
+
+#[test]
+fn disconnect_instead_of_a_response_fails() {
+    let (mock, _dir, config) = setup(vec![Script {
+        originate: Reply::Disconnect,
+        ..Script::default()
+    }]);
+    let output = run_cli(&config, &["test-call", "PJSIP/2222@sip_trunk_endpoint"]);
+    assert!(!output.status.success(), "{}", output_text(&output));
+    assert_eq!(mock.actions_named("Originate").len(), 1);
+}
+
+/// Kills the service when the test ends, even if it failed
+struct Service(Child);
+impl Drop for Service {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn service_reconnects_after_a_disconnect() {
+    // connection 0 is the check on startup, 1 the first call round, 2 the next one
+    let (mock, dir, _) = setup(vec![
+        Script::default(),
+        Script {
+            originate: Reply::Disconnect,
+            ..Script::default()
+        },
+        Script::default(),
+    ]);
+    let cmi_port = free_udp_port();
+    let config = mock.write_config(dir.path(), cmi_port);
+    let _service = Service(
+        Command::new(BIN)
+            .arg("--config")
+            .arg(&config)
+            .arg("run")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("start the service"),
+    );
+    assert!(
+        mock.wait_for(TIMEOUT, |x| x.actions_named("Logoff").len() == 1),
+        "the service did not check AMI on startup: {:?}",
+        mock.actions()
+    );
+
+    // the alarm state, as sent by a CMI to CAN-ID 12, PDO 1 of the web-GUI
+    let payload = Payload::new(12, 0, COEValue::Digital(DigitalCOEValue::OnOff(true)));
+    let packet = Packet::try_from_payloads(&[payload])
+        .expect("packet")
+        .serialize_into_vec();
+    let cmi = UdpSocket::bind("127.0.0.1:0").expect("bind CMI socket");
+    let originated_on = |connection: usize| {
+        move |mock: &MockAmi| {
+            mock.actions_named("Originate")
+                .iter()
+                .any(|x| x.connection == connection)
+        }
+    };
+    // the service may take a moment to listen, and the CMI repeats the alarm state anyway
+    let start = Instant::now();
+    while !originated_on(1)(&mock) && start.elapsed() < TIMEOUT {
+        cmi.send_to(&packet, ("127.0.0.1", cmi_port))
+            .expect("send COE");
+        mock.wait_for(Duration::from_millis(500), originated_on(1));
+    }
+    assert!(
+        originated_on(1)(&mock),
+        "no call round: {:?}",
+        mock.actions()
+    );
+
+    // the first round failed, so the next packet starts a round on a new connection
+    let start = Instant::now();
+    while !originated_on(2)(&mock) && start.elapsed() < TIMEOUT {
+        cmi.send_to(&packet, ("127.0.0.1", cmi_port))
+            .expect("send COE");
+        mock.wait_for(Duration::from_millis(500), originated_on(2));
+    }
+    assert!(
+        originated_on(2)(&mock),
+        "no call after reconnecting: {:?}",
+        mock.actions()
+    );
+    assert_eq!(mock.connections(), 3);
+    let logins = mock.actions_named("Login");
+    assert!(logins.iter().any(|x| x.connection == 2), "{logins:?}");
+}
@@ -0,0 +1,94 @@
+//! Integration tests of the library API: the alarm engine fed with COE datagrams directly
+
+mod mock_ami;
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use coe::{COEValue, DigitalCOEValue, Packet, Payload};
+use mock_ami::{MockAmi, Script};
+use serde_json::json;
+use ta_asterisk_alarm::{Config, Engine, Event, EventKind};
+
+const TIMEOUT: Duration = Duration::from_secs(20);
+const CMI: &str = "127.0.0.1:5442";
+
+/// The datagram a CMI sends for CAN-ID 12, PDO 1 of the web-GUI
+fn datagram(on: bool) -> Vec<u8> {
+    let payload = Payload::new(12, 0, COEValue::Digital(DigitalCOEValue::OnOff(on)));
+    Packet::try_from_payloads(&[payload])
+        .expect("packet")
+        .serialize_into_vec()
+}
+
+/// A config for `mock`, read like the binary reads it
+fn config(mock: &MockAmi, dir: &tempfile::TempDir) -> Arc<Config> {
+    Arc::new(Config::create(&mock.write_config(dir.path(), 5442)).expect("valid config"))
+}
+
+#[test]
+fn dry_run_records_the_calls_it_would_send() {
+    let mock = MockAmi::start(vec![Script::default()]);
+    let dir = tempfile::tempdir().expect("temporary directory");
+    let engine = Engine::dry_run(config(&mock, &dir));
+    let cmi: SocketAddr = CMI.parse().expect("address");
+
+    engine.handle_datagram(&datagram(true), cmi);
+    assert_eq!(engine.active_alarms(), ["alarm"]);
+    engine.handle_datagram(&datagram(false), cmi);
+    assert!(engine.active_alarms().is_empty());
+
+    let events = engine.take_events();
+    assert!(
+        matches!(
+            &events[..],
+            [
+                Event { kind: EventKind::Raised { .. }, .. },
+                Event { kind: EventKind::WouldCall { endpoints }, .. },
+                Event { kind: EventKind::Cleared { .. }, .. },
+            ] if endpoints == &["PJSIP/1111@sip_trunk_endpoint"]
+        ),
+        "{events:?}"
+    );
+    assert!(events.iter().all(|x| x.alarm == "alarm"), "{events:?}");
+    // like a line of the journal
+    assert_eq!(
+        serde_json::to_value(&events[1]).expect("JSON event"),
+        json!({
+            "time": events[1].time,
+            "alarm": "alarm",
+            "kind": "would_call",
+            "endpoints": ["PJSIP/1111@sip_trunk_endpoint"],
+        })
+    );
+    assert!(mock.actions().is_empty());
+}
+
+#[test]
+fn datagrams_from_other_hosts_are_ignored() {
+    let mock = MockAmi::start(vec![Script::default()]);
+    let dir = tempfile::tempdir().expect("temporary directory");
+    let engine = Engine::dry_run(config(&mock, &dir));
+
+    engine.handle_datagram(&datagram(true), "127.0.0.2:5442".parse().expect("address"));
+    assert!(engine.active_alarms().is_empty());
+    assert!(engine.take_events().is_empty());
+}
+
+#[test]
+fn raised_alarms_call_asterisk() {
+    let mock = MockAmi::start(vec![Script::default()]);
+    let dir = tempfile::tempdir().expect("temporary directory");
+    let engine = Engine::new(config(&mock, &dir));
+
+    engine.handle_datagram(&datagram(true), CMI.parse().expect("address"));
+    assert!(
+        mock.wait_for(TIMEOUT, |x| x.actions_named("Originate").len() == 1),
+        "no call: {:?}",
+        mock.actions()
+    );
+    let originate = &mock.actions_named("Originate")[0];
+    assert_eq!(
+        originate.get("Channel"),
+        Some("PJSIP/1111@sip_trunk_endpoint")
+    );
+}
@@ -0,0 +1,352 @@
+//! A mock of the asterisk manager interface (AMI) over TLS, for the integration tests.
+//!
+//! It serves a certificate for `localhost` signed by a test CA generated on start, answers
+//! actions as scripted and records every action it receives.
+
+// every test crate including it uses only a part
+#![allow(dead_code)]
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    ServerConfig, ServerConnection, StreamOwned,
+};
+use tempfile::TempDir;
+
+/// How the mock answers an originate action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    Success,
+    Error,
+    /// close the connection instead of answering
+    Disconnect,
+}
+
+/// What the mock does on a single connection
+#[derive(Debug, Clone)]
+pub struct Script {
+    /// close the connection after the TLS handshake, before sending the version line
+    pub disconnect_before_version: bool,
+    /// answer the login with an error
+    pub reject_login: bool,
+    pub originate: Reply,
+    /// sent before the response to each originate; `{action_id}` is replaced by its ActionID
+    pub events: Vec<String>,
+    /// write in pieces of this many bytes, each in its own TLS record. Default: all at once
+    pub chunk_size: Option<usize>,
+}
+impl Default for Script {
+    fn default() -> Self {
+        Self {
+            disconnect_before_version: false,
+            reject_login: false,
+            originate: Reply::Success,
+            events: Vec::new(),
+            chunk_size: None,
+        }
+    }
+}
+
+/// The event asterisk sends once an asynchronous originate was answered or failed
+pub fn originate_response(response: &str) -> String {
+    format!(
+        "Event: OriginateResponse\r\nActionID: {{action_id}}\r\nResponse: {response}\r\nChannel: PJSIP/1111-00000001\r\nReason: 4\r\n\r\n"
+    )
+}
+
+/// The event asterisk sends when a channel hangs up
+pub fn hangup() -> String {
+    "Event: Hangup\r\nChannel: PJSIP/1111-00000001\r\nCause: 16\r\nCause-txt: Normal Clearing\r\n\r\n"
+        .to_owned()
+}
+
+/// An event sent by `UserEvent()` in the dialplan
+pub fn user_event(name: &str) -> String {
+    format!("Event: UserEvent\r\nUserEvent: {name}\r\nAlarm: fire\r\n\r\n")
+}
+
+/// An action received by the mock
+#[derive(Debug, Clone)]
+pub struct Action {
+    /// number of the connection, counting from 0
+    pub connection: usize,
+    pub fields: Vec<(String, String)>,
+}
+impl Action {
+    /// The value of the first field `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The name of the action, e.g. `Login`
+    pub fn name(&self) -> &str {
+        self.get("Action").unwrap_or_default()
+    }
+}
+
+/// A running mock AMI server. It stops with the test process.
+pub struct MockAmi {
+    pub port: u16,
+    dir: TempDir,
+    actions: Arc<Mutex<Vec<Action>>>,
+    connections: Arc<AtomicUsize>,
+}
+impl MockAmi {
+    /// Serve on a free port of 127.0.0.1. Connection `i` follows `scripts[i]`, or the last
+    /// script once they ran out.
+    pub fn start(scripts: Vec<Script>) -> Self {
+        assert!(!scripts.is_empty(), "the mock needs at least one script");
+        let dir = tempfile::tempdir().expect("temporary directory");
+
+        // a CA, and a certificate for localhost signed by it
+        let ca_key = KeyPair::generate().expect("CA key");
+        let mut ca_params = CertificateParams::new(Vec::new()).expect("CA params");
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "ta-asterisk-alarm test CA");
+        let ca = ca_params.self_signed(&ca_key).expect("CA certificate");
+        let key = KeyPair::generate().expect("server key");
+        let cert = CertificateParams::new(vec!["localhost".to_owned()])
+            .expect("server params")
+            .signed_by(&key, &ca, &ca_key)
+            .expect("server certificate");
+        std::fs::write(dir.path().join("ca.pem"), ca.pem()).expect("write CA");
+
+        let tls = Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![CertificateDer::from(cert.der().to_vec())],
+                    PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+                )
+                .expect("server TLS config"),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind mock AMI");
+        let port = listener.local_addr().expect("local addr").port();
+        let actions = Arc::new(Mutex::new(Vec::new()));
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let (actions2, connections2) = (actions.clone(), connections.clone());
+        std::thread::spawn(move || {
+            for tcp in listener.incoming() {
+                let Ok(tcp) = tcp else {
+                    continue;
+                };
+                let connection = connections2.fetch_add(1, Ordering::SeqCst);
+                let script = scripts[connection.min(scripts.len() - 1)].clone();
+                let (tls, actions) = (tls.clone(), actions2.clone());
+                std::thread::spawn(move || {
+                    let conn = ServerConnection::new(tls).expect("server connection");
+                    let mut session = Session {
+                        stream: StreamOwned::new(conn, tcp),
+                        script,
+                        connection,
+                        actions,
+                        buffer: Vec::new(),
+                    };
+                    // errors only mean the client went away
+                    let _ = session.serve();
+                });
+            }
+        });
+        Self {
+            port,
+            dir,
+            actions,
+            connections,
+        }
+    }
+
+    /// The PEM file with the test CA, for `asterisk.trust_extra_pem`
+    pub fn ca_file(&self) -> PathBuf {
+        self.dir.path().join("ca.pem")
+    }
+
+    /// All actions received so far, in order
+    pub fn actions(&self) -> Vec<Action> {
+        self.actions.lock().expect("actions mutex poisoned").clone()
+    }
+
+    /// The actions named `name` received so far
+    pub fn actions_named(&self, name: &str) -> Vec<Action> {
+        self.actions()
+            .into_iter()
+            .filter(|x| x.name().eq_ignore_ascii_case(name))
+            .collect()
+    }
+
+    /// How many connections were accepted so far
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Wait until `f` holds, at most `timeout`
+    pub fn wait_for(&self, timeout: Duration, f: impl Fn(&Self) -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if f(self) {
+                return true;
+            };
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        f(self)
+    }
+
+    /// Write a config talking to this mock into `dir`, listening for COE on `cmi_port`
+    pub fn write_config(&self, dir: &Path, cmi_port: u16) -> PathBuf {
+        let path = dir.join("config.yaml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"cmi:
+  listen_addr: "127.0.0.1"
+  port: {cmi_port}
+  expect_from_addr: "127.0.0.1"
+  expect_index: 12
+  expect_pdo: 1
+  circuit_is_normally_closed: false
+asterisk:
+  host: "localhost"
+  port: {}
+  trust_extra_pem: "{}"
+  username: "ta-asterisk-alarm"
+  secret: "test-secret"
+  execute_context: "commands"
+  execute_exten: "alarm"
+  call_external_endpoints:
+  - "PJSIP/1111@sip_trunk_endpoint"
+  caller_id: "5555"
+"#,
+                self.port,
+                self.ca_file().display()
+            ),
+        )
+        .expect("write config");
+        path
+    }
+}
+
+/// A single connection to the mock
+struct Session {
+    stream: StreamOwned<ServerConnection, TcpStream>,
+    script: Script,
+    connection: usize,
+    actions: Arc<Mutex<Vec<Action>>>,
+    /// received bytes not yet parsed as an action
+    buffer: Vec<u8>,
+}
+impl Session {
+    fn serve(&mut self) -> std::io::Result<()> {
+        if self.script.disconnect_before_version {
+            while self.stream.conn.is_handshaking() {
+                self.stream.conn.complete_io(&mut self.stream.sock)?;
+            }
+            return self.close();
+        };
+        self.send("Asterisk Call Manager/9.0.0\r\n")?;
+        while let Some(action) = self.read_action()? {
+            self.actions
+                .lock()
+                .expect("actions mutex poisoned")
+                .push(action.clone());
+            let action_id = action.get("ActionID").unwrap_or_default().to_owned();
+            match action.name().to_ascii_lowercase().as_str() {
+                "login" if self.script.reject_login => {
+                    self.send("Response: Error\r\nMessage: Authentication failed\r\n\r\n")?;
+                }
+                "login" => {
+                    self.send("Response: Success\r\nMessage: Authentication accepted\r\n\r\n")?;
+                }
+                "originate" => {
+                    if self.script.originate == Reply::Disconnect {
+                        return self.close();
+                    };
+                    let mut message = self
+                        .script
+                        .events
+                        .iter()
+                        .map(|x| x.replace("{action_id}", &action_id))
+                        .collect::<String>();
+                    message.push_str(&match self.script.originate {
+                        Reply::Success => format!(
+                            "Response: Success\r\nActionID: {action_id}\r\nMessage: Originate successfully queued\r\n\r\n"
+                        ),
+                        _ => format!(
+                            "Response: Error\r\nActionID: {action_id}\r\nMessage: Originate failed\r\n\r\n"
+                        ),
+                    });
+                    self.send(&message)?;
+                }
+                "logoff" => {
+                    self.send("Response: Goodbye\r\nMessage: Thanks for all the fish.\r\n\r\n")?;
+                    return self.close();
+                }
+                _ => {
+                    self.send("Response: Error\r\nMessage: Invalid/unknown command\r\n\r\n")?;
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Write `message`, in pieces if scripted
+    fn send(&mut self, message: &str) -> std::io::Result<()> {
+        let chunk_size = self.script.chunk_size.unwrap_or(message.len()).max(1);
+        for chunk in message.as_bytes().chunks(chunk_size) {
+            self.stream.write_all(chunk)?;
+            self.stream.flush()?;
+            if self.script.chunk_size.is_some() {
+                // let every piece arrive on its own
+                std::thread::sleep(Duration::from_millis(2));
+            };
+        }
+        Ok(())
+    }
+
+    /// The next action, `None` once the client closed the connection
+    fn read_action(&mut self) -> std::io::Result<Option<Action>> {
+        loop {
+            if let Some(pos) = self.buffer.windows(4).position(|x| x == b"\r\n\r\n") {
+                let raw = self.buffer.drain(..pos + 4).collect::<Vec<_>>();
+                let fields = String::from_utf8_lossy(&raw)
+                    .lines()
+                    .filter_map(|x| x.split_once(": "))
+                    .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                    .collect();
+                return Ok(Some(Action {
+                    connection: self.connection,
+                    fields,
+                }));
+            };
+            let mut buf = [0_u8; 1024];
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(x) => self.buffer.extend_from_slice(&buf[..x]),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            };
+        }
+    }
+
+    /// Close the connection cleanly, like asterisk does
+    fn close(&mut self) -> std::io::Result<()> {
+        self.stream.conn.send_close_notify();
+        self.stream.flush()?;
+        self.stream.sock.shutdown(std::net::Shutdown::Both)
+    }
+}
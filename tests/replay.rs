@@ -0,0 +1,101 @@
+//! Integration tests of replaying a capture through the command line
+
+mod mock_ami;
+
+use std::{
+    fmt::Write as _,
+    path::Path,
+    process::{Command, Output},
+};
+
+use coe::{AnalogueCOEValue, COEValue, DigitalCOEValue, Packet, Payload};
+use mock_ami::{MockAmi, Script};
+
+const BIN: &str = env!("CARGO_BIN_EXE_ta-asterisk-alarm");
+/// 2024-01-01 00:00:00.250 UTC, off the whole seconds like a real capture
+const START_MS: u64 = 1_704_067_200_250;
+
+/// Run `replay` of `capture` with `config`
+fn replay(config: &Path, capture: &Path) -> Output {
+    Command::new(BIN)
+        .arg("--config")
+        .arg(config)
+        .arg("replay")
+        .arg(capture)
+        .output()
+        .expect("run ta-asterisk-alarm replay")
+}
+
+/// A datagram with the digital value `on` at web-GUI PDO 2 and the analog value `flow`
+/// (without decimals) at PDO 3 of CAN-ID `node`
+fn datagram(node: u8, on: bool, flow: i32) -> String {
+    let flow = AnalogueCOEValue::try_from((&0, &flow.to_le_bytes()[..])).expect("analog value");
+    let payloads = [
+        Payload::new(node, 1, COEValue::Digital(DigitalCOEValue::OnOff(on))),
+        Payload::new(node, 2, COEValue::Analogue(flow)),
+    ];
+    Packet::try_from_payloads(&payloads)
+        .expect("packet")
+        .serialize_into_vec()
+        .iter()
+        .fold(String::new(), |mut res, x| {
+            let _ = write!(res, "{x:02x}");
+            res
+        })
+}
+
+#[test]
+fn timers_run_between_datagrams_less_than_a_second_apart() {
+    let mock = MockAmi::start(vec![Script::default()]);
+    let dir = tempfile::tempdir().expect("temporary directory");
+    let config = mock.write_config(dir.path(), 5442);
+    let mut yaml = std::fs::read_to_string(&config).expect("read config");
+    yaml.push_str(
+        r#"signals:
+- name: "pump"
+  expect_from_addr: "127.0.0.1"
+  expect_index: 12
+  expect_pdo: 2
+- name: "flow"
+  expect_from_addr: "127.0.0.1"
+  expect_index: 12
+  expect_pdo: 3
+rules:
+- name: "pump-dry-run"
+  when: "pump and flow < 2.5 for 2m"
+"#,
+    );
+    std::fs::write(&config, yaml).expect("write config");
+
+    // the signals arrive once, then only another CMI sends, every 500 ms for 3 minutes
+    let mut capture = format!(
+        "{{\"at_ms\":{START_MS},\"from\":\"127.0.0.1:5442\",\"data\":\"{}\"}}\n",
+        datagram(12, true, 1)
+    );
+    let other = datagram(13, false, 0);
+    for i in 1..=360 {
+        let _ = writeln!(
+            capture,
+            "{{\"at_ms\":{},\"from\":\"127.0.0.2:5442\",\"data\":\"{other}\"}}",
+            START_MS + i * 500
+        );
+    }
+    let capture_file = dir.path().join("capture.jsonl");
+    std::fs::write(&capture_file, capture).expect("write capture");
+
+    let output = replay(&config, &capture_file);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{stdout}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let events = stdout
+        .lines()
+        .map(|x| serde_json::from_str::<serde_json::Value>(x).expect("JSON event"))
+        .filter(|x| x["alarm"] == "pump-dry-run")
+        .collect::<Vec<_>>();
+    assert_eq!(events[0]["kind"], "raised", "{events:?}");
+    // at the first tick 2 minutes after the signals arrived
+    assert_eq!(events[0]["time"], START_MS / 1000 + 121, "{events:?}");
+}